@@ -0,0 +1,98 @@
+use std::rand::{OsRng, Rng};
+use std::io::{File, IoResult, IoError, OtherIoError};
+
+use serialize::hex::{ToHex, FromHex};
+
+use crypto::util::fixed_time_eq;
+
+use encryption::hmac_sha256;
+
+
+static CHALLENGE_SIZE: uint = 32;
+
+// Keeps a response from passing for any other HMAC made with the key
+static RESPONSE_LABEL: &'static [u8] = b"btrfs-backup client auth\x00";
+
+
+/// What an authenticated client may do; see
+/// `ProtocolCommand::allowed_for`.
+#[deriving(Encodable, Decodable, PartialEq, Clone, Show)]
+pub enum ClientRole {
+    // Upload, and see enough of the graph to pick parents
+    UploadRole,
+    // Look and download, but change nothing
+    ReadRole,
+    // Everything, including deleting
+    AdminRole
+}
+
+
+/// A client allowed to connect, as listed in the root config.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct ClientIdentity {
+    pub name: String,
+    // Hex HMAC-SHA256 key, shared with the client
+    pub key: String,
+    pub role: ClientRole,
+    // The one namespace the client sees, if it's kept to one
+    pub namespace: Option<String>
+}
+
+
+impl ClientIdentity {
+    /// Whether `response` to `challenge` shows the client holds the key.
+    pub fn verify(&self, challenge: &str, response: &str) -> bool {
+        match self.key.as_slice().from_hex() {
+            Ok(key) => fixed_time_eq(
+                respond(key.as_slice(), challenge).as_bytes(),
+                response.as_bytes()),
+            Err(_) => false
+        }
+    }
+}
+
+
+pub fn new_challenge() -> IoResult<String> {
+    let mut rng = try!(OsRng::new());
+    let mut nonce = Vec::from_elem(CHALLENGE_SIZE, 0u8);
+    rng.fill_bytes(nonce.as_mut_slice());
+    Ok(nonce.as_slice().to_hex())
+}
+
+
+/// Reads a client key stored as hex in a file, as it appears in the
+/// server's config.
+pub fn load_key(path: &Path) -> IoResult<Vec<u8>> {
+    let contents = try!(try!(File::open(path)).read_to_string());
+    match contents.as_slice().trim().from_hex() {
+        Ok(key) => Ok(key),
+        Err(_) => Err(IoError {
+            kind: OtherIoError,
+            desc: "client key file is not hex",
+            detail: Some(format!("{}", path.display()))
+        })
+    }
+}
+
+
+/// What a client holding `key` answers to `challenge`.
+pub fn respond(key: &[u8], challenge: &str) -> String {
+    hmac_sha256(key, &[RESPONSE_LABEL, challenge.as_bytes()]).as_slice().to_hex()
+}
+
+
+#[test]
+fn test_challenge_response() {
+    let identity = ClientIdentity {
+        name: "backup-1".to_string(),
+        key: b"secret key".to_hex(),
+        role: UploadRole,
+        namespace: None
+    };
+    let challenge = new_challenge().unwrap();
+    assert!(identity.verify(challenge.as_slice(), respond(b"secret key", challenge.as_slice()).as_slice()));
+    assert!(!identity.verify(challenge.as_slice(), respond(b"other key", challenge.as_slice()).as_slice()));
+    assert!(!identity.verify(new_challenge().unwrap().as_slice(),
+        respond(b"secret key", challenge.as_slice()).as_slice()));
+    assert!(!identity.verify(challenge.as_slice(), ""));
+}
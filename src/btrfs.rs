@@ -557,3 +557,68 @@ pub fn get_first_command(reader: &mut Reader) -> Result<BtrfsCommand, BtrfsParse
         None => Err(ProtocolError(format!("No commands")))
     }
 }
+
+
+/// Reads a whole stream, checking every command's CRC and that it ends
+/// with an END command.  Returns the first command, which says what the
+/// stream is a backup of.
+pub fn validate_stream(reader: &mut Reader) -> Result<BtrfsCommand, BtrfsParseError> {
+    let header = try!(BtrfsHeader::parse(reader));
+    if header.version != 1 {
+        return Err(InvalidVersion);
+    }
+    let mut first: Option<BtrfsCommand> = None;
+    let mut index: uint = 0;
+    loop {
+        let command_buf = match BtrfsCommandBuf::read(reader) {
+            Ok(command_buf) => command_buf,
+            Err(ref err) if err.kind == EndOfFile => {
+                return Err(ProtocolError(format!("stream ends without END after {} commands", index)));
+            },
+            Err(err) => return Err(ReadError(err))
+        };
+        let kind = match command_buf.get_kind() {
+            Some(kind) => kind,
+            None => return Err(ProtocolError(format!("unknown command at index {}", index)))
+        };
+        if !command_buf.validate_crc32() {
+            return Err(ProtocolError(format!("bad CRC on {} at index {}", kind, index)));
+        }
+        if first.is_none() {
+            first = Some(try!(command_buf.parse()));
+        }
+        if kind == BTRFS_SEND_C_END {
+            break;
+        }
+        index += 1;
+    }
+    match first {
+        Some(command) => Ok(command),
+        None => unreachable!()
+    }
+}
+
+
+#[test]
+fn test_validate_stream() {
+    let subvol = BtrfsSubvol {
+        name: b"snap".to_vec(),
+        uuid: Uuid::parse_str("a3374b40-c08e-b545-93f7-8361e8b435b8").ok().unwrap(),
+        ctransid: 1
+    };
+    let mut stream = BtrfsHeader { version: 1 }.serialize();
+    stream.push_all(subvol.encap().serialize().as_slice());
+    stream.push_all(BtrfsCommand::from_kind(BTRFS_SEND_C_END, Vec::new()).serialize().as_slice());
+
+    match validate_stream(&mut BufReader::new(stream.as_slice())) {
+        Ok(command) => assert_eq!(command.kind, BTRFS_SEND_C_SUBVOL),
+        Err(err) => fail!("err: {}", err)
+    }
+
+    // The samples stop partway through
+    assert!(validate_stream(&mut BufReader::new(BTRFS_SAMPLE_SUBVOL)).is_err());
+
+    let name_offset = BTRFS_HEADER_MAGIC.len() + 4 + 10 + 4;
+    stream.as_mut_slice()[name_offset] ^= 0xff;
+    assert!(validate_stream(&mut BufReader::new(stream.as_slice())).is_err());
+}
@@ -3,14 +3,23 @@
 
 extern crate uuid;
 extern crate debug;
+extern crate flate;
+extern crate serialize;
+extern crate libc;
+extern crate "rust-crypto" as crypto;
 
 use std::path::Path;
-use std::io::{BufferedReader, File};
+use std::io::BufferedReader;
 use std::os::args_as_bytes;
 
 use btrfs::BtrfsCommandIter;
+use object::open_object;
 mod btrfs;
 mod crc32;
+mod object;
+mod compression;
+mod chunking;
+mod lock;
 
 
 fn main() {
@@ -24,7 +33,7 @@ fn main() {
         [_, ref filename, ..] => filename.clone()
     });
 
-    let mut reader = match File::open(&filename) {
+    let mut reader = match open_object(&filename) {
         Ok(file) => BufferedReader::new(file),
         Err(err) => fail!("{}", err)
     };
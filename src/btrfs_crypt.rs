@@ -0,0 +1,249 @@
+#![allow(dead_code)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate argparse;
+
+use std::os;
+use std::io::{BufferedReader, BufferedWriter, IoResult, stdin, stdout, stderr};
+use std::io::util::copy;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+use btrfs::{BtrfsHeader, BtrfsCommandBuf};
+use encryption::{RepositoryKey, MetadataEnvelope, EncryptWriter, DecryptReader};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    mode: String,
+    key_path: String,
+    envelope_path: String,
+    unsigned: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            mode: "".to_string(),
+            key_path: "".to_string(),
+            envelope_path: "".to_string(),
+            unsigned: false
+        }
+    }
+}
+
+
+// Counts what passes through, so the envelope can carry the plaintext size.
+struct CountingReader<R> {
+    inner: R,
+    count: u64
+}
+
+impl<R: Reader> Reader for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let len = try!(self.inner.read(buf));
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+
+fn encrypt(key: &RepositoryKey, args: &ProgramArgs) {
+    let mut reader = CountingReader {
+        inner: BufferedReader::new(stdin()),
+        count: 0
+    };
+
+    // Peek at the stream header; it goes into the envelope in the clear
+    // and into the object encrypted like everything else.
+    let header = match BtrfsHeader::parse(&mut reader) {
+        Ok(header) => header,
+        Err(err) => fail!("error reading stream header: {}", err)
+    };
+    let first = match BtrfsCommandBuf::read(&mut reader) {
+        Ok(first) => first,
+        Err(err) => fail!("error reading first command: {}", err)
+    };
+    let command = match first.parse() {
+        Ok(command) => command,
+        Err(err) => fail!("error parsing first command: {}", err)
+    };
+
+    let mut writer = match EncryptWriter::new(BufferedWriter::new(stdout()), key) {
+        Ok(writer) => writer,
+        Err(err) => fail!("error starting encryption: {}", err)
+    };
+    assert!(writer.write(header.serialize().as_slice()).is_ok());
+    assert!(writer.write(first.as_slice()).is_ok());
+    match copy(&mut reader, &mut writer) {
+        Ok(()) => (),
+        Err(err) => fail!("error encrypting: {}", err)
+    }
+    match writer.finish() {
+        Ok(_) => (),
+        Err(err) => fail!("error encrypting: {}", err)
+    }
+
+    let mut envelope = match MetadataEnvelope::from_command(&command, reader.count) {
+        Some(envelope) => envelope,
+        None => fail!("stream does not start with a subvolume or snapshot")
+    };
+    if !args.unsigned {
+        key.sign_envelope(&mut envelope);
+    }
+    match envelope.save(&Path::new(args.envelope_path.as_slice())) {
+        Ok(()) => (),
+        Err(err) => fail!("error writing envelope: {}", err)
+    }
+}
+
+
+fn decrypt(key: &RepositoryKey, args: &ProgramArgs) {
+    let envelope = if args.envelope_path.len() > 0 {
+        match MetadataEnvelope::load(&Path::new(args.envelope_path.as_slice())) {
+            Ok(envelope) => Some(envelope),
+            Err(err) => fail!("error reading envelope: {}", err)
+        }
+    } else {
+        None
+    };
+    match envelope {
+        Some(ref envelope) if !args.unsigned && !key.verify_envelope(envelope) => {
+            fail!("envelope signature does not verify");
+        },
+        _ => ()
+    }
+
+    let mut reader = match DecryptReader::new(BufferedReader::new(stdin()), key) {
+        Ok(reader) => reader,
+        Err(err) => fail!("error reading object: {}", err)
+    };
+
+    // Make sure the envelope describes this object before emitting anything
+    let header = match BtrfsHeader::parse(&mut reader) {
+        Ok(header) => header,
+        Err(err) => fail!("error reading stream header: {}", err)
+    };
+    let first = match BtrfsCommandBuf::read(&mut reader) {
+        Ok(first) => first,
+        Err(err) => fail!("error reading first command: {}", err)
+    };
+    match envelope {
+        Some(ref envelope) => {
+            let actual = match first.parse() {
+                Ok(ref command) => MetadataEnvelope::from_command(command, 0),
+                Err(_) => None
+            };
+            match actual {
+                Some(ref actual) if actual.uuid == envelope.uuid &&
+                        actual.parent_uuid == envelope.parent_uuid => (),
+                _ => fail!("envelope does not match the object")
+            }
+        },
+        None => ()
+    }
+
+    let mut writer = BufferedWriter::new(stdout());
+    assert!(writer.write(header.serialize().as_slice()).is_ok());
+    assert!(writer.write(first.as_slice()).is_ok());
+    match copy(&mut reader, &mut writer) {
+        Ok(()) => (),
+        Err(err) => fail!("error decrypting: {}", err)
+    }
+    assert!(writer.flush().is_ok());
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Encrypt a btrfs stream before upload, or decrypt one after ",
+            "download.  Reads stdin, writes stdout."));
+
+        ap.refer(&mut prog_args.mode)
+            .add_argument(
+                "mode", box Store::<String>, "keygen, encrypt or decrypt")
+            .required();
+
+        ap.refer(&mut prog_args.key_path)
+            .add_option(["-k", "--key"], box Store::<String>,
+            "Repository key file")
+            .required();
+
+        ap.refer(&mut prog_args.envelope_path)
+            .add_option(["-e", "--envelope"], box Store::<String>,
+            "Metadata envelope to write (encrypt) or check against (decrypt)");
+
+        ap.refer(&mut prog_args.unsigned)
+            .add_option(["--unsigned"], box StoreTrue,
+            "Don't sign (encrypt) or verify the signature of (decrypt) the envelope");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    let key_path = Path::new(prog_args.key_path.as_slice());
+
+    if prog_args.mode.as_slice() == "keygen" {
+        if key_path.exists() {
+            fail!("refusing to overwrite {}", key_path.display());
+        }
+        match RepositoryKey::generate().and_then(|key| key.save(&key_path)) {
+            Ok(()) => (),
+            Err(err) => fail!("error generating key: {}", err)
+        }
+        return;
+    }
+
+    let key = match RepositoryKey::load(&key_path) {
+        Ok(key) => key,
+        Err(err) => fail!("error loading key: {}", err)
+    };
+
+    match prog_args.mode.as_slice() {
+        "encrypt" => {
+            if prog_args.envelope_path.len() == 0 {
+                fail!("encrypt needs --envelope");
+            }
+            encrypt(&key, &prog_args);
+        },
+        "decrypt" => decrypt(&key, &prog_args),
+        other => {
+            let mut err = stderr();
+            assert!(err.write(format!("unknown mode: {}\n", other).as_bytes()).is_ok());
+            os::set_exit_status(2);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{IoResult, IoError, OtherIoError, EndOfFile, SeekSet, SeekEnd, RefReader};
+use std::io::util::LimitReader;
+
+use serialize::json;
+use serialize::hex::ToHex;
+
+use uuid::Uuid;
+
+use metadata::{ObjectMetadata, HashingWriter};
+
+#[cfg(test)]
+use std::io::{MemWriter, BufReader};
+
+
+// A bundle is the send streams of a restore chain, back to back and in
+// replay order, then a JSON manifest describing them:
+//
+//     stream... manifest u64:manifest-length BUNDLE_MAGIC
+//
+// The manifest goes last so a bundle can be written in one pass.  The
+// streams stand on their own: the first `streams_size` bytes can go
+// straight to `btrfs receive`.
+pub static BUNDLE_MAGIC: &'static [u8] = b"btrfs-backup-bundle\x00\x01";
+
+static MAX_MANIFEST_SIZE: u64 = 16 * 1024 * 1024;
+
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct BundleEntry {
+    pub uuid: Uuid,
+    pub parent: Option<Uuid>,
+    // Where the stream starts in the bundle, and its length
+    pub offset: u64,
+    pub size: u64,
+    // Hex SHA-256 of the stream
+    pub content_hash: String,
+    pub metadata: Option<ObjectMetadata>
+}
+
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct BundleManifest {
+    pub target: Uuid,
+    pub created_at: i64,
+    pub streams_size: u64,
+    pub entries: Vec<BundleEntry>
+}
+
+
+fn bundle_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail
+    }
+}
+
+
+pub struct BundleWriter<W> {
+    inner: W,
+    offset: u64,
+    entries: Vec<BundleEntry>
+}
+
+
+impl<W: Writer> BundleWriter<W> {
+    pub fn new(inner: W) -> BundleWriter<W> {
+        BundleWriter {
+            inner: inner,
+            offset: 0,
+            entries: Vec::new()
+        }
+    }
+
+    /// Appends one stream.  Parents must be added before their children.
+    pub fn add(&mut self, uuid: &Uuid, parent: Option<&Uuid>,
+               metadata: Option<&ObjectMetadata>, stream: &mut Reader) -> IoResult<&BundleEntry> {
+        let mut size: u64 = 0;
+        let content_hash = {
+            let mut writer = HashingWriter::new(&mut self.inner);
+            let mut buf = [0u8, ..64 * 1024];
+            loop {
+                let len = match stream.read(buf) {
+                    Ok(len) => len,
+                    Err(ref err) if err.kind == EndOfFile => break,
+                    Err(err) => return Err(err)
+                };
+                try!(writer.write(buf[..len]));
+                size += len as u64;
+            }
+            writer.digest()
+        };
+        self.entries.push(BundleEntry {
+            uuid: uuid.clone(),
+            parent: parent.map(|p| p.clone()),
+            offset: self.offset,
+            size: size,
+            content_hash: content_hash.as_slice().to_hex(),
+            metadata: metadata.map(|m| m.clone())
+        });
+        self.offset += size;
+        Ok(self.entries.last().unwrap())
+    }
+
+    /// Writes the manifest, returning it with the underlying writer.
+    pub fn finish(mut self, target: &Uuid, created_at: i64) -> IoResult<(BundleManifest, W)> {
+        let manifest = BundleManifest {
+            target: target.clone(),
+            created_at: created_at,
+            streams_size: self.offset,
+            entries: self.entries
+        };
+        let encoded = json::encode(&manifest);
+        try!(self.inner.write(encoded.as_bytes()));
+        try!(self.inner.write_be_u64(encoded.len() as u64));
+        try!(self.inner.write(BUNDLE_MAGIC));
+        try!(self.inner.flush());
+        Ok((manifest, self.inner))
+    }
+}
+
+
+/// The manifest of a bundle, or `None` if this isn't one.
+pub fn read_manifest<R: Reader + Seek>(reader: &mut R) -> IoResult<Option<BundleManifest>> {
+    let trailer_size = (8 + BUNDLE_MAGIC.len()) as i64;
+    try!(reader.seek(0, SeekEnd));
+    let total_size = try!(reader.tell());
+    if total_size < trailer_size as u64 {
+        return Ok(None);
+    }
+    try!(reader.seek(-trailer_size, SeekEnd));
+    let manifest_size = try!(reader.read_be_u64());
+    if try!(reader.read_exact(BUNDLE_MAGIC.len())).as_slice() != BUNDLE_MAGIC {
+        return Ok(None);
+    }
+    if manifest_size > MAX_MANIFEST_SIZE || manifest_size + trailer_size as u64 > total_size {
+        return Err(bundle_error("malformed bundle trailer", None));
+    }
+    try!(reader.seek(total_size as i64 - trailer_size - manifest_size as i64, SeekSet));
+    let encoded = try!(reader.read_exact(manifest_size as uint));
+    let decoded = match String::from_utf8(encoded) {
+        Ok(string) => json::decode(string.as_slice()),
+        Err(_) => return Err(bundle_error("malformed bundle manifest", None))
+    };
+    match decoded {
+        Ok(manifest) => Ok(Some(manifest)),
+        Err(err) => Err(bundle_error("malformed bundle manifest",
+            Some(format!("{}", err))))
+    }
+}
+
+
+/// A reader over one stream of the bundle.
+pub fn open_entry<'a, R: Reader + Seek>(reader: &'a mut R, entry: &BundleEntry) -> IoResult<LimitReader<RefReader<'a, R>>> {
+    try!(reader.seek(entry.offset as i64, SeekSet));
+    Ok(LimitReader::new(reader.by_ref(), entry.size as uint))
+}
+
+
+#[test]
+fn test_bundle_roundtrip() {
+    let first = Uuid::from_bytes(&[1u8, ..16]).unwrap();
+    let second = Uuid::from_bytes(&[2u8, ..16]).unwrap();
+
+    let mut bundle = BundleWriter::new(MemWriter::new());
+    assert!(bundle.add(&first, None, None, &mut BufReader::new(b"first stream")).is_ok());
+    assert!(bundle.add(&second, Some(&first), None, &mut BufReader::new(b"second")).is_ok());
+    let (written, writer) = bundle.finish(&second, 0).ok().unwrap();
+    assert_eq!(written.streams_size, 18);
+
+    let bytes = writer.unwrap();
+    assert_eq!(bytes.slice_to(12), b"first stream".as_slice());
+
+    let mut reader = BufReader::new(bytes.as_slice());
+    let manifest = read_manifest(&mut reader).ok().unwrap().unwrap();
+    assert_eq!(manifest.target, second);
+    assert_eq!(manifest.entries.len(), 2);
+    assert_eq!(manifest.entries[1].parent, Some(first));
+
+    let stream = open_entry(&mut reader, &manifest.entries[1]).ok().unwrap().read_to_end();
+    assert_eq!(stream.ok().unwrap().as_slice(), b"second");
+
+    let mut not_bundle = BufReader::new(b"btrfs-stream\x00");
+    assert!(read_manifest(&mut not_bundle).ok().unwrap().is_none());
+}
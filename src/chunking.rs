@@ -0,0 +1,364 @@
+use std::cmp::min;
+use std::io::{File, IoResult, IoError, OtherIoError, EndOfFile, Seek, SeekEnd, USER_RWX};
+use std::io::{USER_READ, GROUP_READ, OTHER_READ, standard_error};
+use std::io::fs::{mkdir_recursive, rename, readdir, unlink, chmod, stat};
+use std::collections::HashSet;
+use std::slice::bytes::copy_memory;
+
+use serialize::hex::{ToHex, FromHex};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use uuid::Uuid;
+
+#[cfg(test)]
+use std::io::{MemWriter, BufReader, TempDir};
+
+
+// A chunked object is a recipe: this magic, then entries of
+//
+//     len: u32be, sha256: [u8, ..32]
+//
+// naming the chunks that make up the stream, in order.  An entry with
+// a zero length (and no hash) ends the recipe and is followed by the
+// total stream length as a u64be.  Chunks live under `chunks/` in the
+// repository, named by the hex of their SHA-256, and are shared by
+// every recipe that contains them.
+pub static CHUNKED_MAGIC: &'static [u8] = b"btrfs-backup-c\x00\x01";
+
+pub static CHUNK_DIRECTORY: &'static str = "chunks";
+
+// Cut points are chosen by a gear hash over the content, so an insertion
+// only disturbs the chunks around it.  The mask gives ~256KiB chunks.
+static MIN_CHUNK_SIZE: uint = 64 * 1024;
+static MAX_CHUNK_SIZE: uint = 1024 * 1024;
+static CUT_MASK: u64 = (1 << 18) - 1;
+
+static HASH_SIZE: uint = 32;
+static TRAILER_SIZE: i64 = 12;
+
+
+fn chunk_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail
+    }
+}
+
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut out = Vec::from_elem(HASH_SIZE, 0u8);
+    hasher.result(out.as_mut_slice());
+    out
+}
+
+
+// The gear table must never change: chunks cut with a different table
+// still read back fine, but no longer deduplicate against older ones.
+fn gear_table() -> Vec<u64> {
+    let mut state: u64 = 0x6274726673626b70;
+    Vec::from_fn(256, |_| {
+        // splitmix64
+        state += 0x9e3779b97f4a7c15;
+        let mut z = state;
+        z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9;
+        z = (z ^ (z >> 27)) * 0x94d049bb133111eb;
+        z ^ (z >> 31)
+    })
+}
+
+
+pub struct ChunkStore {
+    root: Path
+}
+
+
+impl ChunkStore {
+    pub fn new(repo_root: &Path) -> ChunkStore {
+        ChunkStore {
+            root: repo_root.join(CHUNK_DIRECTORY)
+        }
+    }
+
+    pub fn chunk_path(&self, hash: &[u8]) -> Path {
+        let hex = hash.to_hex();
+        self.root.join(hex.as_slice().slice_to(2)).join(hex.as_slice())
+    }
+
+    /// Stores a chunk unless it is already present.  Returns whether it
+    /// was new.  Two writers storing the same chunk each write their own
+    /// temporary file; whichever loses the race finds the chunk there.
+    pub fn put(&self, hash: &[u8], data: &[u8]) -> IoResult<bool> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            return Ok(false);
+        }
+        try!(mkdir_recursive(&path.dir_path(), USER_RWX));
+        let tmp_path = path.with_extension(
+            format!("{}.tmp", Uuid::new_v4().to_hyphenated_string()));
+        {
+            let mut file = try!(File::create(&tmp_path));
+            try!(file.write(data));
+            try!(file.fsync());
+        }
+        match rename(&tmp_path, &path) {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                let _ = unlink(&tmp_path);
+                if path.exists() { Ok(false) } else { Err(err) }
+            }
+        }
+    }
+
+    /// Deletes every chunk not in `referenced`, along with leftovers of
+    /// interrupted writes.  Chunks written at or after `kept_since`, in
+    /// seconds since the epoch, are kept regardless.  The caller must
+    /// hold the repository lock exclusively, or it will delete chunks of
+    /// uploads in progress.
+    pub fn collect_garbage(&self, referenced: &HashSet<Vec<u8>>,
+                           kept_since: Option<i64>) -> IoResult<Vec<Path>> {
+        let mut removed = Vec::new();
+        if !self.root.exists() {
+            return Ok(removed);
+        }
+        for prefix in try!(readdir(&self.root)).iter() {
+            for path in try!(readdir(prefix)).iter() {
+                let keep = match path.filename_str().and_then(|name| name.from_hex().ok()) {
+                    Some(ref hash) if referenced.contains(hash) => true,
+                    Some(_) => match kept_since {
+                        Some(since) => (try!(stat(path)).modified / 1000) as i64 >= since,
+                        None => false
+                    },
+                    None => false
+                };
+                if !keep {
+                    try!(unlink(path));
+                    removed.push(path.clone());
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Makes every stored chunk read-only.  Returns how many there are.
+    pub fn seal_all(&self) -> IoResult<uint> {
+        let mut count = 0u;
+        if !self.root.exists() {
+            return Ok(count);
+        }
+        for prefix in try!(readdir(&self.root)).iter() {
+            for path in try!(readdir(prefix)).iter() {
+                if path.extension().is_none() {
+                    try!(chmod(path, USER_READ | GROUP_READ | OTHER_READ));
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> IoResult<Vec<u8>> {
+        let path = self.chunk_path(hash);
+        let data = try!(try!(File::open(&path)).read_to_end());
+        if sha256(data.as_slice()).as_slice() != hash {
+            return Err(chunk_error("chunk is corrupt",
+                Some(format!("{}", path.display()))));
+        }
+        Ok(data)
+    }
+}
+
+
+pub struct RecipeEntry {
+    pub len: u32,
+    pub hash: Vec<u8>
+}
+
+
+pub struct ChunkingWriter<W> {
+    inner: W,
+    store: ChunkStore,
+    gear: Vec<u64>,
+    hash: u64,
+    buf: Vec<u8>,
+    raw_total: u64
+}
+
+
+impl<W: Writer> ChunkingWriter<W> {
+    pub fn new(mut inner: W, store: ChunkStore) -> IoResult<ChunkingWriter<W>> {
+        try!(inner.write(CHUNKED_MAGIC));
+        Ok(ChunkingWriter {
+            inner: inner,
+            store: store,
+            gear: gear_table(),
+            hash: 0,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            raw_total: 0
+        })
+    }
+
+    fn emit_chunk(&mut self) -> IoResult<()> {
+        if self.buf.len() == 0 {
+            return Ok(());
+        }
+        let hash = sha256(self.buf.as_slice());
+        try!(self.store.put(hash.as_slice(), self.buf.as_slice()));
+        try!(self.inner.write_be_u32(self.buf.len() as u32));
+        try!(self.inner.write(hash.as_slice()));
+        self.raw_total += self.buf.len() as u64;
+        self.buf.clear();
+        self.hash = 0;
+        Ok(())
+    }
+
+    /// Stores the last chunk and ends the recipe, returning the
+    /// underlying writer.
+    pub fn finish(mut self) -> IoResult<W> {
+        try!(self.emit_chunk());
+        try!(self.inner.write_be_u32(0));
+        try!(self.inner.write_be_u64(self.raw_total));
+        try!(self.inner.flush());
+        Ok(self.inner)
+    }
+}
+
+
+impl<W: Writer> Writer for ChunkingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        for byte in buf.iter() {
+            self.buf.push(*byte);
+            self.hash = (self.hash << 1) + self.gear[*byte as uint];
+            let len = self.buf.len();
+            if (len >= MIN_CHUNK_SIZE && self.hash & CUT_MASK == 0) || len >= MAX_CHUNK_SIZE {
+                try!(self.emit_chunk());
+            }
+        }
+        Ok(())
+    }
+}
+
+
+pub fn read_recipe_entry(reader: &mut Reader) -> IoResult<Option<RecipeEntry>> {
+    let len = try!(reader.read_be_u32());
+    if len == 0 {
+        return Ok(None);
+    }
+    let hash = try!(reader.read_exact(HASH_SIZE));
+    Ok(Some(RecipeEntry { len: len, hash: hash }))
+}
+
+
+/// Lists the chunks of a recipe whose magic has already been consumed.
+pub fn read_recipe(reader: &mut Reader) -> IoResult<Vec<RecipeEntry>> {
+    let mut out = Vec::new();
+    loop {
+        match try!(read_recipe_entry(reader)) {
+            Some(entry) => out.push(entry),
+            None => return Ok(out)
+        }
+    }
+}
+
+
+/// Reads the stream length from the trailer of a complete recipe.
+pub fn read_recipe_size<R: Reader + Seek>(reader: &mut R) -> IoResult<u64> {
+    try!(reader.seek(-TRAILER_SIZE, SeekEnd));
+    if try!(reader.read_be_u32()) != 0 {
+        return Err(chunk_error("recipe is missing its trailer", None));
+    }
+    reader.read_be_u64()
+}
+
+
+pub struct ChunkedReader<R> {
+    recipe: R,
+    store: ChunkStore,
+    chunk: Vec<u8>,
+    pos: uint,
+    finished: bool
+}
+
+
+impl<R: Reader> ChunkedReader<R> {
+    /// For a recipe reader that has already consumed `CHUNKED_MAGIC`.
+    pub fn after_magic(recipe: R, store: ChunkStore) -> ChunkedReader<R> {
+        ChunkedReader {
+            recipe: recipe,
+            store: store,
+            chunk: Vec::new(),
+            pos: 0,
+            finished: false
+        }
+    }
+
+    fn next_chunk(&mut self) -> IoResult<()> {
+        self.pos = 0;
+        match try!(read_recipe_entry(&mut self.recipe)) {
+            Some(entry) => {
+                self.chunk = try!(self.store.get(entry.hash.as_slice()));
+                if self.chunk.len() != entry.len as uint {
+                    return Err(chunk_error("chunk has the wrong length", None));
+                }
+            },
+            None => {
+                self.chunk.clear();
+                self.finished = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl<R: Reader> Reader for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.pos == self.chunk.len() {
+            if self.finished {
+                return Err(standard_error(EndOfFile));
+            }
+            try!(self.next_chunk());
+        }
+        let len = min(buf.len(), self.chunk.len() - self.pos);
+        copy_memory(buf, self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+#[test]
+fn test_chunking_roundtrip_and_dedup() {
+    let tmpdir = TempDir::new("chunking").unwrap();
+
+    // Pseudo-random content, so the gear hash finds cut points
+    let mut state: u32 = 1;
+    let mut data: Vec<u8> = Vec::new();
+    for _ in range(0u, 3 * MAX_CHUNK_SIZE) {
+        state = state * 1103515245 + 12345;
+        data.push((state >> 16) as u8);
+    }
+
+    let mut recipes = Vec::new();
+    for _ in range(0u, 2) {
+        let store = ChunkStore::new(tmpdir.path());
+        let mut writer = ChunkingWriter::new(MemWriter::new(), store).unwrap();
+        assert!(writer.write(data.as_slice()).is_ok());
+        recipes.push(writer.finish().unwrap().unwrap());
+    }
+    assert_eq!(recipes[0], recipes[1]);
+
+    let mut recipe = BufReader::new(recipes[0][CHUNKED_MAGIC.len()..]);
+    let entries = read_recipe(&mut recipe).unwrap();
+    assert!(entries.len() > 1);
+    assert!(entries.iter().all(|e| e.len as uint <= MAX_CHUNK_SIZE));
+
+    let store = ChunkStore::new(tmpdir.path());
+    let recipe = BufReader::new(recipes[0][CHUNKED_MAGIC.len()..]);
+    let mut reader = ChunkedReader::after_magic(recipe, store);
+    assert_eq!(reader.read_to_end().unwrap(), data);
+}
@@ -0,0 +1,225 @@
+use std::cmp::min;
+use std::io::{IoResult, IoError, OtherIoError, EndOfFile, Seek, SeekEnd, standard_error};
+use std::slice::bytes::copy_memory;
+
+use flate::{deflate_bytes, inflate_bytes};
+
+#[cfg(test)]
+use std::io::{MemWriter, BufReader};
+
+
+// A compressed object is this magic, then frames of
+//
+//     raw_len: u32be, stored_len: u32be, data: [u8, ..stored_len]
+//
+// where stored_len == raw_len means the frame did not compress and is
+// stored as-is.  A frame with both lengths zero ends the object and is
+// followed by the total raw length as a u64be, so the logical size can
+// be read without inflating anything.
+pub static COMPRESSED_MAGIC: &'static [u8] = b"btrfs-backup-z\x00\x01";
+
+static FRAME_SIZE: uint = 1 << 20;
+
+static TRAILER_SIZE: i64 = 16;
+
+
+fn deflate_error() -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: "deflate failed",
+        detail: None
+    }
+}
+
+
+fn inflate_error(detail: String) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: "corrupt compressed object",
+        detail: Some(detail)
+    }
+}
+
+
+pub struct CompressWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+    raw_total: u64
+}
+
+
+impl<W: Writer> CompressWriter<W> {
+    pub fn new(mut inner: W) -> IoResult<CompressWriter<W>> {
+        try!(inner.write(COMPRESSED_MAGIC));
+        Ok(CompressWriter {
+            inner: inner,
+            buf: Vec::with_capacity(FRAME_SIZE),
+            raw_total: 0
+        })
+    }
+
+    fn write_frame(&mut self) -> IoResult<()> {
+        if self.buf.len() == 0 {
+            return Ok(());
+        }
+        let compressed = match deflate_bytes(self.buf.as_slice()) {
+            Some(compressed) => compressed,
+            None => return Err(deflate_error())
+        };
+        try!(self.inner.write_be_u32(self.buf.len() as u32));
+        if compressed.len() < self.buf.len() {
+            try!(self.inner.write_be_u32(compressed.len() as u32));
+            try!(self.inner.write(compressed.as_slice()));
+        } else {
+            try!(self.inner.write_be_u32(self.buf.len() as u32));
+            try!(self.inner.write(self.buf.as_slice()));
+        }
+        self.raw_total += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes out any buffered data and the trailer, returning the
+    /// underlying writer.  Dropping a `CompressWriter` without calling
+    /// this leaves a truncated object.
+    pub fn finish(mut self) -> IoResult<W> {
+        try!(self.write_frame());
+        try!(self.inner.write_be_u32(0));
+        try!(self.inner.write_be_u32(0));
+        try!(self.inner.write_be_u64(self.raw_total));
+        try!(self.inner.flush());
+        Ok(self.inner)
+    }
+}
+
+
+impl<W: Writer> Writer for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut buf = buf;
+        while buf.len() > 0 {
+            let take = min(FRAME_SIZE - self.buf.len(), buf.len());
+            self.buf.push_all(buf[..take]);
+            buf = buf[take..];
+            if self.buf.len() == FRAME_SIZE {
+                try!(self.write_frame());
+            }
+        }
+        Ok(())
+    }
+}
+
+
+pub struct DecompressReader<R> {
+    inner: R,
+    frame: Vec<u8>,
+    pos: uint,
+    raw_total: u64,
+    finished: bool
+}
+
+
+impl<R: Reader> DecompressReader<R> {
+    pub fn new(mut inner: R) -> IoResult<DecompressReader<R>> {
+        let magic = try!(inner.read_exact(COMPRESSED_MAGIC.len()));
+        if magic.as_slice() != COMPRESSED_MAGIC {
+            return Err(inflate_error(format!("bad magic")));
+        }
+        Ok(DecompressReader::after_magic(inner))
+    }
+
+    /// For a reader that has already consumed `COMPRESSED_MAGIC`.
+    pub fn after_magic(inner: R) -> DecompressReader<R> {
+        DecompressReader {
+            inner: inner,
+            frame: Vec::new(),
+            pos: 0,
+            raw_total: 0,
+            finished: false
+        }
+    }
+
+    fn next_frame(&mut self) -> IoResult<()> {
+        let raw_len = try!(self.inner.read_be_u32()) as uint;
+        let stored_len = try!(self.inner.read_be_u32()) as uint;
+        self.pos = 0;
+        if raw_len == 0 && stored_len == 0 {
+            let expected = try!(self.inner.read_be_u64());
+            if expected != self.raw_total {
+                return Err(inflate_error(format!(
+                    "trailer claims {} bytes, read {}", expected, self.raw_total)));
+            }
+            self.frame.clear();
+            self.finished = true;
+            return Ok(());
+        }
+        let stored = try!(self.inner.read_exact(stored_len));
+        self.frame = if stored_len == raw_len {
+            stored
+        } else {
+            match inflate_bytes(stored.as_slice()) {
+                Some(inflated) => inflated.as_slice().to_vec(),
+                None => return Err(inflate_error(format!("inflate failed")))
+            }
+        };
+        if self.frame.len() != raw_len {
+            return Err(inflate_error(format!(
+                "frame inflated to {} bytes, expected {}", self.frame.len(), raw_len)));
+        }
+        self.raw_total += raw_len as u64;
+        Ok(())
+    }
+}
+
+
+impl<R: Reader> Reader for DecompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.pos == self.frame.len() {
+            if self.finished {
+                return Err(standard_error(EndOfFile));
+            }
+            try!(self.next_frame());
+        }
+        let len = min(buf.len(), self.frame.len() - self.pos);
+        copy_memory(buf, self.frame[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+/// Reads the logical size from the trailer of a complete compressed object.
+pub fn read_raw_size<R: Reader + Seek>(reader: &mut R) -> IoResult<u64> {
+    try!(reader.seek(-TRAILER_SIZE, SeekEnd));
+    let raw_len = try!(reader.read_be_u32());
+    let stored_len = try!(reader.read_be_u32());
+    if raw_len != 0 || stored_len != 0 {
+        return Err(inflate_error(format!("missing trailer")));
+    }
+    reader.read_be_u64()
+}
+
+
+#[test]
+fn test_compress_roundtrip() {
+    let mut data: Vec<u8> = Vec::new();
+    for i in range(0u, FRAME_SIZE * 2 + 1234) {
+        data.push((i / 7 % 251) as u8);
+    }
+
+    let mut writer = CompressWriter::new(MemWriter::new()).unwrap();
+    assert!(writer.write(data.as_slice()).is_ok());
+    let stored = writer.finish().unwrap().unwrap();
+    assert!(stored.len() < data.len());
+
+    let mut reader = DecompressReader::new(BufReader::new(stored.as_slice())).unwrap();
+    assert_eq!(reader.read_to_end().unwrap(), data);
+}
+
+
+#[test]
+fn test_compress_empty() {
+    let writer = CompressWriter::new(MemWriter::new()).unwrap();
+    let stored = writer.finish().unwrap().unwrap();
+    let mut reader = DecompressReader::new(BufReader::new(stored.as_slice())).unwrap();
+    assert_eq!(reader.read_to_end().unwrap(), Vec::new());
+}
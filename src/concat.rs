@@ -0,0 +1,355 @@
+use std::io::{BufReader, BufferedReader, IoResult, IoError, OtherIoError, EndOfFile};
+use std::io::standard_error;
+use std::cmp::min;
+use std::collections::{RingBuf, Deque};
+
+use uuid::Uuid;
+
+use btrfs::{
+    BtrfsHeader,
+    BtrfsCommandBuf,
+    BtrfsSubvol,
+    BtrfsSnapshot,
+    BtrfsParseResult,
+    ReadError,
+    ProtocolError,
+    BtrfsParseError,
+    BTRFS_SEND_C_SUBVOL,
+    BTRFS_SEND_C_SNAPSHOT,
+    BTRFS_SEND_C_END,
+};
+use object::{open_object, ObjectReader};
+
+#[cfg(test)]
+use std::io::{File, TempDir};
+#[cfg(test)]
+use btrfs::BtrfsCommand;
+
+macro_rules! some_try(
+    ($e:expr) => (match $e { Ok(e) => e, Err(err) => return Some(Err(err)) })
+)
+
+
+fn chain_error(desc: &'static str, detail: String) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: Some(detail)
+    }
+}
+
+
+// Opens an object of the chain, past a stream header we can replay
+fn open_stream(path: &Path) -> IoResult<BufferedReader<ObjectReader>> {
+    let mut reader = BufferedReader::new(try!(open_object(path)));
+    match BtrfsHeader::parse(&mut reader) {
+        Ok(ref header) if header.version == 1 => Ok(reader),
+        Ok(header) => Err(chain_error("unsupported send stream version",
+            format!("{}: version {}", path.display(), header.version))),
+        // Running out here must not pass for the end of the chain
+        Err(ReadError(ref err)) if err.kind == EndOfFile => Err(chain_error(
+            "truncated stream in chain", format!("{}: no stream header", path.display()))),
+        Err(ReadError(err)) => Err(err),
+        Err(err) => Err(chain_error("malformed stream in chain",
+            format!("{}: {}", path.display(), err)))
+    }
+}
+
+
+pub struct BtrfsCommandConcatIter {
+    paths: RingBuf<Path>,
+    current_path: Path,
+    reader: Option<BufferedReader<ObjectReader>>,
+    // Whether the current stream has sent its END command
+    ended: bool,
+    last_path: Path,
+    last_snap_cmd: Option<BtrfsSnapshot>,
+    // What the last stream is a snapshot of
+    last_parent: Uuid,
+    last_reader: Option<BufferedReader<ObjectReader>>,
+    curr_uuid: Option<Uuid>,
+    retarget: bool
+}
+
+// iters: Vec<BtrfsCommandIter>
+impl BtrfsCommandConcatIter {
+    pub fn new(paths: Vec<Path>) -> IoResult<BtrfsCommandConcatIter> {
+        let mut paths: RingBuf<Path> = FromIterator::from_iter(paths.into_iter());
+        if paths.len() < 2 {
+            return Err(chain_error("chain too short",
+                format!("{} streams, need at least 2", paths.len())));
+        }
+
+        let last_path = paths.pop().unwrap();
+        let mut last_reader = try!(open_stream(&last_path));
+
+        let last_snap_cmd = match BtrfsCommandBuf::read(&mut last_reader) {
+            Ok(command) => match BtrfsSnapshot::load(command.get_data()) {
+                Ok(snapshot) => snapshot,
+                Err(err) => return Err(chain_error("error reading last snapshot",
+                    format!("{}: {}", last_path.display(), err)))
+            },
+            Err(err) => return Err(err)
+        };
+
+        let first_path = paths.pop_front().unwrap();
+        let first_reader = try!(open_stream(&first_path));
+
+        Ok(BtrfsCommandConcatIter {
+            paths: paths,
+            current_path: first_path,
+            reader: Some(first_reader),
+            ended: false,
+            last_path: last_path,
+            last_parent: last_snap_cmd.clone_uuid.clone(),
+            last_snap_cmd: Some(last_snap_cmd),
+            last_reader: Some(last_reader),
+            curr_uuid: None,
+            retarget: false
+        })
+    }
+
+    /// Like `new`, but the emitted SUBVOL command also takes the UUID and
+    /// ctransid of the last snapshot, so the output is a full backup of
+    /// that snapshot rather than of the first subvolume.
+    pub fn new_synthetic(paths: Vec<Path>) -> IoResult<BtrfsCommandConcatIter> {
+        let mut iter = try!(BtrfsCommandConcatIter::new(paths));
+        iter.retarget = true;
+        Ok(iter)
+    }
+
+    fn chain_broken(&self, expected: &Uuid) -> BtrfsParseError {
+        ProtocolError(format!("{}: not built on {}, the snapshot before it",
+            self.current_path.display(), expected.to_hyphenated_string()))
+    }
+
+    // Each stream must follow on from the one before: one full, then
+    // snapshots each of the last
+    #[inline]
+    fn validation_hook(&mut self, command: &BtrfsCommandBuf) -> BtrfsParseResult<()> {
+        if command.get_kind() == Some(BTRFS_SEND_C_SUBVOL) {
+            if self.curr_uuid.is_some() {
+                return Err(ProtocolError(format!("{}: a second full stream in the chain",
+                    self.current_path.display())));
+            }
+            let subvol = try!(BtrfsSubvol::load(command.get_data()));
+            self.curr_uuid = Some(subvol.uuid);
+        }
+        if command.get_kind() == Some(BTRFS_SEND_C_SNAPSHOT) {
+            let snap = try!(BtrfsSnapshot::load(command.get_data()));
+            match self.curr_uuid {
+                Some(ref uuid) if *uuid == snap.clone_uuid => (),
+                Some(ref uuid) => return Err(self.chain_broken(uuid)),
+                None => return Err(ProtocolError(format!("{}: the chain starts with a snapshot",
+                    self.current_path.display())))
+            }
+            self.curr_uuid = Some(snap.uuid);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn suppress_command(&self, command: &BtrfsCommandBuf) -> bool {
+        (
+            (
+                command.get_kind() == Some(BTRFS_SEND_C_END) &&
+                self.last_reader.is_some()
+            ) || (
+                command.get_kind() == Some(BTRFS_SEND_C_SNAPSHOT)
+            )
+        )
+    }
+
+    #[inline]
+    fn transform(&mut self, command: BtrfsCommandBuf) -> BtrfsParseResult<BtrfsCommandBuf> {
+        if self.last_snap_cmd.is_some() && command.get_kind() == Some(BTRFS_SEND_C_SUBVOL) {
+            let mut subv = try!(BtrfsSubvol::load(command.get_data()));
+            let last_snap = self.last_snap_cmd.take().unwrap();
+            if self.retarget {
+                subv.uuid = last_snap.uuid;
+                subv.ctransid = last_snap.ctransid;
+            }
+            subv.name = last_snap.name;
+            let encapped = subv.encap().serialize();
+            match BtrfsCommandBuf::read(&mut BufReader::new(encapped[])) {
+                Ok(buf) => Ok(buf),
+                Err(err) => Err(ReadError(err))
+            }
+        } else {
+            Ok(command)
+        }
+    }
+
+    // The next command of the chain, moving on to the next stream when
+    // one sends END.  A stream that stops before its END is truncated,
+    // not finished.
+    fn current_command<'a>(&'a mut self) -> Option<BtrfsParseResult<BtrfsCommandBuf>> {
+        if self.reader.is_some() {
+            match BtrfsCommandBuf::read(self.reader.as_mut().unwrap()) {
+                Ok(buf) => {
+                    match buf.get_kind() {
+                        Some(BTRFS_SEND_C_END) => self.ended = true,
+                        Some(_) => (),
+                        None => return Some(Err(ProtocolError(format!(
+                            "{}: unknown command", self.current_path.display()))))
+                    }
+                    some_try!(self.validation_hook(&buf));
+                    return Some(self.transform(buf));
+                },
+                Err(ref err) if err.kind == EndOfFile && self.ended => {
+                    self.reader = None;
+                },
+                Err(ref err) if err.kind == EndOfFile => {
+                    return Some(Err(ProtocolError(format!("{}: stream ends without END",
+                        self.current_path.display()))));
+                },
+                Err(err) => return Some(Err(ReadError(err)))
+            }
+        }
+        if self.paths.is_empty() && self.last_reader.is_some() {
+            // Its SNAPSHOT command was read up front, so check it here
+            let last_parent = self.last_parent.clone();
+            self.current_path = self.last_path.clone();
+            if self.curr_uuid.as_ref() != Some(&last_parent) {
+                return Some(Err(match self.curr_uuid {
+                    Some(ref uuid) => self.chain_broken(uuid),
+                    None => ProtocolError(format!("{}: nothing in the chain before it",
+                        self.current_path.display()))
+                }));
+            }
+            self.curr_uuid = Some(last_parent);
+            self.reader = self.last_reader.take();
+            self.ended = false;
+            return self.current_command();
+        }
+        let path = match self.paths.pop_front() {
+            Some(path) => path,
+            None => return None
+        };
+        self.reader = Some(match open_stream(&path) {
+            Ok(reader) => reader,
+            Err(err) => return Some(Err(ReadError(err)))
+        });
+        self.current_path = path;
+        self.ended = false;
+        self.current_command()
+    }
+}
+
+impl Iterator<BtrfsParseResult<BtrfsCommandBuf>> for BtrfsCommandConcatIter {
+    fn next(&mut self) -> Option<BtrfsParseResult<BtrfsCommandBuf>> {
+        loop {
+            match self.current_command() {
+                Some(Ok(command)) => {
+                    if !self.suppress_command(&command) {
+                        return Some(Ok(command))
+                    }
+                },
+                Some(Err(err)) => return Some(Err(err)),
+                None => return None
+            }
+        }
+    }
+}
+
+pub fn write_out(mut iter: BtrfsCommandConcatIter, writer: &mut Writer) -> BtrfsParseResult<()> {
+    match writer.write(BtrfsHeader { version: 1 }.serialize()[]) {
+        Ok(()) => (),
+        Err(err) => return Err(ReadError(err))
+    }
+    for command in iter {
+        let command = try!(command);
+        match writer.write(command.as_slice()) {
+            Ok(()) => (),
+            Err(err) => return Err(ReadError(err))
+        }
+    }
+    Ok(())
+}
+
+
+/// The output of `write_out` as a `Reader`, for sending on.
+pub struct ConcatReader {
+    iter: BtrfsCommandConcatIter,
+    buf: Vec<u8>,
+    pos: uint
+}
+
+
+impl ConcatReader {
+    pub fn new(iter: BtrfsCommandConcatIter) -> ConcatReader {
+        ConcatReader {
+            iter: iter,
+            buf: BtrfsHeader { version: 1 }.serialize(),
+            pos: 0
+        }
+    }
+}
+
+
+impl Reader for ConcatReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.pos == self.buf.len() {
+            match self.iter.next() {
+                Some(Ok(command)) => {
+                    self.buf = command.as_slice().to_vec();
+                    self.pos = 0;
+                },
+                Some(Err(ReadError(err))) => return Err(err),
+                Some(Err(err)) => return Err(IoError {
+                    kind: OtherIoError,
+                    desc: "malformed stream in chain",
+                    detail: Some(format!("{}", err))
+                }),
+                None => return Err(standard_error(EndOfFile))
+            }
+        }
+        let len = min(buf.len(), self.buf.len() - self.pos);
+        buf.slice_to_mut(len).copy_from(self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+#[test]
+fn test_truncated_middle_stream() {
+    let uuids: Vec<Uuid> = range(1u8, 4).map(|idx| Uuid::from_bytes(&[idx, ..16]).unwrap()).collect();
+    let first = BtrfsSubvol { name: b"snap".to_vec(), uuid: uuids[0].clone(), ctransid: 1 }.encap();
+    let snapshot = |idx: uint| BtrfsSnapshot {
+        name: b"snap".to_vec(),
+        uuid: uuids[idx].clone(),
+        ctransid: idx as u64 + 1,
+        clone_uuid: uuids[idx - 1].clone(),
+        clone_ctransid: idx as u64
+    }.encap();
+    let end = BtrfsCommand::from_kind(BTRFS_SEND_C_END, Vec::new()).serialize();
+
+    let tmpdir = TempDir::new("concat").unwrap();
+    let mut paths = Vec::new();
+    for (idx, command) in [first, snapshot(1), snapshot(2)].iter().enumerate() {
+        let mut stream = BtrfsHeader { version: 1 }.serialize();
+        stream.push_all(command.serialize().as_slice());
+        stream.push_all(end.as_slice());
+        let path = tmpdir.path().join(format!("{}", idx));
+        File::create(&path).write(stream.as_slice()).unwrap();
+        paths.push(path);
+    }
+
+    let whole = ConcatReader::new(BtrfsCommandConcatIter::new(paths.clone()).unwrap()).read_to_end();
+    assert!(whole.is_ok());
+
+    // Cut the middle stream off before its END
+    let middle = File::open(&paths[1]).read_to_end().unwrap();
+    File::create(&paths[1]).write(middle.slice_to(middle.len() - end.len())).unwrap();
+    let truncated = ConcatReader::new(BtrfsCommandConcatIter::new(paths.clone()).unwrap()).read_to_end();
+    assert!(truncated.is_err());
+
+    // A stream that isn't built on the one before it
+    let mut stream = BtrfsHeader { version: 1 }.serialize();
+    stream.push_all(snapshot(2).serialize().as_slice());
+    stream.push_all(end.as_slice());
+    File::create(&paths[1]).write(stream.as_slice()).unwrap();
+    let broken = ConcatReader::new(BtrfsCommandConcatIter::new(paths).unwrap()).read_to_end();
+    assert!(broken.is_err());
+}
@@ -0,0 +1,117 @@
+use std::io::{File, IoResult, IoError, OtherIoError};
+use std::io::fs::rename;
+
+use serialize::json;
+
+use quota::Quota;
+use auth::ClientIdentity;
+use encryption::create_private;
+
+
+// Settings that belong to a repository rather than to whichever tool is
+// running on it.  A missing file means every default.
+pub static CONFIG_FILENAME: &'static str = "config.json";
+
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct RepositoryConfig {
+    // Where backupserver-tier moves old objects.  Relative paths are
+    // taken from the repository root.
+    pub cold_root: Option<String>,
+    // Limits on everything stored in this repository or namespace
+    pub quota: Option<Quota>,
+    // Limits on what each client stores, by the name it uploads under.
+    // Uploads that give no name share one allowance.
+    pub client_quota: Option<Quota>,
+    // Committed objects are made read-only and clients may not delete
+    // anything.  Only local tools remove objects, and only once they are
+    // older than the retention period.
+    pub append_only: Option<bool>,
+    pub retention_days: Option<u64>,
+    // Clients may delete and prune nodes.  Off unless set.
+    pub remote_delete: Option<bool>,
+    // A client asking for a namespace that doesn't exist gets it
+    // created.  Off unless set; backupserver-init creates them otherwise.
+    // Once clients authenticate, only admins may, whatever this says.
+    pub create_namespaces: Option<bool>,
+    // Who may connect.  When set, in the root config, every client has
+    // to prove it holds one of these keys.
+    pub clients: Option<Vec<ClientIdentity>>
+}
+
+
+impl RepositoryConfig {
+    pub fn new() -> RepositoryConfig {
+        RepositoryConfig {
+            cold_root: None,
+            quota: None,
+            client_quota: None,
+            append_only: None,
+            retention_days: None,
+            remote_delete: None,
+            create_namespaces: None,
+            clients: None
+        }
+    }
+
+    pub fn load(root: &Path) -> IoResult<RepositoryConfig> {
+        let path = root.join(CONFIG_FILENAME);
+        if !path.exists() {
+            return Ok(RepositoryConfig::new());
+        }
+        let contents = try!(try!(File::open(&path)).read_to_string());
+        match json::decode(contents.as_slice()) {
+            Ok(config) => Ok(config),
+            Err(err) => Err(IoError {
+                kind: OtherIoError,
+                desc: "malformed repository config",
+                detail: Some(format!("{}", err))
+            })
+        }
+    }
+
+    pub fn save(&self, root: &Path) -> IoResult<()> {
+        let path = root.join(CONFIG_FILENAME);
+        let tmp_path = path.with_extension("tmp");
+        {
+            // It may hold client keys
+            let mut file = try!(create_private(&tmp_path));
+            try!(file.write_str(json::encode(self).as_slice()));
+            try!(file.fsync());
+        }
+        rename(&tmp_path, &path)
+    }
+
+    pub fn is_append_only(&self) -> bool {
+        self.append_only == Some(true)
+    }
+
+    pub fn allows_remote_delete(&self) -> bool {
+        self.remote_delete == Some(true)
+    }
+
+    pub fn allows_namespace_creation(&self) -> bool {
+        self.create_namespaces == Some(true)
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        self.clients.is_some()
+    }
+
+    pub fn find_client(&self, name: &str) -> Option<&ClientIdentity> {
+        match self.clients {
+            Some(ref clients) => clients.iter().find(|c| c.name.as_slice() == name),
+            None => None
+        }
+    }
+
+    /// How long, in seconds, an object in an append-only repository must
+    /// be kept.
+    pub fn retention_secs(&self) -> i64 {
+        self.retention_days.unwrap_or(0) as i64 * 24 * 60 * 60
+    }
+
+    pub fn cold_root(&self, root: &Path) -> Option<Path> {
+        self.cold_root.as_ref().map(|cold| root.join(cold.as_slice()))
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::io::{IoResult, BufferedReader, Listener, Acceptor, stderr};
+use std::io::net::pipe::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::task::spawn;
+
+use repository::Repository;
+use config::RepositoryConfig;
+use object::ObjectEncoding;
+use lock::{RepositoryLock, SharedLock};
+use inotify::{Watcher, Changed, Overflowed};
+use protocol::ProtocolServer;
+
+
+fn log(message: String) {
+    let mut stderr_writer = stderr();
+    let _ = stderr_writer.write(format!("SERVER: {}\n", message).as_bytes());
+}
+
+
+fn load_logged(ns_root: &Path, encoding: ObjectEncoding) -> IoResult<Repository> {
+    let _lock = try!(RepositoryLock::acquire(ns_root, SharedLock));
+    let mut repo = try!(Repository::load_from(ns_root));
+    repo.set_encoding(encoding);
+    for diagnostic in repo.diagnostics.iter() {
+        log(format!("skipped {}", diagnostic));
+    }
+    Ok(repo)
+}
+
+
+// Applies what the watcher sees to the shared repository until the
+// watch breaks.
+fn follow(ns_root: Path, encoding: ObjectEncoding, mut watcher: Watcher,
+          repo: Arc<Mutex<Repository>>) {
+    loop {
+        let events = match watcher.wait() {
+            Ok(events) => events,
+            Err(err) => {
+                log(format!("stopped watching {}: {}", ns_root.display(), err));
+                return;
+            }
+        };
+        for event in events.into_iter() {
+            match event {
+                Changed(path) => match repo.lock().refresh_path(&path) {
+                    Some(diagnostic) => log(format!("skipped {}", diagnostic)),
+                    None => ()
+                },
+                // Too much changed to follow, so start over.  Loaded
+                // before taking the lock, so sessions carry on meanwhile.
+                Overflowed => match load_logged(&ns_root, encoding.clone()) {
+                    Ok(fresh) => *repo.lock() = fresh,
+                    Err(err) => log(format!("error reloading {}: {}", ns_root.display(), err))
+                }
+            }
+        }
+    }
+}
+
+
+/// The namespaces a daemon has loaded, each kept in step with the disk
+/// by a task watching its directories.
+pub struct LiveRepositories {
+    root: Path,
+    encoding: ObjectEncoding,
+    open: Mutex<HashMap<Path, Arc<Mutex<Repository>>>>
+}
+
+
+impl LiveRepositories {
+    pub fn new(root: &Path, encoding: ObjectEncoding) -> LiveRepositories {
+        LiveRepositories {
+            root: root.clone(),
+            encoding: encoding,
+            open: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The namespace at `ns_root`, loaded on first use.
+    pub fn open(&self, ns_root: &Path) -> IoResult<Arc<Mutex<Repository>>> {
+        let mut open = self.open.lock();
+        match open.find(ns_root) {
+            Some(repo) => return Ok(repo.clone()),
+            None => ()
+        }
+
+        // Watching starts before loading, so nothing committed in between
+        // is missed
+        let mut watcher = try!(Watcher::new());
+        try!(watcher.watch(ns_root));
+        match try!(RepositoryConfig::load(ns_root)).cold_root(ns_root) {
+            Some(ref cold_root) if cold_root.exists() => try!(watcher.watch(cold_root)),
+            _ => ()
+        }
+        let repo = Arc::new(Mutex::new(try!(load_logged(ns_root, self.encoding.clone()))));
+
+        let (follow_root, encoding, follow_repo) =
+            (ns_root.clone(), self.encoding.clone(), repo.clone());
+        spawn(proc() {
+            follow(follow_root, encoding, watcher, follow_repo);
+        });
+        log(format!("watching {}", ns_root.display()));
+        open.insert(ns_root.clone(), repo.clone());
+        Ok(repo)
+    }
+}
+
+
+/// Serves every client that connects to the unix socket at `socket`,
+/// each on its own task.  Never returns unless the socket fails.
+pub fn listen(root: &Path, socket: &Path, pinned: Option<String>,
+              encoding: ObjectEncoding) -> IoResult<()> {
+    let live = Arc::new(LiveRepositories::new(root, encoding));
+    let mut acceptor = try!(UnixListener::bind(socket).listen());
+    log(format!("listening on {}", socket.display()));
+
+    for stream in acceptor.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log(format!("error accepting client: {}", err));
+                continue;
+            }
+        };
+        let (live, pinned) = (live.clone(), pinned.clone());
+        spawn(proc() {
+            let mut reader = BufferedReader::new(stream.clone());
+            let mut writer = stream;
+            let mut proto = ProtocolServer::new(&mut reader, &mut writer);
+            let result = proto.run_live(live.get_root(),
+                pinned.as_ref().map(|p| p.as_slice()),
+                |ns_root| live.open(ns_root));
+            match result {
+                Ok(()) => (),
+                Err(err) => log(format!("session ended: {}", err))
+            }
+        });
+    }
+    Ok(())
+}
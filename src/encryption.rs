@@ -0,0 +1,443 @@
+use std::cmp::min;
+use std::io::{File, IoResult, IoError, OtherIoError, EndOfFile, USER_READ, USER_WRITE, standard_error};
+use std::io::fs::chmod;
+use std::rand::{OsRng, Rng};
+use std::slice::bytes::copy_memory;
+
+use serialize::json;
+use serialize::hex::{ToHex, FromHex};
+
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+use uuid::Uuid;
+
+use btrfs::{
+    BtrfsCommand,
+    BtrfsSubvol,
+    BtrfsSnapshot,
+    BTRFS_SEND_C_SUBVOL,
+    BTRFS_SEND_C_SNAPSHOT,
+};
+use object::PlainObject;
+use repository::{BackupNode, FullBackup, IncrementalBackup, HotTier};
+
+#[cfg(test)]
+use std::io::{MemWriter, BufReader};
+
+
+// An encrypted object is this magic and a random per-object salt,
+// followed by frames of
+//
+//     last: u8, len: u32be, ciphertext: [u8, ..len], tag: [u8, ..16]
+//
+// sealed with ChaCha20-Poly1305 under a key derived from the repository
+// key and the salt.  The nonce is the frame number, and the frame number
+// and `last` flag are authenticated, so frames can't be reordered,
+// dropped or truncated from the end without detection.
+pub static ENCRYPTED_MAGIC: &'static [u8] = b"btrfs-backup-e\x00\x01";
+
+static FRAME_SIZE: uint = 1 << 16;
+static SALT_SIZE: uint = 16;
+static TAG_SIZE: uint = 16;
+static KEY_SIZE: uint = 32;
+
+static OBJECT_KEY_LABEL: &'static [u8] = b"btrfs-backup object key\x00";
+static ENVELOPE_KEY_LABEL: &'static [u8] = b"btrfs-backup envelope key\x00";
+
+
+fn crypto_error(desc: &'static str) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: None
+    }
+}
+
+
+/// Creates `path` readable by its owner only, for files holding keys.
+/// The mode is set before anything is written to it.
+pub fn create_private(path: &Path) -> IoResult<File> {
+    let file = try!(File::create(path));
+    try!(chmod(path, USER_READ | USER_WRITE));
+    Ok(file)
+}
+
+
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    for part in parts.iter() {
+        hmac.input(*part);
+    }
+    hmac.result().code().to_vec()
+}
+
+
+fn frame_aad(counter: u64, last: bool) -> [u8, ..9] {
+    let mut aad = [0u8, ..9];
+    for i in range(0u, 8) {
+        aad[i] = (counter >> (8 * i)) as u8;
+    }
+    aad[8] = last as u8;
+    aad
+}
+
+
+fn frame_nonce(counter: u64) -> [u8, ..8] {
+    let mut nonce = [0u8, ..8];
+    for i in range(0u, 8) {
+        nonce[i] = (counter >> (8 * i)) as u8;
+    }
+    nonce
+}
+
+
+/// The secret shared by the clients of a repository.  It never leaves
+/// the client; the server only sees ciphertext and envelopes.
+pub struct RepositoryKey {
+    key: Vec<u8>
+}
+
+
+impl RepositoryKey {
+    pub fn generate() -> IoResult<RepositoryKey> {
+        let mut rng = try!(OsRng::new());
+        let mut key = Vec::from_elem(KEY_SIZE, 0u8);
+        rng.fill_bytes(key.as_mut_slice());
+        Ok(RepositoryKey { key: key })
+    }
+
+    /// Reads a key stored as hex in a file.
+    pub fn load(path: &Path) -> IoResult<RepositoryKey> {
+        let contents = try!(try!(File::open(path)).read_to_string());
+        let key = match contents.as_slice().trim().from_hex() {
+            Ok(key) => key,
+            Err(_) => return Err(crypto_error("key file is not hex"))
+        };
+        if key.len() != KEY_SIZE {
+            return Err(crypto_error("key file has the wrong length"));
+        }
+        Ok(RepositoryKey { key: key })
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(create_private(path));
+        try!(file.write_str(self.key.as_slice().to_hex().as_slice()));
+        try!(file.write_str("\n"));
+        file.fsync()
+    }
+
+    fn object_key(&self, salt: &[u8]) -> Vec<u8> {
+        hmac_sha256(self.key.as_slice(), &[OBJECT_KEY_LABEL, salt])
+    }
+
+    fn envelope_signature(&self, envelope: &MetadataEnvelope) -> String {
+        let mut unsigned = envelope.clone();
+        unsigned.signature = None;
+        let encoded = json::encode(&unsigned);
+        let key = hmac_sha256(self.key.as_slice(), &[ENVELOPE_KEY_LABEL]);
+        hmac_sha256(key.as_slice(), &[encoded.as_bytes()]).as_slice().to_hex()
+    }
+
+    pub fn sign_envelope(&self, envelope: &mut MetadataEnvelope) {
+        envelope.signature = Some(self.envelope_signature(envelope));
+    }
+
+    pub fn verify_envelope(&self, envelope: &MetadataEnvelope) -> bool {
+        match envelope.signature {
+            Some(ref signature) => fixed_time_eq(
+                signature.as_bytes(),
+                self.envelope_signature(envelope).as_bytes()),
+            None => false
+        }
+    }
+}
+
+
+/// The stream header of an encrypted object, which the server needs to
+/// place the object in the graph.  It travels next to the ciphertext and
+/// may be signed with the repository key so clients can detect a server
+/// that tampers with it.
+#[deriving(Encodable, Decodable, Clone)]
+pub struct MetadataEnvelope {
+    pub name: Vec<u8>,
+    pub uuid: Uuid,
+    pub ctransid: u64,
+    pub parent_uuid: Option<Uuid>,
+    pub parent_ctransid: Option<u64>,
+    // Size of the plaintext stream
+    pub size: u64,
+    pub signature: Option<String>
+}
+
+
+impl MetadataEnvelope {
+    /// Built from the first command of the plaintext stream.
+    pub fn from_command(command: &BtrfsCommand, size: u64) -> Option<MetadataEnvelope> {
+        match command.kind {
+            BTRFS_SEND_C_SUBVOL => match BtrfsSubvol::load(command.data.as_slice()) {
+                Ok(subvol) => Some(MetadataEnvelope {
+                    name: subvol.name,
+                    uuid: subvol.uuid,
+                    ctransid: subvol.ctransid,
+                    parent_uuid: None,
+                    parent_ctransid: None,
+                    size: size,
+                    signature: None
+                }),
+                Err(_) => None
+            },
+            BTRFS_SEND_C_SNAPSHOT => match BtrfsSnapshot::load(command.data.as_slice()) {
+                Ok(snap) => Some(MetadataEnvelope {
+                    name: snap.name,
+                    uuid: snap.uuid,
+                    ctransid: snap.ctransid,
+                    parent_uuid: Some(snap.clone_uuid),
+                    parent_ctransid: Some(snap.clone_ctransid),
+                    size: size,
+                    signature: None
+                }),
+                Err(_) => None
+            },
+            _ => None
+        }
+    }
+
+    pub fn load(path: &Path) -> IoResult<MetadataEnvelope> {
+        let contents = try!(try!(File::open(path)).read_to_string());
+        match json::decode(contents.as_slice()) {
+            Ok(envelope) => Ok(envelope),
+            Err(err) => Err(IoError {
+                kind: OtherIoError,
+                desc: "malformed envelope",
+                detail: Some(format!("{}", err))
+            })
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(File::create(path));
+        try!(file.write_str(json::encode(self).as_slice()));
+        file.fsync()
+    }
+
+    pub fn to_node(&self, path: &Path, stored_size: u64) -> BackupNode {
+        let kind = match (self.parent_uuid, self.parent_ctransid) {
+            (Some(parent_uuid), Some(parent_ctransid)) => IncrementalBackup(BtrfsSnapshot {
+                name: self.name.clone(),
+                uuid: self.uuid.clone(),
+                ctransid: self.ctransid,
+                clone_uuid: parent_uuid,
+                clone_ctransid: parent_ctransid
+            }),
+            _ => FullBackup(BtrfsSubvol {
+                name: self.name.clone(),
+                uuid: self.uuid.clone(),
+                ctransid: self.ctransid
+            })
+        };
+        BackupNode {
+            size: stored_size,
+            stored_size: stored_size,
+            encoding: PlainObject,
+            encrypted: true,
+            kind: kind,
+            uuid: self.uuid.clone(),
+            parent_uuid: self.parent_uuid.clone(),
+            path: path.clone(),
+            name: self.name.clone(),
+            metadata: None,
+            tier: HotTier
+        }
+    }
+}
+
+
+pub struct EncryptWriter<W> {
+    inner: W,
+    key: Vec<u8>,
+    buf: Vec<u8>,
+    counter: u64
+}
+
+
+impl<W: Writer> EncryptWriter<W> {
+    pub fn new(mut inner: W, repo_key: &RepositoryKey) -> IoResult<EncryptWriter<W>> {
+        let mut rng = try!(OsRng::new());
+        let mut salt = [0u8, ..SALT_SIZE];
+        rng.fill_bytes(&mut salt);
+        try!(inner.write(ENCRYPTED_MAGIC));
+        try!(inner.write(&salt));
+        Ok(EncryptWriter {
+            inner: inner,
+            key: repo_key.object_key(&salt),
+            buf: Vec::with_capacity(FRAME_SIZE),
+            counter: 0
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> IoResult<()> {
+        let mut out = Vec::from_elem(self.buf.len(), 0u8);
+        let mut tag = [0u8, ..TAG_SIZE];
+        let mut cipher = ChaCha20Poly1305::new(
+            self.key.as_slice(),
+            &frame_nonce(self.counter),
+            &frame_aad(self.counter, last));
+        cipher.encrypt(self.buf.as_slice(), out.as_mut_slice(), &mut tag);
+
+        try!(self.inner.write_u8(last as u8));
+        try!(self.inner.write_be_u32(out.len() as u32));
+        try!(self.inner.write(out.as_slice()));
+        try!(self.inner.write(&tag));
+        self.counter += 1;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Seals the final frame and returns the underlying writer.
+    pub fn finish(mut self) -> IoResult<W> {
+        try!(self.seal(true));
+        try!(self.inner.flush());
+        Ok(self.inner)
+    }
+}
+
+
+impl<W: Writer> Writer for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut buf = buf;
+        while buf.len() > 0 {
+            // A full frame is only sealed once we know it isn't the last.
+            if self.buf.len() == FRAME_SIZE {
+                try!(self.seal(false));
+            }
+            let take = min(FRAME_SIZE - self.buf.len(), buf.len());
+            self.buf.push_all(buf[..take]);
+            buf = buf[take..];
+        }
+        Ok(())
+    }
+}
+
+
+pub struct DecryptReader<R> {
+    inner: R,
+    key: Vec<u8>,
+    frame: Vec<u8>,
+    pos: uint,
+    counter: u64,
+    finished: bool
+}
+
+
+impl<R: Reader> DecryptReader<R> {
+    pub fn new(mut inner: R, repo_key: &RepositoryKey) -> IoResult<DecryptReader<R>> {
+        let magic = try!(inner.read_exact(ENCRYPTED_MAGIC.len()));
+        if magic.as_slice() != ENCRYPTED_MAGIC {
+            return Err(crypto_error("not an encrypted object"));
+        }
+        let salt = try!(inner.read_exact(SALT_SIZE));
+        Ok(DecryptReader {
+            inner: inner,
+            key: repo_key.object_key(salt.as_slice()),
+            frame: Vec::new(),
+            pos: 0,
+            counter: 0,
+            finished: false
+        })
+    }
+
+    fn next_frame(&mut self) -> IoResult<()> {
+        let last = match self.inner.read_u8() {
+            Ok(0) => false,
+            Ok(1) => true,
+            Ok(_) => return Err(crypto_error("corrupt frame header")),
+            Err(ref err) if err.kind == EndOfFile => {
+                return Err(crypto_error("encrypted object is truncated"));
+            },
+            Err(err) => return Err(err)
+        };
+        let len = try!(self.inner.read_be_u32()) as uint;
+        if len > FRAME_SIZE {
+            return Err(crypto_error("corrupt frame header"));
+        }
+        let ciphertext = try!(self.inner.read_exact(len));
+        let tag = try!(self.inner.read_exact(TAG_SIZE));
+
+        let mut plaintext = Vec::from_elem(len, 0u8);
+        let mut cipher = ChaCha20Poly1305::new(
+            self.key.as_slice(),
+            &frame_nonce(self.counter),
+            &frame_aad(self.counter, last));
+        if !cipher.decrypt(ciphertext.as_slice(), plaintext.as_mut_slice(), tag.as_slice()) {
+            return Err(crypto_error("authentication failed"));
+        }
+        self.frame = plaintext;
+        self.pos = 0;
+        self.counter += 1;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+
+impl<R: Reader> Reader for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.pos == self.frame.len() {
+            if self.finished {
+                return Err(standard_error(EndOfFile));
+            }
+            try!(self.next_frame());
+        }
+        let len = min(buf.len(), self.frame.len() - self.pos);
+        copy_memory(buf, self.frame[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+#[test]
+fn test_encrypt_roundtrip() {
+    let key = RepositoryKey::generate().unwrap();
+    let mut data: Vec<u8> = Vec::new();
+    for i in range(0u, FRAME_SIZE * 3) {
+        data.push((i % 253) as u8);
+    }
+
+    let mut writer = EncryptWriter::new(MemWriter::new(), &key).unwrap();
+    assert!(writer.write(data.as_slice()).is_ok());
+    let sealed = writer.finish().unwrap().unwrap();
+
+    let mut reader = DecryptReader::new(BufReader::new(sealed.as_slice()), &key).unwrap();
+    assert_eq!(reader.read_to_end().unwrap(), data);
+
+    // Dropping the final frame must not go unnoticed
+    let truncated = sealed.slice_to(sealed.len() - (1 + 4 + TAG_SIZE));
+    let mut reader = DecryptReader::new(BufReader::new(truncated), &key).unwrap();
+    assert!(reader.read_to_end().is_err());
+}
+
+
+#[test]
+fn test_envelope_signature() {
+    let key = RepositoryKey::generate().unwrap();
+    let mut envelope = MetadataEnvelope {
+        name: b"root".to_vec(),
+        uuid: Uuid::new_v4(),
+        ctransid: 10,
+        parent_uuid: None,
+        parent_ctransid: None,
+        size: 1234,
+        signature: None
+    };
+    assert!(!key.verify_envelope(&envelope));
+    key.sign_envelope(&mut envelope);
+    assert!(key.verify_envelope(&envelope));
+    envelope.ctransid = 11;
+    assert!(!key.verify_envelope(&envelope));
+}
@@ -0,0 +1,154 @@
+use std::io::{File, IoResult, IoError, OtherIoError};
+use std::io::fs::{readdir, rename, unlink, mkdir_recursive};
+use std::io::USER_RWX;
+
+#[cfg(test)]
+use std::io::TempDir;
+
+
+// A repository is marked by a `format` file holding this prefix and a
+// version number.  Repositories from before the marker existed are a
+// flat directory of objects and count as version 0.
+pub static FORMAT_FILENAME: &'static str = "format";
+
+static FORMAT_PREFIX: &'static str = "btrfs-backup-repository ";
+
+pub static LEGACY_VERSION: uint = 0;
+
+/// The newest format this build reads and writes.
+pub static CURRENT_VERSION: uint = 1;
+
+
+fn format_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail
+    }
+}
+
+
+pub fn read_version(root: &Path) -> IoResult<uint> {
+    let path = root.join(FORMAT_FILENAME);
+    if !path.exists() {
+        return Ok(LEGACY_VERSION);
+    }
+    let contents = try!(try!(File::open(&path)).read_to_string());
+    let line = contents.as_slice().trim_right();
+    if !line.starts_with(FORMAT_PREFIX) {
+        return Err(format_error("not a repository format file",
+            Some(format!("{}", path.display()))));
+    }
+    match from_str::<uint>(line.slice_from(FORMAT_PREFIX.len())) {
+        Some(version) => Ok(version),
+        None => Err(format_error("bad repository format version",
+            Some(line.to_string())))
+    }
+}
+
+
+pub fn write_version(root: &Path, version: uint) -> IoResult<()> {
+    let path = root.join(FORMAT_FILENAME);
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = try!(File::create(&tmp_path));
+        try!(file.write_str(format!("{}{}\n", FORMAT_PREFIX, version).as_slice()));
+        try!(file.fsync());
+    }
+    rename(&tmp_path, &path)
+}
+
+
+/// Fails for repositories written by a newer version of this software.
+/// Older formats still load; `migrate` brings them up to date.
+pub fn check_version(root: &Path) -> IoResult<uint> {
+    let version = try!(read_version(root));
+    if version > CURRENT_VERSION {
+        return Err(format_error("repository format is newer than this server",
+            Some(format!("version {}, understood up to {}", version, CURRENT_VERSION))));
+    }
+    Ok(version)
+}
+
+
+/// Creates an empty repository at `root`.
+pub fn init(root: &Path) -> IoResult<()> {
+    if root.exists() {
+        if root.join(FORMAT_FILENAME).exists() {
+            return Err(format_error("already a repository",
+                Some(format!("{}", root.display()))));
+        }
+        if try!(readdir(root)).len() > 0 {
+            return Err(format_error("directory is not empty; migrate it instead",
+                Some(format!("{}", root.display()))));
+        }
+    }
+    try!(mkdir_recursive(root, USER_RWX));
+    write_version(root, CURRENT_VERSION)
+}
+
+
+// 0 -> 1: the legacy flat directory.  Objects stay where they are; only
+// leftovers of interrupted uploads are removed, since later formats give
+// `.tmp` files no meaning outside an upload in progress.
+fn migrate_legacy(root: &Path) -> IoResult<()> {
+    for path in try!(readdir(root)).iter() {
+        if !path.is_dir() && path.extension_str() == Some("tmp") {
+            try!(unlink(path));
+        }
+    }
+    Ok(())
+}
+
+
+// The step upgrading a repository from `version` to `version + 1`
+fn migration_from(version: uint) -> Option<fn(&Path) -> IoResult<()>> {
+    match version {
+        0 => Some(migrate_legacy),
+        _ => None
+    }
+}
+
+
+/// Upgrades the repository at `root` to `CURRENT_VERSION` in place,
+/// one version at a time, recording each step as it completes so an
+/// interrupted migration resumes where it stopped.  The caller must hold
+/// the repository lock exclusively.  Returns the version it started at.
+pub fn migrate(root: &Path) -> IoResult<uint> {
+    let start = try!(check_version(root));
+    let mut version = start;
+    while version < CURRENT_VERSION {
+        let step = match migration_from(version) {
+            Some(step) => step,
+            None => return Err(format_error("no migration from format",
+                Some(format!("version {}", version))))
+        };
+        try!(step(root));
+        version += 1;
+        try!(write_version(root, version));
+    }
+    Ok(start)
+}
+
+
+#[test]
+fn test_init_and_migrate() {
+    let tmpdir = TempDir::new("format").unwrap();
+
+    let fresh = tmpdir.path().join("fresh");
+    assert!(init(&fresh).is_ok());
+    assert_eq!(read_version(&fresh).unwrap(), CURRENT_VERSION);
+    assert!(init(&fresh).is_err());
+
+    let legacy = tmpdir.path().join("legacy");
+    assert!(mkdir_recursive(&legacy, USER_RWX).is_ok());
+    assert!(File::create(&legacy.join("stale.tmp")).is_ok());
+    assert!(init(&legacy).is_err());
+    assert_eq!(migrate(&legacy).unwrap(), LEGACY_VERSION);
+    assert_eq!(read_version(&legacy).unwrap(), CURRENT_VERSION);
+    assert!(!legacy.join("stale.tmp").exists());
+
+    assert!(write_version(&legacy, CURRENT_VERSION + 1).is_ok());
+    assert!(check_version(&legacy).is_err());
+    assert!(migrate(&legacy).is_err());
+}
@@ -0,0 +1,125 @@
+use std::os;
+use std::ptr;
+use std::mem::size_of;
+use std::collections::HashMap;
+use std::io::{IoResult, IoError};
+use std::c_str::ToCStr;
+
+use libc;
+use libc::{c_int, c_char, c_void, size_t};
+
+
+static IN_CLOSE_WRITE: u32 = 0x00000008;
+static IN_MOVED_FROM: u32 = 0x00000040;
+static IN_MOVED_TO: u32 = 0x00000080;
+static IN_DELETE: u32 = 0x00000200;
+static IN_Q_OVERFLOW: u32 = 0x00004000;
+
+static IN_CLOEXEC: c_int = 0o2000000;
+
+// Files are only ever written whole and renamed into place, or deleted,
+// so these are all the changes the index can miss.
+static WATCH_MASK: u32 = IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_DELETE;
+
+extern {
+    fn inotify_init1(flags: c_int) -> c_int;
+    fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+}
+
+
+#[repr(C)]
+struct InotifyEvent {
+    wd: c_int,
+    mask: u32,
+    cookie: u32,
+    len: u32
+}
+
+
+pub enum WatchEvent {
+    // Something happened to the file at this path
+    Changed(Path),
+    // The kernel dropped events; anything may have changed
+    Overflowed
+}
+
+
+/// Watches directories, not recursively, for files changing.
+pub struct Watcher {
+    fd: c_int,
+    dirs: HashMap<c_int, Path>
+}
+
+
+impl Watcher {
+    pub fn new() -> IoResult<Watcher> {
+        let fd = unsafe { inotify_init1(IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(Watcher { fd: fd, dirs: HashMap::new() })
+    }
+
+    pub fn watch(&mut self, dir: &Path) -> IoResult<()> {
+        let wd = dir.with_c_str(|c_path| unsafe {
+            inotify_add_watch(self.fd, c_path, WATCH_MASK)
+        });
+        if wd < 0 {
+            return Err(IoError::last_error());
+        }
+        self.dirs.insert(wd, dir.clone());
+        Ok(())
+    }
+
+    /// Blocks until something changes.
+    pub fn wait(&mut self) -> IoResult<Vec<WatchEvent>> {
+        let mut buf = [0u8, ..64 * 1024];
+        let len;
+        loop {
+            let read = unsafe {
+                libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t)
+            };
+            if read >= 0 {
+                len = read as uint;
+                break;
+            }
+            if os::errno() as c_int != libc::EINTR {
+                return Err(IoError::last_error());
+            }
+        }
+
+        let mut events = Vec::new();
+        let mut offset = 0u;
+        while offset + size_of::<InotifyEvent>() <= len {
+            let event: InotifyEvent = unsafe {
+                ptr::read(buf.as_ptr().offset(offset as int) as *const InotifyEvent)
+            };
+            let name_start = offset + size_of::<InotifyEvent>();
+            offset = name_start + event.len as uint;
+
+            if event.mask & IN_Q_OVERFLOW != 0 {
+                events.push(Overflowed);
+                continue;
+            }
+            // The name is padded out with NULs
+            let name: Vec<u8> = buf[name_start..offset].iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c)
+                .collect();
+            match self.dirs.find(&event.wd) {
+                Some(dir) if name.len() > 0 => events.push(Changed(dir.join(name))),
+                _ => ()
+            }
+        }
+        Ok(events)
+    }
+}
+
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
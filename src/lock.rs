@@ -0,0 +1,114 @@
+use std::os;
+use std::io::{IoResult, IoError};
+use std::c_str::ToCStr;
+
+use libc;
+use libc::c_int;
+
+
+// Writers (uploads, synthesized fulls) and readers take the lock shared;
+// anything that deletes objects or chunks takes it exclusive.  An upload
+// holds its shared lock until the object is committed, so nothing it
+// depends on can be removed underneath it.
+pub static LOCK_FILENAME: &'static str = "lock";
+
+static LOCK_SH: c_int = 1;
+static LOCK_EX: c_int = 2;
+static LOCK_NB: c_int = 4;
+static LOCK_UN: c_int = 8;
+
+extern {
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+}
+
+
+#[deriving(PartialEq, Clone, Show)]
+pub enum LockMode {
+    SharedLock,
+    ExclusiveLock
+}
+
+
+pub struct RepositoryLock {
+    fd: c_int,
+    pub mode: LockMode
+}
+
+
+impl RepositoryLock {
+    /// Blocks until the lock is available.
+    pub fn acquire(root: &Path, mode: LockMode) -> IoResult<RepositoryLock> {
+        match try!(RepositoryLock::lock(root, mode, false)) {
+            Some(lock) => Ok(lock),
+            None => unreachable!()
+        }
+    }
+
+    /// Returns `None` instead of waiting if someone holds a conflicting lock.
+    pub fn try_acquire(root: &Path, mode: LockMode) -> IoResult<Option<RepositoryLock>> {
+        RepositoryLock::lock(root, mode, true)
+    }
+
+    /// Locks `path` itself, for files that only one session may work
+    /// on at a time.  `None` if someone else holds it or it's gone.
+    pub fn try_acquire_file(path: &Path, mode: LockMode) -> IoResult<Option<RepositoryLock>> {
+        let fd = path.with_c_str(|c_path| unsafe {
+            libc::open(c_path, libc::O_RDWR, 0)
+        });
+        if fd < 0 {
+            if os::errno() as c_int == libc::ENOENT {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        RepositoryLock::lock_fd(fd, mode, true)
+    }
+
+    fn lock(root: &Path, mode: LockMode, nonblocking: bool) -> IoResult<Option<RepositoryLock>> {
+        let path = root.join(LOCK_FILENAME);
+        let fd = path.with_c_str(|c_path| unsafe {
+            libc::open(c_path, libc::O_RDWR | libc::O_CREAT, 0o644)
+        });
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        RepositoryLock::lock_fd(fd, mode, nonblocking)
+    }
+
+    // Takes ownership of `fd`, closing it unless the lock is taken
+    fn lock_fd(fd: c_int, mode: LockMode, nonblocking: bool) -> IoResult<Option<RepositoryLock>> {
+        let mut operation = match mode {
+            SharedLock => LOCK_SH,
+            ExclusiveLock => LOCK_EX
+        };
+        if nonblocking {
+            operation |= LOCK_NB;
+        }
+
+        loop {
+            if unsafe { flock(fd, operation) } == 0 {
+                return Ok(Some(RepositoryLock { fd: fd, mode: mode }));
+            }
+            let errno = os::errno() as c_int;
+            if errno == libc::EINTR {
+                continue;
+            }
+            let err = IoError::last_error();
+            unsafe { libc::close(fd) };
+            if errno == libc::EWOULDBLOCK {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+    }
+}
+
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        unsafe {
+            flock(self.fd, LOCK_UN);
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::{File, IoResult, IoError, OtherIoError};
+
+use serialize::json;
+use serialize::hex::ToHex;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use time;
+
+#[cfg(test)]
+use std::io::{MemWriter, MemReader};
+
+
+// Kept next to each object as `<object>.meta`.  Objects uploaded before
+// sidecars existed have none.
+pub static METADATA_SIDECAR: &'static str = "meta";
+
+// Kept next to each encrypted object as `<object>.envelope`.
+pub static ENVELOPE_SIDECAR: &'static str = "envelope";
+
+// reliable-encap ends a stream with the SHA-256 of everything it carried
+static ENCAP_DIGEST_SIZE: uint = 32;
+
+
+/// What we know about an object beyond its stream header.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct ObjectMetadata {
+    // Seconds since the epoch, by the server's clock
+    pub uploaded_at: i64,
+    // Who uploaded it, as the client describes itself
+    pub client: Option<String>,
+    // The subvolume the snapshot was taken of, on the client
+    pub source_path: Option<String>,
+    // Hex SHA-256 of the stream as received, before the object encoding
+    pub content_hash: String,
+    // Hex of the trailing digest of the reliable-encap framing
+    pub encap_digest: Option<String>,
+    pub tool_version: String,
+    pub labels: Vec<String>
+}
+
+
+impl ObjectMetadata {
+    /// Metadata for an object being stored now.
+    pub fn new(content_hash: &[u8]) -> ObjectMetadata {
+        ObjectMetadata {
+            uploaded_at: time::get_time().sec,
+            client: None,
+            source_path: None,
+            content_hash: content_hash.to_hex(),
+            encap_digest: None,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            labels: Vec::new()
+        }
+    }
+
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|l| l.as_slice() == label)
+    }
+
+    pub fn load(path: &Path) -> IoResult<ObjectMetadata> {
+        let contents = try!(try!(File::open(path)).read_to_string());
+        match json::decode(contents.as_slice()) {
+            Ok(metadata) => Ok(metadata),
+            Err(err) => Err(IoError {
+                kind: OtherIoError,
+                desc: "malformed metadata",
+                detail: Some(format!("{}", err))
+            })
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(File::create(path));
+        try!(file.write_str(json::encode(self).as_slice()));
+        file.fsync()
+    }
+}
+
+
+/// Passes writes through, hashing them on the way.
+pub struct HashingWriter<'a> {
+    inner: &'a mut Writer+'a,
+    hasher: Sha256
+}
+
+
+impl<'a> HashingWriter<'a> {
+    pub fn new<'a>(inner: &'a mut Writer) -> HashingWriter<'a> {
+        HashingWriter {
+            inner: inner,
+            hasher: Sha256::new()
+        }
+    }
+
+    /// SHA-256 of everything written so far.
+    pub fn digest(&mut self) -> Vec<u8> {
+        let mut out = Vec::from_elem(self.hasher.output_bytes(), 0u8);
+        self.hasher.result(out.as_mut_slice());
+        out
+    }
+}
+
+
+impl<'a> Writer for HashingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.hasher.input(buf);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// Passes reads through, hashing them on the way.
+pub struct HashingReader<'a> {
+    inner: &'a mut Reader+'a,
+    hasher: Sha256
+}
+
+
+impl<'a> HashingReader<'a> {
+    pub fn new<'a>(inner: &'a mut Reader) -> HashingReader<'a> {
+        HashingReader {
+            inner: inner,
+            hasher: Sha256::new()
+        }
+    }
+
+    /// SHA-256 of everything read so far.
+    pub fn digest(&mut self) -> Vec<u8> {
+        let mut out = Vec::from_elem(self.hasher.output_bytes(), 0u8);
+        self.hasher.result(out.as_mut_slice());
+        out
+    }
+}
+
+
+impl<'a> Reader for HashingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let len = try!(self.inner.read(buf));
+        self.hasher.input(buf[..len]);
+        Ok(len)
+    }
+}
+
+
+/// Passes reads through, remembering the reliable-encap digest that
+/// ends the stream.
+pub struct EncapDigestReader<'a> {
+    inner: &'a mut Reader+'a,
+    tail: Vec<u8>
+}
+
+
+impl<'a> EncapDigestReader<'a> {
+    pub fn new<'a>(inner: &'a mut Reader) -> EncapDigestReader<'a> {
+        EncapDigestReader {
+            inner: inner,
+            tail: Vec::with_capacity(2 * ENCAP_DIGEST_SIZE)
+        }
+    }
+
+    /// Only meaningful once the whole stream has been read.
+    pub fn digest(&self) -> Option<Vec<u8>> {
+        if self.tail.len() == ENCAP_DIGEST_SIZE {
+            Some(self.tail.clone())
+        } else {
+            None
+        }
+    }
+}
+
+
+impl<'a> Reader for EncapDigestReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let len = try!(self.inner.read(buf));
+        self.tail.push_all(buf[..len]);
+        if self.tail.len() > ENCAP_DIGEST_SIZE {
+            let excess = self.tail.len() - ENCAP_DIGEST_SIZE;
+            self.tail = self.tail[excess..].to_vec();
+        }
+        Ok(len)
+    }
+}
+
+
+#[test]
+fn test_digest_passthrough() {
+    let data = Vec::from_fn(100, |i| i as u8);
+
+    let mut out = MemWriter::new();
+    let digest = {
+        let mut writer = HashingWriter::new(&mut out);
+        assert!(writer.write(data.as_slice()).is_ok());
+        writer.digest()
+    };
+    assert_eq!(out.unwrap(), data);
+    assert_eq!(digest.to_hex().as_slice(),
+        "bce0aff19cf5aa6a7469a30d61d04e4376e4bbf6381052ee9e7f33925c954d52");
+
+    let mut inner = MemReader::new(data.clone());
+    let read_digest = {
+        let mut reader = HashingReader::new(&mut inner);
+        assert_eq!(reader.read_to_end().unwrap(), data);
+        reader.digest()
+    };
+    assert_eq!(read_digest, digest);
+
+    let mut inner = MemReader::new(data.clone());
+    let mut reader = EncapDigestReader::new(&mut inner);
+    assert_eq!(reader.read_to_end().unwrap(), data);
+    assert_eq!(reader.digest(), Some(data[68..].to_vec()));
+}
@@ -0,0 +1,386 @@
+use std::io::{File, BufferedReader, BufferedWriter, IoResult, SeekSet, Append, Write};
+use std::io::fs::{rename, unlink, stat, readdir};
+
+use uuid::Uuid;
+use serialize::json;
+
+#[cfg(test)]
+use std::io::TempDir;
+
+use compression::{
+    COMPRESSED_MAGIC,
+    CompressWriter,
+    DecompressReader,
+    read_raw_size,
+};
+use chunking::{
+    CHUNKED_MAGIC,
+    ChunkStore,
+    ChunkingWriter,
+    ChunkedReader,
+    read_recipe_size,
+};
+use lock::{RepositoryLock, ExclusiveLock};
+
+
+#[deriving(PartialEq, Clone, Show)]
+pub enum ObjectEncoding {
+    PlainObject,
+    DeflateObject,
+    // A recipe of deduplicated chunks
+    ChunkedObject
+}
+
+
+pub struct ObjectInfo {
+    pub encoding: ObjectEncoding,
+    // Size of the stream as the client sent it
+    pub size: u64,
+    // Size of the file on disk.  For a chunked object this is the recipe
+    // alone; its chunks may be shared with other objects.
+    pub stored_size: u64
+}
+
+
+pub enum ObjectReader {
+    PlainReader(File),
+    DeflateReader(DecompressReader<File>),
+    RecipeReader(ChunkedReader<BufferedReader<File>>)
+}
+
+
+impl Reader for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match *self {
+            PlainReader(ref mut reader) => reader.read(buf),
+            DeflateReader(ref mut reader) => reader.read(buf),
+            RecipeReader(ref mut reader) => reader.read(buf)
+        }
+    }
+}
+
+
+fn sniff_encoding(file: &mut File) -> IoResult<ObjectEncoding> {
+    let encoding = match file.read_exact(COMPRESSED_MAGIC.len()) {
+        Ok(ref magic) if magic.as_slice() == COMPRESSED_MAGIC => DeflateObject,
+        Ok(ref magic) if magic.as_slice() == CHUNKED_MAGIC => ChunkedObject,
+        _ => PlainObject
+    };
+    if encoding == PlainObject {
+        try!(file.seek(0, SeekSet));
+    }
+    Ok(encoding)
+}
+
+
+/// Where data about an object that isn't part of the object lives.
+pub fn sidecar_path(path: &Path, kind: &str) -> Path {
+    let filename = path.filename_str().unwrap_or("");
+    path.with_filename(format!("{}.{}", filename, kind))
+}
+
+
+/// Opens a stored object, undoing whatever encoding it was stored with.
+pub fn open_object(path: &Path) -> IoResult<ObjectReader> {
+    let mut file = try!(File::open(path));
+    Ok(match try!(sniff_encoding(&mut file)) {
+        PlainObject => PlainReader(file),
+        DeflateObject => DeflateReader(DecompressReader::after_magic(file)),
+        ChunkedObject => RecipeReader(ChunkedReader::after_magic(
+            BufferedReader::new(file), ChunkStore::new(&path.dir_path())))
+    })
+}
+
+
+pub fn object_info(path: &Path) -> IoResult<ObjectInfo> {
+    let mut file = try!(File::open(path));
+    let stored_size = try!(file.stat()).size;
+    let encoding = try!(sniff_encoding(&mut file));
+    let size = match encoding {
+        PlainObject => stored_size,
+        DeflateObject => try!(read_raw_size(&mut file)),
+        ChunkedObject => try!(read_recipe_size(&mut file))
+    };
+    Ok(ObjectInfo {
+        encoding: encoding,
+        size: size,
+        stored_size: stored_size
+    })
+}
+
+
+pub enum ObjectWriter {
+    PlainWriter(BufferedWriter<File>),
+    DeflateWriter(CompressWriter<File>),
+    RecipeWriter(ChunkingWriter<BufferedWriter<File>>)
+}
+
+
+impl ObjectWriter {
+    pub fn create(path: &Path, encoding: ObjectEncoding) -> IoResult<ObjectWriter> {
+        let file = try!(File::create(path));
+        Ok(match encoding {
+            PlainObject => PlainWriter(BufferedWriter::new(file)),
+            DeflateObject => DeflateWriter(try!(CompressWriter::new(file))),
+            ChunkedObject => RecipeWriter(try!(ChunkingWriter::new(
+                BufferedWriter::new(file), ChunkStore::new(&path.dir_path()))))
+        })
+    }
+
+    /// Completes the encoding and syncs the file to disk.
+    pub fn finish(self) -> IoResult<()> {
+        let mut file = match self {
+            PlainWriter(mut writer) => {
+                try!(writer.flush());
+                writer.unwrap()
+            },
+            DeflateWriter(writer) => try!(writer.finish()),
+            RecipeWriter(writer) => try!(writer.finish()).unwrap()
+        };
+        try!(file.flush());
+        file.fsync()
+    }
+}
+
+
+impl Writer for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        match *self {
+            PlainWriter(ref mut writer) => writer.write(buf),
+            DeflateWriter(ref mut writer) => writer.write(buf),
+            RecipeWriter(ref mut writer) => writer.write(buf)
+        }
+    }
+}
+
+
+/// An object being written to a temporary file in the repository.
+pub struct PendingObject {
+    pub object_id: Uuid,
+    pub writer: ObjectWriter,
+    tmp_path: Path,
+    final_path: Path
+}
+
+
+impl PendingObject {
+    pub fn create(root: &Path, encoding: ObjectEncoding) -> IoResult<PendingObject> {
+        let object_id = Uuid::new_v4();
+        let object_id_str = object_id.to_hyphenated_string();
+
+        let mut tmp_path = root.clone();
+        tmp_path.push(format!("{}.tmp", object_id_str).as_slice());
+
+        let mut final_path = root.clone();
+        final_path.push(object_id_str.as_slice());
+
+        let writer = try!(ObjectWriter::create(&tmp_path, encoding));
+        Ok(PendingObject {
+            object_id: object_id,
+            writer: writer,
+            tmp_path: tmp_path,
+            final_path: final_path
+        })
+    }
+
+    pub fn finish(self) -> IoResult<FinishedObject> {
+        let PendingObject { object_id, writer, tmp_path, final_path } = self;
+        match writer.finish() {
+            Ok(()) => Ok(FinishedObject {
+                object_id: object_id,
+                tmp_path: tmp_path,
+                final_path: final_path
+            }),
+            Err(err) => {
+                let _ = unlink(&tmp_path);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn rollback(self) -> IoResult<()> {
+        let PendingObject { writer, tmp_path, .. } = self;
+        drop(writer);
+        unlink(&tmp_path)
+    }
+}
+
+
+/// A fully written object that is not yet visible in the repository.
+pub struct FinishedObject {
+    pub object_id: Uuid,
+    tmp_path: Path,
+    final_path: Path
+}
+
+
+impl FinishedObject {
+    pub fn tmp_path(&self) -> &Path {
+        &self.tmp_path
+    }
+
+    pub fn final_path(&self) -> &Path {
+        &self.final_path
+    }
+
+    pub fn commit(self) -> IoResult<Path> {
+        try!(rename(&self.tmp_path, &self.final_path));
+        Ok(self.final_path)
+    }
+
+    pub fn rollback(self) -> IoResult<()> {
+        unlink(&self.tmp_path)
+    }
+}
+
+
+/// The raw stream of an upload that may take several sessions to
+/// arrive, kept as `<session>.partial` until all of it is there.
+pub struct PartialUpload {
+    pub session: Uuid,
+    path: Path
+}
+
+
+// The part of a saved upload request that decides whose quota the
+// partial counts against
+#[deriving(Decodable)]
+struct PartialRequest {
+    client: Option<String>
+}
+
+
+impl PartialUpload {
+    pub fn create(root: &Path) -> IoResult<PartialUpload> {
+        let session = Uuid::new_v4();
+        let path = partial_path(root, &session);
+        try!(File::create(&path));
+        Ok(PartialUpload {
+            session: session,
+            path: path
+        })
+    }
+
+    /// The upload `session` left behind, if it's still there.
+    pub fn open(root: &Path, session: &Uuid) -> Option<PartialUpload> {
+        let path = partial_path(root, session);
+        if !path.exists() {
+            return None;
+        }
+        Some(PartialUpload {
+            session: session.clone(),
+            path: path
+        })
+    }
+
+    /// Every upload in `root` that hasn't finished.
+    pub fn list(root: &Path) -> IoResult<Vec<PartialUpload>> {
+        let mut partials = Vec::new();
+        for path in try!(readdir(root)).into_iter() {
+            if path.extension_str() != Some("partial") {
+                continue;
+            }
+            let session = path.filestem_str().and_then(|stem| Uuid::parse_str(stem).ok());
+            match session {
+                Some(session) => partials.push(PartialUpload {
+                    session: session,
+                    path: path
+                }),
+                None => ()
+            }
+        }
+        Ok(partials)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Locks the upload for one session to append to.  `None` if
+    /// another session has it, or it finished in the meantime.
+    pub fn claim(&self) -> IoResult<Option<RepositoryLock>> {
+        RepositoryLock::try_acquire_file(&self.path, ExclusiveLock)
+    }
+
+    /// Where what the upload is for is kept between sessions.
+    pub fn request_path(&self) -> Path {
+        sidecar_path(&self.path, "request")
+    }
+
+    /// How much of the stream has arrived.
+    pub fn offset(&self) -> IoResult<u64> {
+        Ok(try!(stat(&self.path)).size)
+    }
+
+    /// When the stream last grew, in seconds since the epoch.
+    pub fn modified(&self) -> IoResult<i64> {
+        Ok((try!(stat(&self.path)).modified / 1000) as i64)
+    }
+
+    /// The client the upload is for, as saved with its request.
+    pub fn client(&self) -> Option<String> {
+        File::open(&self.request_path())
+            .and_then(|mut file| file.read_to_string())
+            .ok()
+            .and_then(|contents| json::decode::<PartialRequest>(contents.as_slice()).ok())
+            .and_then(|request| request.client)
+    }
+
+    /// Opens the file to add the rest of the stream to.
+    pub fn append(&self) -> IoResult<File> {
+        File::open_mode(&self.path, Append, Write)
+    }
+
+    /// Moves the stream into a new object in `root` as it is, for
+    /// repositories that store objects unencoded.
+    pub fn into_object(&self, root: &Path) -> IoResult<FinishedObject> {
+        let object_id = Uuid::new_v4();
+        let object_id_str = object_id.to_hyphenated_string();
+        let tmp_path = root.join(format!("{}.tmp", object_id_str));
+        let final_path = root.join(object_id_str);
+        try!(rename(&self.path, &tmp_path));
+        Ok(FinishedObject {
+            object_id: object_id,
+            tmp_path: tmp_path,
+            final_path: final_path
+        })
+    }
+
+    /// Deletes whatever is left of the upload; the stream is already
+    /// gone if it was moved into an object.
+    pub fn remove(self) -> IoResult<()> {
+        let request_path = self.request_path();
+        if request_path.exists() {
+            try!(unlink(&request_path));
+        }
+        if self.path.exists() {
+            try!(unlink(&self.path));
+        }
+        Ok(())
+    }
+}
+
+
+fn partial_path(root: &Path, session: &Uuid) -> Path {
+    root.join(format!("{}.partial", session.to_hyphenated_string()))
+}
+
+
+#[test]
+fn test_partial_upload_claim() {
+    let tmpdir = TempDir::new("partial").unwrap();
+    let partial = PartialUpload::create(tmpdir.path()).unwrap();
+    assert_eq!(PartialUpload::list(tmpdir.path()).unwrap().len(), 1);
+
+    let claim = partial.claim().unwrap();
+    assert!(claim.is_some());
+    assert!(partial.claim().unwrap().is_none());
+    drop(claim);
+    assert!(partial.claim().unwrap().is_some());
+
+    let finished = partial.into_object(tmpdir.path()).unwrap();
+    assert!(partial.claim().unwrap().is_none());
+    assert!(PartialUpload::list(tmpdir.path()).unwrap().is_empty());
+    assert!(partial.remove().is_ok());
+    assert!(finished.commit().unwrap().exists());
+}
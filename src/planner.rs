@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use repository::{BackupNode, ColdTier};
+
+#[cfg(test)]
+use repository::{test_node, test_uuid};
+
+
+pub struct RestorePlan<'a> {
+    pub target: Uuid,
+    pub total_size: u64,
+    // Objects in replay order: a full backup followed by incrementals
+    pub steps: Vec<&'a BackupNode>
+}
+
+
+impl<'a> RestorePlan<'a> {
+    pub fn len(&self) -> uint {
+        self.steps.len()
+    }
+
+    pub fn paths(&self) -> Vec<Path> {
+        self.steps.iter().map(|n| n.path.clone()).collect()
+    }
+
+    /// How many of the objects have to come from the cold tier.
+    pub fn cold_steps(&self) -> uint {
+        self.steps.iter().filter(|n| n.tier == ColdTier).count()
+    }
+}
+
+
+struct Reach {
+    cost: u64,
+    links: uint,
+    via: uint
+}
+
+
+/// Finds the set of objects that restores `target` while reading the
+/// fewest bytes.  Every object is an edge from its parent (or from
+/// nothing, for a full backup) to its snapshot, so this is a shortest
+/// path search; ties are broken on the number of objects to replay.
+pub fn plan_restore<'a>(nodes: &'a [BackupNode], target: &Uuid) -> Option<RestorePlan<'a>> {
+    let mut best: HashMap<Uuid, Reach> = HashMap::new();
+
+    // Relax until nothing improves.  Sizes are never negative, so this
+    // settles in at most one pass per link of the longest chain.
+    loop {
+        let mut changed = false;
+
+        for (idx, node) in nodes.iter().enumerate() {
+            let (base_cost, base_links) = match node.parent_uuid {
+                None => (0, 0),
+                Some(ref parent) => match best.find(parent) {
+                    Some(reach) => (reach.cost, reach.links),
+                    None => continue
+                }
+            };
+            let candidate = Reach {
+                cost: base_cost + node.size,
+                links: base_links + 1,
+                via: idx
+            };
+            let is_better = match best.find(&node.uuid) {
+                Some(current) => {
+                    (candidate.cost, candidate.links) < (current.cost, current.links)
+                },
+                None => true
+            };
+            if is_better {
+                best.insert(node.uuid.clone(), candidate);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let total_size = match best.find(target) {
+        Some(reach) => reach.cost,
+        None => return None
+    };
+
+    let mut steps: Vec<&'a BackupNode> = Vec::new();
+    let mut cursor = target.clone();
+    loop {
+        let node = &nodes[best.find(&cursor).unwrap().via];
+        steps.push(node);
+        match node.parent_uuid {
+            Some(ref parent) => cursor = parent.clone(),
+            None => break
+        }
+    }
+    steps.reverse();
+
+    Some(RestorePlan {
+        target: target.clone(),
+        total_size: total_size,
+        steps: steps
+    })
+}
+
+
+#[cfg(test)]
+fn sized_node(uuid: u8, parent: Option<u8>, size: u64) -> BackupNode {
+    BackupNode { size: size, stored_size: size, ..test_node(uuid, parent) }
+}
+
+
+#[test]
+fn test_plan_prefers_cheaper_full() {
+    let nodes = vec![
+        sized_node(1, None, 1000),
+        sized_node(2, Some(1), 10),
+        sized_node(3, Some(2), 10),
+        sized_node(3, None, 500),
+        sized_node(4, Some(3), 10),
+    ];
+    let plan = plan_restore(nodes.as_slice(), &test_uuid(4)).unwrap();
+    assert_eq!(plan.total_size, 510);
+    assert_eq!(plan.len(), 2);
+    assert_eq!(plan.steps[0].parent_uuid, None);
+    assert_eq!(plan.steps[1].uuid, test_uuid(4));
+}
+
+
+#[test]
+fn test_plan_follows_chain() {
+    let nodes = vec![
+        sized_node(3, Some(2), 10),
+        sized_node(2, Some(1), 20),
+        sized_node(1, None, 1000),
+        sized_node(2, Some(1), 30),
+    ];
+    let plan = plan_restore(nodes.as_slice(), &test_uuid(3)).unwrap();
+    assert_eq!(plan.total_size, 1030);
+    let sizes: Vec<u64> = plan.steps.iter().map(|n| n.size).collect();
+    assert_eq!(sizes, vec![1000, 20, 10]);
+}
+
+
+#[test]
+fn test_plan_unreachable() {
+    let nodes = vec![
+        sized_node(2, Some(1), 10),
+    ];
+    assert!(plan_restore(nodes.as_slice(), &test_uuid(2)).is_none());
+    assert!(plan_restore(nodes.as_slice(), &test_uuid(9)).is_none());
+}
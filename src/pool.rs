@@ -0,0 +1,68 @@
+use std::cmp::min;
+use std::comm::channel;
+use std::sync::{Arc, Mutex};
+use std::task::spawn;
+use std::os;
+
+
+/// How many tasks to use when the caller doesn't say.  Most of the work
+/// is waiting on reads, so this is about keeping the disks busy.
+pub fn default_jobs() -> uint {
+    min(os::num_cpus(), 16)
+}
+
+
+/// Applies `work` to every item on up to `jobs` tasks.  The results come
+/// back in the order of `items`, as a serial `map` would give them.
+pub fn map_jobs<T: Send, R: Send>(items: Vec<T>, jobs: uint, work: fn(T) -> R) -> Vec<R> {
+    let count = items.len();
+    if jobs <= 1 || count <= 1 {
+        return items.into_iter().map(work).collect();
+    }
+
+    // Reversed, so popping hands out the items in order
+    let mut queue: Vec<(uint, T)> = items.into_iter().enumerate().collect();
+    queue.reverse();
+    let queue = Arc::new(Mutex::new(queue));
+
+    let (tx, rx) = channel();
+    for _ in range(0, min(jobs, count)) {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        spawn(proc() {
+            loop {
+                let next = queue.lock().pop();
+                match next {
+                    Some((idx, item)) => tx.send((idx, work(item))),
+                    None => break
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let mut results: Vec<Option<R>> = Vec::from_fn(count, |_| None);
+    for (idx, result) in rx.iter() {
+        *results.get_mut(idx) = Some(result);
+    }
+    results.into_iter().map(|result| match result {
+        Some(result) => result,
+        None => fail!("a worker task failed")
+    }).collect()
+}
+
+
+#[cfg(test)]
+fn square(value: uint) -> uint {
+    value * value
+}
+
+
+#[test]
+fn test_map_jobs_keeps_order() {
+    let items: Vec<uint> = range(0u, 100).collect();
+    let serial = map_jobs(items.clone(), 1, square);
+    assert_eq!(map_jobs(items.clone(), 8, square), serial);
+    assert_eq!(map_jobs(items, 200, square), serial);
+    assert_eq!(map_jobs(Vec::new(), 8, square), Vec::new());
+}
@@ -1,24 +1,102 @@
-use std::io::{File, BufReader, IoResult, IoError, OtherIoError, stderr};
-use std::io::fs::{rename, unlink};
+use std::io::{File, BufReader, BufferedReader, IoResult, IoError, OtherIoError, EndOfFile, PermissionDenied, stderr};
+use std::io::util::{copy, NullWriter};
+use std::cmp::{min, max};
+use std::io::fs::unlink;
 use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
-use serialize::json;
+use serialize::{json, Encodable, Decodable};
 use serialize::json::DecoderError;
+use serialize::hex::ToHex;
 
 use uuid::Uuid;
 // use msgpack;
-use reliable_rw::{copy_out, IntegrityError};
+use reliable_rw::{copy_in, copy_out, IntegrityError};
 use reliable_rw::ProtocolError as RelRwProtocolError;
 use reliable_rw::ReadError as RelRwReadError;
 use reliable_rw::WriteError as RelRwWriteError;
 
-use repository::{Repository, FullBackup, IncrementalBackup};
+use repository::{Repository, BackupNode, FullBackup, IncrementalBackup};
+use object::{ObjectEncoding, PendingObject, FinishedObject, PlainObject, PartialUpload,
+             sidecar_path, open_object};
+use encryption::MetadataEnvelope;
+use metadata::{METADATA_SIDECAR, ENVELOPE_SIDECAR, ObjectMetadata, HashingWriter, HashingReader, EncapDigestReader};
+use lock::{RepositoryLock, SharedLock, ExclusiveLock};
+use format::check_version;
+use quota::{Quota, Usage, Allowance, QuotaRejection, QuotaWriter};
+use query::{NodeQuery, NodeInfo};
+use planner::RestorePlan;
+use concat::{BtrfsCommandConcatIter, ConcatReader};
+use config::RepositoryConfig;
+use auth::{ClientIdentity, ClientRole, UploadRole, ReadRole, AdminRole, new_challenge, respond};
 
 
 static MAGIC_REQUEST: &'static [u8] = b"\xa8\x5b\x4b\x2b\x1b\x75\x4c\x0a";
 static MAGIC_RESPONSE: &'static [u8] = b"\xfb\x70\x4c\x63\x41\x1d\x9c\x0a";
 
+// Followed by the namespace name: len: u32be, name: [u8, ..len].  The
+// plain request magic selects the default namespace.
+static MAGIC_REQUEST_NAMESPACE: &'static [u8] = b"\xa8\x5b\x4b\x2b\x1b\x75\x4e\x0a";
+// Sent instead of MAGIC_RESPONSE when the namespace can't be used
+static MAGIC_REFUSED: &'static [u8] = b"\xfb\x70\x4c\x63\x41\x1d\x9c\x00";
+// Followed by a ClientHello.  The server answers with MAGIC_RESPONSE or
+// MAGIC_REFUSED, then a ServerHello.
+static MAGIC_REQUEST_VERSIONED: &'static [u8] = b"\xa8\x5b\x4b\x2b\x1b\x75\x56\x0a";
+// Sent instead of MAGIC_RESPONSE, followed by an AuthChallenge, when the
+// server wants to know who the client is.  The client answers with an
+// AuthResponse, then the handshake ends as usual.
+static MAGIC_CHALLENGE: &'static [u8] = b"\xfb\x70\x4c\x63\x41\x1d\x9c\x01";
 
+// The versions of the protocol this side speaks.  Sessions opened with
+// the older, unversioned magics are version 0 and may use everything.
+pub static PROTOCOL_VERSION: u32 = 2;
+pub static MIN_PROTOCOL_VERSION: u32 = 1;
+static UNVERSIONED: u32 = 0;
+// The first version whose clients answer MAGIC_CHALLENGE
+static AUTH_VERSION: u32 = 2;
+
+// Optional parts of the protocol.  A versioned session only uses what
+// both sides list at the handshake; see `ProtocolCommand::capability`.
+pub static CAPABILITIES: &'static [&'static str] = &[
+    "namespaces",
+    "metadata-upload",
+    "encrypted-upload",
+    "download",
+    "download-chain",
+    "query",
+    "quota",
+    "delete",
+    "resumable-upload",
+];
+
+static MAX_NAMESPACE_REQUEST: uint = 255;
+
+static MAX_REQUEST_SIZE: uint = 1 << 20;
+
+
+// Runs a reliable-rw copy, turning its errors into IoErrors
+macro_rules! relrw_io(
+    ($copy:expr) => (
+        match $copy {
+            Ok(()) => Ok(()),
+            Err(IntegrityError) => Err(IoError {
+                kind: OtherIoError,
+                desc: "IntegrityError during read",
+                detail: None
+            }),
+            Err(RelRwProtocolError) => Err(IoError {
+                kind: OtherIoError,
+                desc: "ProtocolError during read",
+                detail: None
+            }),
+            Err(RelRwReadError(io_error)) => Err(io_error),
+            Err(RelRwWriteError(io_error)) => Err(io_error),
+        }
+    )
+)
+
+
+#[deriving(Show)]
 pub enum ProtocolError {
     ReadError(IoError),
     ObjectDecode(DecoderError),
@@ -27,9 +105,9 @@ pub enum ProtocolError {
 
 #[deriving(Encodable, Decodable)]
 pub struct Edge {
-    size: u64,
-    from_node: Option<Uuid>,
-    to_node: Uuid
+    pub size: u64,
+    pub from_node: Option<Uuid>,
+    pub to_node: Uuid
 }
 
 
@@ -42,7 +120,7 @@ impl Edge {
 
 #[deriving(Encodable, Decodable)]
 pub struct Graph {
-    edges: Vec<Edge>
+    pub edges: Vec<Edge>
 }
 
 impl Graph {
@@ -61,12 +139,472 @@ pub enum ProtocolCommand {
     ListNodes = 2,
     UploadArchive = 3,
     GetGraph = 4,
+    ForceUploadArchive = 5,
+    UploadEncryptedArchive = 6,
+    UploadArchiveWithMetadata = 7,
+    DownloadArchive = 8,
+    GetQuota = 9,
+    QueryNodes = 10,
+    DownloadChain = 11,
+    DeleteNode = 12,
+    Prune = 13,
+    BeginUpload = 14,
+    ResumeUpload = 15,
+}
+
+
+impl ProtocolCommand {
+    /// Whether the command can remove or overwrite anything already
+    /// committed.  Append-only repositories refuse these.  Listed out so
+    /// a new command has to be put on one side or the other.
+    pub fn is_destructive(&self) -> bool {
+        match *self {
+            Quit | FindNodes | ListNodes | QueryNodes | GetGraph | DownloadArchive |
+                DownloadChain | GetQuota => false,
+            // Forcing only stores another copy; nothing is replaced
+            UploadArchive | ForceUploadArchive | UploadEncryptedArchive |
+                UploadArchiveWithMetadata | BeginUpload | ResumeUpload => false,
+            DeleteNode | Prune => true
+        }
+    }
+
+    /// What a versioned session has to have agreed on to use the
+    /// command.  `None` for the commands every version has.
+    pub fn capability(&self) -> Option<&'static str> {
+        match *self {
+            Quit | FindNodes | ListNodes | UploadArchive | GetGraph | ForceUploadArchive => None,
+            UploadArchiveWithMetadata => Some("metadata-upload"),
+            UploadEncryptedArchive => Some("encrypted-upload"),
+            DownloadArchive => Some("download"),
+            DownloadChain => Some("download-chain"),
+            QueryNodes => Some("query"),
+            GetQuota => Some("quota"),
+            DeleteNode | Prune => Some("delete"),
+            BeginUpload | ResumeUpload => Some("resumable-upload")
+        }
+    }
+
+    /// Whether an authenticated client with `role` may use the command.
+    pub fn allowed_for(&self, role: &ClientRole) -> bool {
+        match *role {
+            AdminRole => true,
+            ReadRole => match *self {
+                Quit | FindNodes | ListNodes | QueryNodes | GetGraph | GetQuota |
+                    DownloadArchive | DownloadChain => true,
+                _ => false
+            },
+            UploadRole => match *self {
+                Quit | FindNodes | ListNodes | QueryNodes | GetGraph | GetQuota |
+                    UploadArchive | ForceUploadArchive | UploadEncryptedArchive |
+                    UploadArchiveWithMetadata | BeginUpload | ResumeUpload => true,
+                _ => false
+            }
+        }
+    }
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct EncryptedUploadRequest {
+    pub force: bool,
+    // Who the upload counts against, as for MetadataUploadRequest
+    pub client: Option<String>,
+    pub envelope: MetadataEnvelope
+}
+
+
+fn protocol_error(detail: String) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: "protocol error",
+        detail: Some(detail)
+    }
+}
+
+
+// JSON messages are sent as len: u32be, json: [u8, ..len]
+fn read_json<T: Decodable<json::Decoder, DecoderError>>(reader: &mut Reader) -> IoResult<T> {
+    let len = try!(reader.read_be_u32()) as uint;
+    if len > MAX_REQUEST_SIZE {
+        return Err(IoError {
+            kind: OtherIoError,
+            desc: "request too large",
+            detail: Some(format!("{} bytes", len))
+        });
+    }
+    let bytes = try!(reader.read_exact(len));
+    let string = match String::from_utf8(bytes) {
+        Ok(string) => string,
+        Err(_) => return Err(IoError {
+            kind: OtherIoError,
+            desc: "request is not utf-8",
+            detail: None
+        })
+    };
+    match json::decode(string.as_slice()) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(IoError {
+            kind: OtherIoError,
+            desc: "malformed request",
+            detail: Some(format!("{}", err))
+        })
+    }
+}
+
+
+fn write_json<'a, T: Encodable<json::Encoder<'a>, IoError>>(writer: &mut Writer, value: &T) -> IoResult<()> {
+    let encoded = json::encode(value);
+    try!(writer.write_be_u32(encoded.len() as u32));
+    writer.write(encoded.as_bytes())
+}
+
+
+fn read_uuid(reader: &mut Reader) -> IoResult<Uuid> {
+    let bytes = try!(reader.read_exact(16));
+    match Uuid::from_bytes(bytes.as_slice()) {
+        Some(uuid) => Ok(uuid),
+        None => unreachable!()
+    }
+}
+
+
+enum Handshake {
+    BadMagic,
+    // `None` is the default namespace
+    SelectNamespace(Option<String>),
+    Versioned(ClientHello)
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct ClientHello {
+    // The range of versions the client speaks
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Vec<String>,
+    // `None` is the default namespace
+    pub namespace: Option<String>
+}
+
+
+// What a session was refused over, for the refusals a client can do
+// something about
+#[deriving(Encodable, Decodable, PartialEq, Clone, Show)]
+pub enum Refusal {
+    NamespaceRefused,
+    AuthRefused
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct ServerHello {
+    // The version the session uses, or 0 if there's none in common
+    pub version: u32,
+    // What both sides have
+    pub capabilities: Vec<String>,
+    // Why the session was refused
+    pub error: Option<String>,
+    pub refusal: Option<Refusal>
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct AuthChallenge {
+    // Hex random bytes the client proves it holds its key against
+    pub challenge: String
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct AuthResponse {
+    // `None` if the client has no credentials to offer
+    pub client: Option<String>,
+    // Hex HMAC-SHA256 of the challenge under the client's key
+    pub response: Option<String>
+}
+
+
+/// The newest version both sides speak.
+fn common_version(hello: &ClientHello) -> Option<u32> {
+    let newest = min(hello.max_version, PROTOCOL_VERSION);
+    if newest >= max(hello.min_version, MIN_PROTOCOL_VERSION) {
+        Some(newest)
+    } else {
+        None
+    }
+}
+
+
+// What the server has to offer a session on `repo`
+fn offered_capabilities(repo: &Repository) -> Vec<String> {
+    CAPABILITIES.iter()
+        .filter(|cap| **cap != "delete" || repo.config.allows_remote_delete())
+        .map(|cap| cap.to_string())
+        .collect()
+}
+
+
+// Describes the upload that follows.  Metadata the client can't know,
+// like the content hash and upload time, is filled in by the server.
+#[deriving(Encodable, Decodable)]
+pub struct MetadataUploadRequest {
+    pub force: bool,
+    pub client: Option<String>,
+    pub source_path: Option<String>,
+    pub labels: Vec<String>
+}
+
+
+// Names one edge of the graph: the object holding `uuid` as sent
+// against `parent`, or as a full backup if there is none.
+#[deriving(Encodable, Decodable)]
+pub struct DownloadRequest {
+    pub uuid: Uuid,
+    pub parent: Option<Uuid>
+}
+
+
+// Precedes a downloaded stream, with what's needed to store it again
+#[deriving(Encodable, Decodable)]
+pub struct DownloadHeader {
+    pub size: u64,
+    pub envelope: Option<MetadataEnvelope>,
+    pub metadata: Option<ObjectMetadata>
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct ChainRequest {
+    pub target: Uuid
+}
+
+
+// Precedes a restore stream: the edges it was put together from, in
+// replay order
+#[deriving(Encodable, Decodable)]
+pub struct ChainHeader {
+    pub target: Uuid,
+    pub steps: Vec<DownloadRequest>
+}
+
+
+// Sent instead of a ChainHeader when the chain is there but can't be
+// read back
+#[deriving(Encodable, Decodable)]
+pub struct ChainError {
+    pub error: String
+}
+
+
+// Removes the object holding `uuid` as sent against `parent`
+#[deriving(Encodable, Decodable)]
+pub struct DeleteRequest {
+    pub uuid: Uuid,
+    pub parent: Option<Uuid>,
+    // Also remove whatever can't be restored without it
+    pub cascade: bool,
+    // Only report what would be removed
+    pub dry_run: bool
+}
+
+
+// Removes every object of the snapshots `query` finds
+#[deriving(Encodable, Decodable)]
+pub struct PruneRequest {
+    pub query: NodeQuery,
+    pub cascade: bool,
+    pub dry_run: bool
+}
+
+
+#[deriving(Encodable, Decodable, Show)]
+pub struct DeleteReport {
+    // Why nothing, or not everything, was removed
+    pub refused: Option<String>,
+    // Removed, or what would be on a dry run
+    pub removed: Vec<NodeInfo>,
+    // Left unrestorable by the request, and so keeping it from going ahead
+    pub dependents: Vec<NodeInfo>
+}
+
+
+impl DeleteReport {
+    fn refused(reason: String) -> DeleteReport {
+        DeleteReport {
+            refused: Some(reason),
+            removed: Vec::new(),
+            dependents: Vec::new()
+        }
+    }
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct QuotaRequest {
+    pub client: Option<String>
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct QuotaReport {
+    pub quota: Option<Quota>,
+    pub usage: Usage,
+    pub client_quota: Option<Quota>,
+    // Only when the request named a client
+    pub client_usage: Option<Usage>
+}
+
+
+// Where a resumable upload stands.  The client sends the stream from
+// `offset` on, then an UploadDigest.
+#[deriving(Encodable, Decodable)]
+pub struct UploadSession {
+    pub session: Uuid,
+    // How much of the stream the server already has
+    pub offset: u64
+}
+
+
+#[deriving(Encodable, Decodable)]
+pub struct ResumeRequest {
+    pub session: Uuid
+}
+
+
+// Ends a resumable upload, covering all of the stream and not only what
+// was sent this session
+#[deriving(Encodable, Decodable)]
+pub struct UploadDigest {
+    // Hex SHA-256 of the whole stream
+    pub sha256: String
+}
+
+
+pub enum UploadResult {
+    UploadFailed,
+    UploadCommitted(Uuid),
+    // The server already holds this snapshot
+    UploadDuplicate(Uuid),
+    // Refused over quota.  The server ends the session after this.
+    UploadOverQuota(QuotaRejection)
+}
+
+
+// One stream that restores the plan's target: the full backup on its
+// own, or the chain concatenated into a full of the target.
+fn open_chain(plan: &RestorePlan) -> IoResult<Box<Reader + 'static>> {
+    if plan.len() == 1 {
+        let reader = try!(open_object(&plan.steps[0].path));
+        return Ok(box BufferedReader::new(reader) as Box<Reader + 'static>);
+    }
+    let iter = try!(BtrfsCommandConcatIter::new_synthetic(plan.paths()));
+    Ok(box ConcatReader::new(iter) as Box<Reader + 'static>)
+}
+
+
+fn node_infos(nodes: &[&BackupNode]) -> Vec<NodeInfo> {
+    nodes.iter().map(|n| NodeInfo::from_node(*n)).collect()
+}
+
+
+// Every object `repo` has indexed, orphan or not
+fn indexed_paths(repo: &Repository) -> HashSet<Path> {
+    repo.nodes.iter().chain(repo.orphans.iter()).map(|n| n.path.clone()).collect()
+}
+
+
+// Removes the objects at the paths `select` picks, and whatever can't be
+// restored without them if `cascade`.  The index is read again under the
+// exclusive lock first, so nothing uploaded since the session started
+// gets orphaned.
+fn delete_nodes(repo: &mut Repository, select: |&Repository| -> Vec<Path>,
+                cascade: bool, dry_run: bool) -> IoResult<DeleteReport> {
+    let _lock = match try!(repo.try_lock(ExclusiveLock)) {
+        Some(lock) => lock,
+        None => return Ok(DeleteReport::refused("repository is busy".to_string()))
+    };
+    try!(repo.reload());
+    // Asked only now, so a config changed since the session started counts
+    if !repo.config.allows_remote_delete() {
+        return Ok(DeleteReport::refused("remote deletion is disabled".to_string()));
+    }
+
+    let (report, removed_paths) = {
+        let selected = select(&*repo);
+        let targets: Vec<&BackupNode> = repo.iter_nodes()
+            .filter(|n| selected.contains(&n.path))
+            .collect();
+        let dependents = repo.dependents_of(targets.as_slice());
+        if dependents.len() > 0 && !cascade {
+            return Ok(DeleteReport {
+                refused: Some("other nodes depend on these".to_string()),
+                removed: Vec::new(),
+                dependents: node_infos(dependents.as_slice())
+            });
+        }
+
+        let mut removing = targets;
+        removing.extend(dependents.into_iter());
+        // Children first, so stopping partway leaves no orphans
+        removing.sort_by(|a, b| b.ctransid().cmp(&a.ctransid()));
+        for node in removing.iter() {
+            match repo.check_removable(*node) {
+                Ok(()) => (),
+                Err(err) => return Ok(DeleteReport::refused(format!("{}", err)))
+            }
+        }
+        if dry_run {
+            return Ok(DeleteReport {
+                refused: None,
+                removed: node_infos(removing.as_slice()),
+                dependents: Vec::new()
+            });
+        }
+
+        let mut stderr_writer = stderr();
+        let mut report = DeleteReport {
+            refused: None,
+            removed: Vec::new(),
+            dependents: Vec::new()
+        };
+        let mut removed_paths = Vec::new();
+        for node in removing.iter() {
+            match repo.delete_node(*node) {
+                Ok(()) => {
+                    assert!(stderr_writer.write(format!(
+                        "SERVER: removed {}\n", node.path.display()).as_bytes()).is_ok());
+                    report.removed.push(NodeInfo::from_node(*node));
+                    removed_paths.push(node.path.clone());
+                },
+                Err(err) => {
+                    report.refused = Some(format!("error removing {}: {}", node.path.display(), err));
+                    break;
+                }
+            }
+        }
+        (report, removed_paths)
+    };
+    repo.nodes.retain(|n| !removed_paths.contains(&n.path));
+    Ok(report)
+}
+
+
+fn read_snapshot_uuid(path: &Path) -> Option<Uuid> {
+    Repository::read_node(path).ok().map(|node| node.uuid)
 }
 
 
 pub struct ProtocolServer<'a> {
     reader: &'a mut Reader+'a,
-    writer: &'a mut Writer+'a
+    writer: &'a mut Writer+'a,
+    // Agreed at the handshake, if the client used the versioned one
+    versioned: bool,
+    version: u32,
+    capabilities: Vec<String>,
+    // What the client asked for, until the namespace is open
+    requested_capabilities: Vec<String>,
+    // Who the client proved to be, if the server asked
+    identity: Option<ClientIdentity>
 }
 
 
@@ -74,13 +612,143 @@ impl<'a> ProtocolServer<'a> {
     pub fn new<'a>(reader: &'a mut Reader, writer: &'a mut Writer) -> ProtocolServer<'a> {
         ProtocolServer {
             reader: reader,
-            writer: writer
+            writer: writer,
+            versioned: false,
+            version: UNVERSIONED,
+            capabilities: Vec::new(),
+            requested_capabilities: Vec::new(),
+            identity: None
         }
     }
 
-    pub fn read_magic(&mut self) -> IoResult<bool> {
+    fn read_handshake(&mut self) -> IoResult<Handshake> {
         let magic = try!(self.reader.read_exact(MAGIC_REQUEST.len()));
-        Ok(magic.as_slice() == MAGIC_REQUEST)
+        if magic.as_slice() == MAGIC_REQUEST {
+            return Ok(SelectNamespace(None));
+        }
+        if magic.as_slice() == MAGIC_REQUEST_VERSIONED {
+            return Ok(Versioned(try!(self.read_json())));
+        }
+        if magic.as_slice() != MAGIC_REQUEST_NAMESPACE {
+            return Ok(BadMagic);
+        }
+        let len = try!(self.reader.read_be_u32()) as uint;
+        if len > MAX_NAMESPACE_REQUEST {
+            return Ok(BadMagic);
+        }
+        match String::from_utf8(try!(self.reader.read_exact(len))) {
+            Ok(name) => Ok(SelectNamespace(Some(name))),
+            Err(_) => Ok(BadMagic)
+        }
+    }
+
+    fn refuse(&mut self, refusal: Option<Refusal>, reason: String) -> IoResult<()> {
+        let mut stderr_writer = stderr();
+        try!(stderr_writer.write(format!("SERVER: refusing client: {}\n", reason).as_bytes()));
+        try!(self.writer.write(MAGIC_REFUSED));
+        if self.versioned {
+            try!(self.write_json(&ServerHello {
+                version: self.version,
+                capabilities: Vec::new(),
+                error: Some(reason),
+                refusal: refusal
+            }));
+        }
+        self.writer.flush()
+    }
+
+    // Accepts the session.  A versioned client is told what it may use.
+    fn welcome(&mut self, repo: &Repository) -> IoResult<()> {
+        try!(self.writer.write(MAGIC_RESPONSE));
+        if self.versioned {
+            let offered = offered_capabilities(repo);
+            self.capabilities = self.requested_capabilities.iter()
+                .filter(|cap| offered.contains(*cap))
+                .map(|cap| cap.clone())
+                .collect();
+            let hello = ServerHello {
+                version: self.version,
+                capabilities: self.capabilities.clone(),
+                error: None,
+                refusal: None
+            };
+            try!(self.write_json(&hello));
+        }
+        self.writer.flush()
+    }
+
+    // The root of the namespace the client asked for, unless the server
+    // was started pinned to a different one.
+    fn resolve_namespace(&mut self, root: &Path, pinned: Option<&str>,
+                         requested: Option<String>) -> IoResult<Option<Path>> {
+        match check_version(root) {
+            Ok(_) => (),
+            Err(err) => {
+                try!(self.refuse(None, format!("{}", err)));
+                return Ok(None);
+            }
+        }
+        // A client kept to one namespace is pinned to it too
+        let client_ns = self.identity.as_ref().and_then(|identity| identity.namespace.clone());
+        let pinned = match (pinned, client_ns) {
+            (Some(pinned), Some(client_ns)) => {
+                if pinned != client_ns.as_slice() {
+                    try!(self.refuse(Some(NamespaceRefused), format!(
+                        "client is kept to namespace {}, server to {}", client_ns, pinned)));
+                    return Ok(None);
+                }
+                Some(client_ns)
+            },
+            (pinned, client_ns) => client_ns.or(pinned.map(|p| p.to_string()))
+        };
+        let name = match (pinned, requested) {
+            (Some(pinned), None) => Some(pinned),
+            (Some(pinned), Some(requested)) => {
+                if pinned != requested {
+                    try!(self.refuse(Some(NamespaceRefused),
+                                     format!("namespace {} not permitted", requested)));
+                    return Ok(None);
+                }
+                Some(requested)
+            },
+            (None, requested) => requested
+        };
+        let ns_root = match Repository::namespace_root(root, name.as_ref().map(|n| n.as_slice())) {
+            Some(ns_root) => ns_root,
+            None => {
+                try!(self.refuse(Some(NamespaceRefused), format!("invalid namespace {}", name)));
+                return Ok(None);
+            }
+        };
+        if ns_root.exists() {
+            return Ok(Some(ns_root));
+        }
+        // Namespaces are made by the operator unless the config says
+        // otherwise, and then only by admins once clients authenticate
+        let may_create = match self.identity {
+            Some(ref identity) => identity.role == AdminRole,
+            None => try!(RepositoryConfig::load(root)).allows_namespace_creation()
+        };
+        if !may_create {
+            try!(self.refuse(Some(NamespaceRefused), format!("no such namespace {}", name)));
+            return Ok(None);
+        }
+        Repository::create_namespace(root, name.as_ref().map(|n| n.as_slice()))
+    }
+
+    fn open_namespace(&mut self, root: &Path, pinned: Option<&str>,
+                      requested: Option<String>) -> IoResult<Option<Repository>> {
+        let ns_root = match try!(self.resolve_namespace(root, pinned, requested)) {
+            Some(ns_root) => ns_root,
+            None => return Ok(None)
+        };
+        let _lock = try!(RepositoryLock::acquire(&ns_root, SharedLock));
+        let repo = try!(Repository::load_from(&ns_root));
+        let mut stderr_writer = stderr();
+        for diagnostic in repo.diagnostics.iter() {
+            try!(stderr_writer.write(format!("SERVER: skipped {}\n", diagnostic).as_bytes()));
+        }
+        Ok(Some(repo))
     }
 
     pub fn read_parent_list(&mut self) -> IoResult<Vec<Uuid>> {
@@ -138,65 +806,507 @@ impl<'a> ProtocolServer<'a> {
         Ok(())
     }
 
-    fn dispatch_upload_archive(&mut self, repo: &Repository) -> IoResult<()> {
-        let object_id = Uuid::new_v4();
-        let object_id_str = object_id.to_hyphenated_string();
+    fn dispatch_query_nodes(&mut self, repo: &Repository) -> IoResult<()> {
+        let query: NodeQuery = try!(self.read_json());
+        let found: Vec<NodeInfo> = repo.query(&query).into_iter()
+            .map(|node| NodeInfo::from_node(node))
+            .collect();
+        try!(self.write_json(&found));
+        self.writer.flush()
+    }
+
+    fn read_json<T: Decodable<json::Decoder, DecoderError>>(&mut self) -> IoResult<T> {
+        read_json(self.reader)
+    }
+
+    fn write_json<'b, T: Encodable<json::Encoder<'b>, IoError>>(&mut self, value: &T) -> IoResult<()> {
+        write_json(self.writer, value)
+    }
+
+    // Tells the client its upload won't fit.  The rest of the stream
+    // can't be skipped, so the session ends here.
+    fn reject_quota(&mut self, rejection: &QuotaRejection) -> IoResult<()> {
         let mut stderr_writer = stderr();
-        
+        assert!(stderr_writer.write(format!(
+            "SERVER: upload over {} quota: {} of {} {}\n",
+            rejection.scope, rejection.used, rejection.limit, rejection.limit_kind
+        ).as_bytes()).is_ok());
+        try!(self.writer.write(b"\x03"));
+        try!(self.write_json(rejection));
+        try!(self.writer.flush());
+        Err(IoError {
+            kind: OtherIoError,
+            desc: "upload refused over quota",
+            detail: None
+        })
+    }
+
+    // Streams a reliable-rw framed object from the client into `pending`,
+    // along with the metadata the server can work out from the stream.
+    // Fails once more than `allowance` bytes arrive.  On failure the
+    // client is told and the object is gone.
+    fn receive_object(&mut self, mut pending: PendingObject,
+                      allowance: Option<Allowance>) -> IoResult<(FinishedObject, ObjectMetadata)> {
+        let object_id_str = pending.object_id.to_hyphenated_string();
+        let mut stderr_writer = stderr();
+
         assert!(stderr_writer.write(format!(
             "SERVER: obj:{} create\n",
             object_id_str
         ).as_bytes()).is_ok());
 
-        let mut tmp_path = repo.get_root().clone();
-        tmp_path.push(format!("{}.tmp", object_id_str).as_slice());
+        // Scoped so the borrows of the streams end before we answer
+        let (result, rejection) = {
+            let mut reader = EncapDigestReader::new(self.reader);
+            let mut quota_writer = QuotaWriter::new(&mut pending.writer, allowance);
+            let result = {
+                let mut writer = HashingWriter::new(&mut quota_writer);
+                relrw_io!(copy_out(&mut reader, &mut writer)).map(|()| {
+                    let mut metadata = ObjectMetadata::new(writer.digest().as_slice());
+                    metadata.encap_digest = reader.digest().map(|d| d.as_slice().to_hex());
+                    metadata
+                })
+            };
+            (result, quota_writer.rejection())
+        };
+        let result = match result {
+            Ok(metadata) => pending.finish().map(|finished| (finished, metadata)),
+            Err(err) => {
+                let _ = pending.rollback();
+                Err(err)
+            }
+        };
+        match result {
+            Ok(received) => Ok(received),
+            Err(err) => {
+                assert!(stderr_writer.write(format!(
+                    "SERVER: obj:{} rollback: {}\n",
+                    object_id_str, err
+                ).as_bytes()).is_ok());
+                match rejection {
+                    Some(ref rejection) => {
+                        // The rest of the stream is still coming; the
+                        // session can't continue past it.
+                        try!(self.writer.write(b"\x03"));
+                        try!(self.write_json(rejection));
+                    },
+                    None => try!(self.writer.write(b"\x00"))
+                }
+                try!(self.writer.flush());
+                Err(err)
+            }
+        }
+    }
 
-        let mut final_path = repo.get_root().clone();
-        final_path.push(object_id_str.as_slice());
+    fn reject_duplicate(&mut self, finished: FinishedObject, uuid: &Uuid) -> IoResult<()> {
+        let mut stderr_writer = stderr();
+        assert!(stderr_writer.write(format!(
+            "SERVER: obj:{} duplicate of {}, rejecting\n",
+            finished.object_id.to_hyphenated_string(),
+            uuid.to_hyphenated_string()
+        ).as_bytes()).is_ok());
+        try!(finished.rollback());
+        try!(self.writer.write(b"\x02"));
+        try!(self.writer.write(uuid.as_bytes()));
+        try!(self.writer.flush());
+        Ok(())
+    }
 
-        let mut file = try!(File::create(&tmp_path));
+    // Writes the sidecars where the object is about to land, then makes
+    // it visible, so a loaded object always has its sidecars.
+    fn commit_object(&mut self, finished: FinishedObject, metadata: &ObjectMetadata,
+                     envelope: Option<&MetadataEnvelope>) -> IoResult<Path> {
+        let object_id = finished.object_id.clone();
+        let metadata_path = sidecar_path(finished.final_path(), METADATA_SIDECAR);
+        let envelope_path = sidecar_path(finished.final_path(), ENVELOPE_SIDECAR);
+        let saved = metadata.save(&metadata_path).and_then(|()| match envelope {
+            Some(envelope) => envelope.save(&envelope_path),
+            None => Ok(())
+        });
+        match saved {
+            Ok(()) => (),
+            Err(err) => {
+                let _ = unlink(&metadata_path);
+                let _ = unlink(&envelope_path);
+                let _ = finished.rollback();
+                try!(self.writer.write(b"\x00"));
+                try!(self.writer.flush());
+                return Err(err);
+            }
+        }
 
-        let result = match copy_out(self.reader, &mut file) {
-            Ok(()) => {
-                Ok(())
-            },
-            // TODO: fix hacks.
-            Err(IntegrityError) => Err(IoError {
-                kind: OtherIoError,
-                desc: "IntegrityError during read",
-                detail: None
-            }),
-            Err(RelRwProtocolError) => Err(IoError {
-                kind: OtherIoError,
-                desc: "ProtocolError during read",
-                detail: None
-            }),
-            Err(RelRwReadError(io_error)) => Err(io_error),
-            Err(RelRwWriteError(io_error)) => Err(io_error),
+        let mut stderr_writer = stderr();
+        assert!(stderr_writer.write(format!(
+            "SERVER: obj:{} commit\n",
+            object_id.to_hyphenated_string()
+        ).as_bytes()).is_ok());
+        let final_path = try!(finished.commit());
+        try!(self.writer.write(b"\x01"));
+        try!(self.writer.write(object_id.as_bytes()));
+        try!(self.writer.flush());
+        Ok(final_path)
+    }
+
+    // Who an upload counts against: the authenticated client if there
+    // is one, otherwise whoever the request claims
+    fn uploader(&self, claimed: Option<String>) -> Option<String> {
+        match self.identity {
+            Some(ref identity) => Some(identity.name.clone()),
+            None => claimed
+        }
+    }
+
+    fn upload_archive(&mut self, repo: &mut Repository, mut request: MetadataUploadRequest) -> IoResult<()> {
+        request.client = self.uploader(request.client);
+        // Held until the object is committed, so its parent can't be
+        // pruned out from under it.
+        let _lock = try!(repo.lock(SharedLock));
+        let allowance = match repo.admit_upload(request.client.as_ref().map(|c| c.as_slice())) {
+            Ok(allowance) => allowance,
+            Err(rejection) => return self.reject_quota(&rejection)
+        };
+        let pending = try!(repo.create_object());
+        let (finished, mut metadata) = try!(self.receive_object(pending, allowance));
+
+        // Refuse a second copy of a snapshot we already hold, unless the
+        // client asked for it.  Streams we can't parse are kept as before.
+        if !request.force {
+            match read_snapshot_uuid(finished.tmp_path()) {
+                Some(ref uuid) if repo.holds_uuid(uuid) => {
+                    return self.reject_duplicate(finished, uuid);
+                },
+                _ => ()
+            }
+        }
+        metadata.client = request.client;
+        metadata.source_path = request.source_path;
+        metadata.labels = request.labels;
+        let path = try!(self.commit_object(finished, &metadata, None));
+        repo.add_object(&path)
+    }
+
+    fn dispatch_upload_archive(&mut self, repo: &mut Repository, force: bool) -> IoResult<()> {
+        self.upload_archive(repo, MetadataUploadRequest {
+            force: force,
+            client: None,
+            source_path: None,
+            labels: Vec::new()
+        })
+    }
+
+    fn dispatch_upload_archive_with_metadata(&mut self, repo: &mut Repository) -> IoResult<()> {
+        let request: MetadataUploadRequest = try!(self.read_json());
+        self.upload_archive(repo, request)
+    }
+
+    fn dispatch_upload_encrypted_archive(&mut self, repo: &mut Repository) -> IoResult<()> {
+        let mut request: EncryptedUploadRequest = try!(self.read_json());
+        request.client = self.uploader(request.client);
+        let _lock = try!(repo.lock(SharedLock));
+        let allowance = match repo.admit_upload(request.client.as_ref().map(|c| c.as_slice())) {
+            Ok(allowance) => allowance,
+            Err(rejection) => return self.reject_quota(&rejection)
+        };
+
+        // Ciphertext doesn't compress, so don't try
+        let pending = try!(repo.create_object_with(PlainObject));
+        let (finished, mut metadata) = try!(self.receive_object(pending, allowance));
+
+        if !request.force && repo.holds_uuid(&request.envelope.uuid) {
+            return self.reject_duplicate(finished, &request.envelope.uuid);
+        }
+        metadata.client = request.client;
+        let path = try!(self.commit_object(finished, &metadata, Some(&request.envelope)));
+        repo.add_object(&path)
+    }
+
+    fn dispatch_begin_upload(&mut self, repo: &mut Repository) -> IoResult<()> {
+        let mut request: MetadataUploadRequest = try!(self.read_json());
+        request.client = self.uploader(request.client);
+        let _lock = try!(repo.lock(SharedLock));
+        let allowance = match repo.admit_upload(request.client.as_ref().map(|c| c.as_slice())) {
+            Ok(allowance) => allowance,
+            Err(rejection) => return self.reject_quota(&rejection)
+        };
+        let partial = try!(repo.create_partial());
+        let saved = File::create(&partial.request_path()).and_then(|mut file| {
+            try!(file.write_str(json::encode(&request).as_slice()));
+            file.fsync()
+        });
+        match saved {
+            Ok(()) => (),
+            Err(err) => {
+                let _ = partial.remove();
+                return Err(err);
+            }
+        }
+        self.receive_partial(repo, partial, request, allowance)
+    }
+
+    fn dispatch_resume_upload(&mut self, repo: &mut Repository) -> IoResult<()> {
+        let request: ResumeRequest = try!(self.read_json());
+        let _lock = try!(repo.lock(SharedLock));
+        let partial = match repo.find_partial(&request.session) {
+            Some(partial) => partial,
+            None => {
+                try!(self.writer.write(b"\x00"));
+                return self.writer.flush();
+            }
+        };
+        let upload: Option<MetadataUploadRequest> = match File::open(&partial.request_path())
+                .and_then(|mut file| file.read_to_string())
+                .ok()
+                .and_then(|contents| json::decode(contents.as_slice()).ok()) {
+            // Only whoever began an upload may finish it
+            Some(ref upload) if self.identity.is_some()
+                    && self.uploader(None) != upload.client => None,
+            Some(upload) => Some(upload),
+            None => None
+        };
+        let upload = match upload {
+            Some(upload) => upload,
+            None => {
+                try!(self.writer.write(b"\x00"));
+                return self.writer.flush();
+            }
+        };
+        let allowance = match repo.admit_upload(upload.client.as_ref().map(|c| c.as_slice())) {
+            Ok(allowance) => allowance,
+            Err(rejection) => return self.reject_quota(&rejection)
+        };
+        self.receive_partial(repo, partial, upload, allowance)
+    }
+
+    // Tells the client how much of the stream is here and appends the
+    // rest.  Whatever arrives is kept if the connection drops, so the
+    // client can resume.  Once the whole stream is here and matches the
+    // client's digest it is stored as an object like any other upload.
+    fn receive_partial(&mut self, repo: &mut Repository, partial: PartialUpload,
+                       request: MetadataUploadRequest, allowance: Option<Allowance>) -> IoResult<()> {
+        let session_str = partial.session.to_hyphenated_string();
+        let mut stderr_writer = stderr();
+        // Two sessions appending at once would interleave their streams
+        let _claim = match try!(partial.claim()) {
+            Some(claim) => claim,
+            None => {
+                assert!(stderr_writer.write(format!(
+                    "SERVER: upload:{} busy in another session\n", session_str
+                ).as_bytes()).is_ok());
+                try!(self.writer.write(b"\x00"));
+                return self.writer.flush();
+            }
+        };
+        let offset = try!(partial.offset());
+        try!(self.writer.write(b"\x01"));
+        try!(self.write_json(&UploadSession {
+            session: partial.session.clone(),
+            offset: offset
+        }));
+        try!(self.writer.flush());
+
+        // `allowance` already counts what arrived in earlier sessions
+        let (result, rejection) = {
+            let mut file = try!(partial.append());
+            let (result, rejection) = {
+                let mut quota_writer = QuotaWriter::new(&mut file, allowance);
+                let result = relrw_io!(copy_out(self.reader, &mut quota_writer));
+                (result, quota_writer.rejection())
+            };
+            (result.and(file.fsync()), rejection)
         };
         match result {
-            Ok(_) => {
+            Ok(()) => (),
+            Err(err) => {
                 assert!(stderr_writer.write(format!(
-                    "SERVER: obj:{} commit\n",
-                    object_id_str
+                    "SERVER: upload:{} kept {} bytes: {}\n",
+                    session_str, partial.offset().unwrap_or(offset), err
                 ).as_bytes()).is_ok());
-                try!(rename(&tmp_path, &final_path));
-                try!(self.writer.write(b"\x01"));
-                try!(self.writer.write(object_id.as_bytes()));
+                match rejection {
+                    Some(ref rejection) => {
+                        try!(self.writer.write(b"\x03"));
+                        try!(self.write_json(rejection));
+                    },
+                    None => try!(self.writer.write(b"\x00"))
+                }
                 try!(self.writer.flush());
-                Ok(())
+                return Err(err);
+            }
+        }
+
+        let expected: UploadDigest = try!(self.read_json());
+        let (finished, digest) = if repo.get_encoding() == PlainObject {
+            // Already stored the way it would be; just move it into place
+            let mut sink = NullWriter;
+            let digest = try!(File::open(partial.path()).and_then(|mut file| {
+                let mut writer = HashingWriter::new(&mut sink);
+                try!(copy(&mut file, &mut writer));
+                Ok(writer.digest())
+            }));
+            (try!(repo.adopt_partial(&partial)), digest)
+        } else {
+            let mut pending = try!(repo.create_object());
+            let copied = File::open(partial.path()).and_then(|mut file| {
+                let mut writer = HashingWriter::new(&mut pending.writer);
+                try!(copy(&mut file, &mut writer));
+                Ok(writer.digest())
+            });
+            match copied {
+                Ok(digest) => (try!(pending.finish()), digest),
+                Err(err) => {
+                    let _ = pending.rollback();
+                    return Err(err);
+                }
+            }
+        };
+        if digest.as_slice().to_hex() != expected.sha256 {
+            // Either side may be wrong; only starting over settles it
+            assert!(stderr_writer.write(format!(
+                "SERVER: upload:{} digest mismatch, discarding\n", session_str
+            ).as_bytes()).is_ok());
+            try!(finished.rollback());
+            try!(partial.remove());
+            try!(self.writer.write(b"\x00"));
+            return self.writer.flush();
+        }
+
+        if !request.force {
+            match read_snapshot_uuid(finished.tmp_path()) {
+                Some(ref uuid) if repo.holds_uuid(uuid) => {
+                    try!(partial.remove());
+                    return self.reject_duplicate(finished, uuid);
+                },
+                _ => ()
+            }
+        }
+        let mut metadata = ObjectMetadata::new(digest.as_slice());
+        metadata.client = request.client;
+        metadata.source_path = request.source_path;
+        metadata.labels = request.labels;
+        let path = try!(self.commit_object(finished, &metadata, None));
+        try!(partial.remove());
+        repo.add_object(&path)
+    }
+
+    fn dispatch_get_quota(&mut self, repo: &Repository) -> IoResult<()> {
+        let mut request: QuotaRequest = try!(self.read_json());
+        request.client = self.uploader(request.client);
+        let report = QuotaReport {
+            quota: repo.config.quota.clone(),
+            usage: repo.usage(),
+            client_quota: repo.config.client_quota.clone(),
+            client_usage: request.client.as_ref().map(|c| repo.client_usage(Some(c.as_slice())))
+        };
+        try!(self.write_json(&report));
+        self.writer.flush()
+    }
+
+    fn dispatch_download_archive(&mut self, repo: &Repository) -> IoResult<()> {
+        let request: DownloadRequest = try!(self.read_json());
+        // Keeps the object from being deleted while we send it
+        let _lock = try!(repo.lock(SharedLock));
+
+        let found = repo.iter_nodes().find(|n| {
+            n.uuid == request.uuid && n.parent_uuid == request.parent
+        });
+        let opened = match found {
+            Some(node) => {
+                let envelope_path = sidecar_path(&node.path, ENVELOPE_SIDECAR);
+                let envelope = if envelope_path.exists() {
+                    MetadataEnvelope::load(&envelope_path).map(|e| Some(e))
+                } else {
+                    Ok(None)
+                };
+                envelope.and_then(|envelope| open_object(&node.path).map(|reader| {
+                    (reader, DownloadHeader {
+                        size: node.size,
+                        envelope: envelope,
+                        metadata: node.metadata.clone()
+                    })
+                })).ok()
             },
-            Err(err) => {
+            None => None
+        };
+        let (mut reader, header) = match opened {
+            Some(opened) => opened,
+            None => {
+                try!(self.writer.write(b"\x00"));
+                return self.writer.flush();
+            }
+        };
+
+        try!(self.writer.write(b"\x01"));
+        try!(self.write_json(&header));
+        try!(relrw_io!(copy_in(&mut reader, self.writer)));
+        self.writer.flush()
+    }
+
+    fn dispatch_download_chain(&mut self, repo: &Repository) -> IoResult<()> {
+        let request: ChainRequest = try!(self.read_json());
+        // Keeps the chain from being deleted while we send it
+        let _lock = try!(repo.lock(SharedLock));
+
+        // We can't replay what clients encrypted
+        let opened = match repo.plan_restore(&request.target) {
+            Some(ref plan) if !plan.steps.iter().any(|n| n.encrypted) => {
+                Some(open_chain(plan).map(|reader| (reader, ChainHeader {
+                    target: request.target.clone(),
+                    steps: plan.steps.iter().map(|n| DownloadRequest {
+                        uuid: n.uuid.clone(),
+                        parent: n.parent_uuid.clone()
+                    }).collect()
+                })))
+            },
+            _ => None
+        };
+        let (mut reader, header) = match opened {
+            Some(Ok(opened)) => opened,
+            Some(Err(err)) => {
+                let mut stderr_writer = stderr();
                 assert!(stderr_writer.write(format!(
-                    "SERVER: obj:{} rollback: {}\n",
-                    object_id_str, err
+                    "SERVER: chain:{} can't be opened: {}\n",
+                    request.target.to_hyphenated_string(), err
                 ).as_bytes()).is_ok());
+                try!(self.writer.write(b"\x02"));
+                try!(self.write_json(&ChainError { error: format!("{}", err) }));
+                return self.writer.flush();
+            },
+            None => {
                 try!(self.writer.write(b"\x00"));
-                try!(unlink(&tmp_path));
-                try!(self.writer.flush());
-                Err(err)
+                return self.writer.flush();
             }
-        }
+        };
+
+        try!(self.writer.write(b"\x01"));
+        try!(self.write_json(&header));
+        try!(relrw_io!(copy_in(&mut reader, self.writer)));
+        self.writer.flush()
+    }
+
+    fn dispatch_delete_node(&mut self, repo: &mut Repository) -> IoResult<()> {
+        let request: DeleteRequest = try!(self.read_json());
+        let report = try!(delete_nodes(repo, |repo| {
+            repo.iter_nodes()
+                .filter(|n| n.uuid == request.uuid && n.parent_uuid == request.parent)
+                .map(|n| n.path.clone())
+                .collect()
+        }, request.cascade, request.dry_run));
+        try!(self.write_json(&report));
+        self.writer.flush()
+    }
+
+    fn dispatch_prune(&mut self, repo: &mut Repository) -> IoResult<()> {
+        let request: PruneRequest = try!(self.read_json());
+        let report = try!(delete_nodes(repo, |repo| {
+            let uuids: HashSet<Uuid> = repo.query(&request.query).iter()
+                .map(|n| n.uuid.clone())
+                .collect();
+            repo.iter_nodes()
+                .filter(|n| uuids.contains(&n.uuid))
+                .map(|n| n.path.clone())
+                .collect()
+        }, request.cascade, request.dry_run));
+        try!(self.write_json(&report));
+        self.writer.flush()
     }
 
     fn dispatch_get_graph(&mut self, repo: &Repository) -> IoResult<()> {
@@ -236,50 +1346,254 @@ impl<'a> ProtocolServer<'a> {
         Ok(())
     }
 
-    fn dispatch(&mut self, repo: &Repository, command: ProtocolCommand) -> IoResult<()> {
+    fn dispatch(&mut self, repo: &mut Repository, command: ProtocolCommand) -> IoResult<()> {
+        match command.capability() {
+            Some(cap) if self.versioned &&
+                    !self.capabilities.iter().any(|c| c.as_slice() == cap) => {
+                let mut stderr_writer = stderr();
+                assert!(stderr_writer.write(format!(
+                    "SERVER: refusing {}: {} wasn't agreed at the handshake\n", command, cap
+                ).as_bytes()).is_ok());
+                return Err(IoError {
+                    kind: OtherIoError,
+                    desc: "command not negotiated",
+                    detail: Some(format!("{}", command))
+                });
+            },
+            _ => ()
+        }
+        match self.identity {
+            Some(ref identity) if !command.allowed_for(&identity.role) => {
+                let mut stderr_writer = stderr();
+                assert!(stderr_writer.write(format!(
+                    "SERVER: refusing {} to {}, a {}\n", command, identity.name, identity.role
+                ).as_bytes()).is_ok());
+                return Err(IoError {
+                    kind: OtherIoError,
+                    desc: "command not permitted",
+                    detail: Some(format!("{}", command))
+                });
+            },
+            _ => ()
+        }
+        if command.is_destructive() && repo.config.is_append_only() {
+            let mut stderr_writer = stderr();
+            assert!(stderr_writer.write(format!(
+                "SERVER: refusing {} in an append-only repository\n", command
+            ).as_bytes()).is_ok());
+            // Whatever the command would have sent is left unread
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "destructive command refused",
+                detail: Some(format!("{}", command))
+            });
+        }
         Ok(match command {
             Quit => (),
             FindNodes => try!(self.dispatch_find_nodes(repo)),
             ListNodes => try!(self.dispatch_list_nodes(repo)),
-            UploadArchive => try!(self.dispatch_upload_archive(repo)),
+            QueryNodes => try!(self.dispatch_query_nodes(repo)),
+            UploadArchive => try!(self.dispatch_upload_archive(repo, false)),
+            ForceUploadArchive => try!(self.dispatch_upload_archive(repo, true)),
+            UploadEncryptedArchive => try!(self.dispatch_upload_encrypted_archive(repo)),
+            UploadArchiveWithMetadata => try!(self.dispatch_upload_archive_with_metadata(repo)),
+            DownloadArchive => try!(self.dispatch_download_archive(repo)),
+            DownloadChain => try!(self.dispatch_download_chain(repo)),
+            DeleteNode => try!(self.dispatch_delete_node(repo)),
+            Prune => try!(self.dispatch_prune(repo)),
+            BeginUpload => try!(self.dispatch_begin_upload(repo)),
+            ResumeUpload => try!(self.dispatch_resume_upload(repo)),
+            GetQuota => try!(self.dispatch_get_quota(repo)),
             GetGraph => try!(self.dispatch_get_graph(repo)),
         })
     }
 
-    pub fn run(&mut self, repo: &Repository) -> IoResult<()> {
+    // The namespace the client asks for, or `None` if the session is
+    // already over
+    fn accept_handshake(&mut self) -> IoResult<Option<Option<String>>> {
         let mut stderr_writer = stderr();
-        let is_valid = try!(self.read_magic());
-        if !is_valid {
-            try!(stderr_writer.write("Invalid magic".as_bytes()));
-            try!(stderr_writer.flush());
-            return Ok(()); // FIXME?
+        match try!(self.read_handshake()) {
+            SelectNamespace(requested) => Ok(Some(requested)),
+            Versioned(hello) => {
+                self.versioned = true;
+                self.requested_capabilities = hello.capabilities.clone();
+                match common_version(&hello) {
+                    Some(_) if hello.namespace.is_some()
+                            && !hello.capabilities.iter().any(|cap| cap.as_slice() == "namespaces") => {
+                        try!(self.refuse(None, "a namespace was asked for without the namespaces capability"
+                                               .to_string()));
+                        Ok(None)
+                    },
+                    Some(version) => {
+                        self.version = version;
+                        Ok(Some(hello.namespace))
+                    },
+                    None => {
+                        try!(self.refuse(None, format!(
+                            "no common protocol version: client speaks {} to {}, server {} to {}",
+                            hello.min_version, hello.max_version,
+                            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)));
+                        Ok(None)
+                    }
+                }
+            },
+            BadMagic => {
+                try!(stderr_writer.write("Invalid magic".as_bytes()));
+                try!(stderr_writer.flush());
+                Ok(None) // FIXME?
+            }
         }
-        try!(self.writer.write(MAGIC_RESPONSE));
+    }
+
+    // Makes the client prove who it is, if the root config lists clients.
+    // Returns false once the session has been refused.
+    fn authenticate(&mut self, root: &Path) -> IoResult<bool> {
+        let config = match RepositoryConfig::load(root) {
+            Ok(config) => config,
+            Err(err) => {
+                try!(self.refuse(None, format!("{}", err)));
+                return Ok(false);
+            }
+        };
+        if !config.requires_auth() {
+            return Ok(true);
+        }
+        if self.version < AUTH_VERSION {
+            try!(self.refuse(Some(AuthRefused), format!(
+                "the server requires authentication, from protocol version {}", AUTH_VERSION)));
+            return Ok(false);
+        }
+
+        let challenge = try!(new_challenge());
+        try!(self.writer.write(MAGIC_CHALLENGE));
+        try!(self.write_json(&AuthChallenge { challenge: challenge.clone() }));
+        try!(self.writer.flush());
+        let answer: AuthResponse = try!(self.read_json());
+        let identity = match (answer.client, answer.response) {
+            (Some(ref name), Some(ref response)) => match config.find_client(name.as_slice()) {
+                Some(identity) if identity.verify(challenge.as_slice(), response.as_slice()) =>
+                    Some(identity.clone()),
+                _ => None
+            },
+            _ => None
+        };
+        match identity {
+            Some(identity) => {
+                let mut stderr_writer = stderr();
+                try!(stderr_writer.write(format!("SERVER: authenticated {} as a {}\n",
+                                                 identity.name, identity.role).as_bytes()));
+                self.identity = Some(identity);
+                Ok(true)
+            },
+            None => {
+                try!(self.refuse(Some(AuthRefused), "authentication failed".to_string()));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Serves one client.  The handshake picks the namespace of `root`
+    /// to serve; `pinned` restricts the client to that one namespace.
+    pub fn run(&mut self, root: &Path, pinned: Option<&str>,
+               encoding: ObjectEncoding) -> IoResult<()> {
+        let requested = match try!(self.accept_handshake()) {
+            Some(requested) => requested,
+            None => return Ok(())
+        };
+        if !try!(self.authenticate(root)) {
+            return Ok(());
+        }
+        let mut repo = match try!(self.open_namespace(root, pinned, requested)) {
+            Some(repo) => repo,
+            None => return Ok(())
+        };
+        repo.set_encoding(encoding);
+        try!(self.welcome(&repo));
+        self.serve(&mut repo)
+    }
+
+    /// Serves one client of a daemon.  `open` hands out the namespace at
+    /// a root, shared with the daemon's other sessions.
+    pub fn run_live(&mut self, root: &Path, pinned: Option<&str>,
+                    open: |&Path| -> IoResult<Arc<Mutex<Repository>>>) -> IoResult<()> {
+        let requested = match try!(self.accept_handshake()) {
+            Some(requested) => requested,
+            None => return Ok(())
+        };
+        if !try!(self.authenticate(root)) {
+            return Ok(());
+        }
+        let ns_root = match try!(self.resolve_namespace(root, pinned, requested)) {
+            Some(ns_root) => ns_root,
+            None => return Ok(())
+        };
+        let repo = try!(open(&ns_root));
+        try!(self.welcome(&*repo.lock()));
+        self.serve_live(&*repo)
+    }
+
+    // The next command, or `None` once the client is done
+    fn read_command(&mut self) -> IoResult<Option<ProtocolCommand>> {
+        let mut stderr_writer = stderr();
+        let op_code: Option<ProtocolCommand> = FromPrimitive::from_u64(
+            try!(self.reader.read_be_u64()));
+        try!(stderr_writer.write(format!("handling {}\n", op_code).as_bytes()));
+        try!(stderr_writer.flush());
 
+        match op_code {
+            Some(Quit) => Ok(None),
+            Some(val) => Ok(Some(val)),
+            None => {
+                try!(stderr_writer.write("Invalid magic".as_bytes()));
+                try!(stderr_writer.flush());
+                Ok(None) // FIXME?
+            }
+        }
+    }
+
+    fn serve(&mut self, repo: &mut Repository) -> IoResult<()> {
+        loop {
+            match try!(self.read_command()) {
+                Some(command) => try!(self.dispatch(repo, command)),
+                None => return Ok(())
+            }
+        }
+    }
+
+    fn serve_live(&mut self, repo: &Mutex<Repository>) -> IoResult<()> {
         loop {
-            let op_code: Option<ProtocolCommand> = FromPrimitive::from_u64(
-                try!(self.reader.read_be_u64()));
-            try!(stderr_writer.write(format!("handling {}\n", op_code).as_bytes()));
-            try!(stderr_writer.flush());
-
-            match op_code {
-                Some(Quit) => break,
-                Some(val) => try!(self.dispatch(repo, val)),
-                None => {
-                    try!(stderr_writer.write("Invalid magic".as_bytes()));
-                    try!(stderr_writer.flush());
-                    return Ok(()); // FIXME?
+            let command = match try!(self.read_command()) {
+                Some(command) => command,
+                None => return Ok(())
+            };
+            // Each command works on a copy of the index as it stands, so
+            // a long transfer holds up neither other sessions nor the
+            // watcher.  Whatever it committed or removed is passed on
+            // after, without waiting for the watcher to see it.
+            let mut working = repo.lock().clone();
+            let before = indexed_paths(&working);
+            let result = self.dispatch(&mut working, command);
+            let after = indexed_paths(&working);
+            {
+                let mut shared = repo.lock();
+                for path in before.symmetric_difference(&after) {
+                    let _ = shared.refresh_path(path);
                 }
             }
+            try!(result);
         }
-        Ok(())
     }
 }
 
 
 pub struct ProtocolClient<'a> {
     reader: &'a mut Reader+'a,
-    writer: &'a mut Writer+'a
+    writer: &'a mut Writer+'a,
+    // Agreed at the handshake
+    version: u32,
+    capabilities: Vec<String>,
+    // Name and key to answer a challenge with
+    credentials: Option<(String, Vec<u8>)>
 }
 
 
@@ -287,11 +1601,148 @@ impl<'a> ProtocolClient<'a> {
     pub fn new<'a>(reader: &'a mut Reader, writer: &'a mut Writer) -> ProtocolClient<'a> {
         ProtocolClient {
             reader: reader,
-            writer: writer
+            writer: writer,
+            version: UNVERSIONED,
+            capabilities: Vec::new(),
+            credentials: None
         }
     }
 
+    /// A client for a session already opened, as `version()` and
+    /// `capabilities()` of the client that opened it report.
+    pub fn resume<'a>(reader: &'a mut Reader, writer: &'a mut Writer,
+                      version: u32, capabilities: Vec<String>) -> ProtocolClient<'a> {
+        let mut client = ProtocolClient::new(reader, writer);
+        client.version = version;
+        client.capabilities = capabilities;
+        client
+    }
+
+    /// Who to say the client is, should the server ask.
+    pub fn set_credentials(&mut self, name: &str, key: Vec<u8>) {
+        self.credentials = Some((name.to_string(), key));
+    }
+
+    /// Opens the session, in `namespace` if given.  Returns whether the
+    /// server accepted it; `false` means it refused the namespace.  A
+    /// server that turns down the client's credentials is a
+    /// `PermissionDenied` error, and one with no protocol version in
+    /// common another error.  A server from before protocol versions
+    /// hangs up instead, which is an `EndOfFile` error;
+    /// `legacy_handshake` on a new connection gets through to it.
+    pub fn handshake(&mut self, namespace: Option<&str>) -> IoResult<bool> {
+        try!(self.writer.write(MAGIC_REQUEST_VERSIONED));
+        try!(write_json(self.writer, &ClientHello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|cap| cap.to_string()).collect(),
+            namespace: namespace.map(|name| name.to_string())
+        }));
+        try!(self.writer.flush());
+        let mut magic = match self.reader.read_exact(MAGIC_RESPONSE.len()) {
+            Ok(magic) => magic,
+            // What a server from before versions does with this magic
+            Err(ref err) if err.kind == EndOfFile => return Err(IoError {
+                kind: EndOfFile,
+                desc: "server hung up at the handshake",
+                detail: Some("it may predate protocol versions".to_string())
+            }),
+            Err(err) => return Err(err)
+        };
+        if magic.as_slice() == MAGIC_CHALLENGE {
+            let challenge: AuthChallenge = try!(read_json(self.reader));
+            let answer = match self.credentials {
+                Some((ref name, ref key)) => AuthResponse {
+                    client: Some(name.clone()),
+                    response: Some(respond(key.as_slice(), challenge.challenge.as_slice()))
+                },
+                None => AuthResponse { client: None, response: None }
+            };
+            try!(write_json(self.writer, &answer));
+            try!(self.writer.flush());
+            magic = try!(self.reader.read_exact(MAGIC_RESPONSE.len()));
+        }
+        let accepted = magic.as_slice() == MAGIC_RESPONSE;
+        if !accepted && magic.as_slice() != MAGIC_REFUSED {
+            return Err(protocol_error("unexpected handshake response".to_string()));
+        }
+        let hello: ServerHello = try!(read_json(self.reader));
+        if !accepted {
+            return match hello.refusal {
+                Some(NamespaceRefused) => Ok(false),
+                Some(AuthRefused) => Err(IoError {
+                    kind: PermissionDenied,
+                    desc: "the server refused the credentials",
+                    detail: hello.error
+                }),
+                None => Err(protocol_error(
+                    hello.error.unwrap_or("the server refused the session".to_string())))
+            };
+        }
+        if namespace.is_some() && !hello.capabilities.iter().any(|cap| cap.as_slice() == "namespaces") {
+            return Err(protocol_error("the server doesn't offer namespaces".to_string()));
+        }
+        self.version = hello.version;
+        self.capabilities = hello.capabilities;
+        Ok(true)
+    }
+
+    /// Opens a version 0 session, with the magics servers spoke before
+    /// protocol versions.  There is no way to authenticate, and a
+    /// refusal doesn't say why: `false` may also mean the server wants
+    /// credentials.
+    pub fn legacy_handshake(&mut self, namespace: Option<&str>) -> IoResult<bool> {
+        match namespace {
+            Some(name) => {
+                try!(self.writer.write(MAGIC_REQUEST_NAMESPACE));
+                try!(self.writer.write_be_u32(name.len() as u32));
+                try!(self.writer.write_str(name));
+            },
+            None => try!(self.writer.write(MAGIC_REQUEST))
+        }
+        try!(self.writer.flush());
+        let magic = try!(self.reader.read_exact(MAGIC_RESPONSE.len()));
+        if magic.as_slice() != MAGIC_RESPONSE && magic.as_slice() != MAGIC_REFUSED {
+            return Err(protocol_error("unexpected handshake response".to_string()));
+        }
+        self.version = UNVERSIONED;
+        self.capabilities = Vec::new();
+        Ok(magic.as_slice() == MAGIC_RESPONSE)
+    }
+
+    /// The protocol version of the session.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// What the session may use, as agreed at the handshake.
+    pub fn capabilities(&self) -> Vec<String> {
+        self.capabilities.clone()
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap.as_slice() == capability)
+    }
+
+    fn send_command(&mut self, command: ProtocolCommand) -> IoResult<()> {
+        match command.capability() {
+            // Version 0 sessions may use everything
+            Some(cap) if self.version != UNVERSIONED && !self.has_capability(cap) => Err(protocol_error(
+                format!("the server doesn't offer {}, needed for {}", cap, command))),
+            _ => self.writer.write_be_u64(command as u64)
+        }
+    }
+
+    pub fn quit(&mut self) -> IoResult<()> {
+        try!(self.send_command(Quit));
+        self.writer.flush()
+    }
+
     pub fn get_graph(&mut self) -> Result<Graph, ProtocolError> {
+        match self.send_command(GetGraph).and(self.writer.flush()) {
+            Ok(()) => (),
+            Err(err) => return Err(ReadError(err))
+        }
         let len = match self.reader.read_be_u32() {
             Ok(len) => len as uint,
             Err(err) => return Err(ReadError(err))
@@ -309,4 +1760,188 @@ impl<'a> ProtocolClient<'a> {
             Err(err) => Err(ObjectDecode(err))
         }
     }
+
+    /// Writes the logical stream of one object to `writer`.  `None` if
+    /// the server doesn't have it.
+    pub fn download_archive(&mut self, uuid: &Uuid, parent: Option<&Uuid>,
+                            writer: &mut Writer) -> IoResult<Option<DownloadHeader>> {
+        try!(self.send_command(DownloadArchive));
+        try!(write_json(self.writer, &DownloadRequest {
+            uuid: uuid.clone(),
+            parent: parent.map(|p| p.clone())
+        }));
+        try!(self.writer.flush());
+        match try!(self.reader.read_u8()) {
+            0 => return Ok(None),
+            1 => (),
+            other => return Err(protocol_error(format!("unexpected response {}", other)))
+        }
+        let header: DownloadHeader = try!(read_json(self.reader));
+        try!(relrw_io!(copy_out(self.reader, writer)));
+        Ok(Some(header))
+    }
+
+    /// Writes a stream that restores `target` with one `btrfs receive`.
+    /// `None` if the server has no way to restore it.
+    pub fn download_chain(&mut self, target: &Uuid,
+                          writer: &mut Writer) -> IoResult<Option<ChainHeader>> {
+        try!(self.send_command(DownloadChain));
+        try!(write_json(self.writer, &ChainRequest { target: target.clone() }));
+        try!(self.writer.flush());
+        match try!(self.reader.read_u8()) {
+            0 => return Ok(None),
+            1 => (),
+            2 => {
+                let failure: ChainError = try!(read_json(self.reader));
+                return Err(IoError {
+                    kind: OtherIoError,
+                    desc: "server couldn't read the chain",
+                    detail: Some(failure.error)
+                });
+            },
+            other => return Err(protocol_error(format!("unexpected response {}", other)))
+        }
+        let header: ChainHeader = try!(read_json(self.reader));
+        try!(relrw_io!(copy_out(self.reader, writer)));
+        Ok(Some(header))
+    }
+
+    pub fn delete_node(&mut self, request: &DeleteRequest) -> IoResult<DeleteReport> {
+        try!(self.send_command(DeleteNode));
+        try!(write_json(self.writer, request));
+        try!(self.writer.flush());
+        read_json(self.reader)
+    }
+
+    pub fn prune(&mut self, request: &PruneRequest) -> IoResult<DeleteReport> {
+        try!(self.send_command(Prune));
+        try!(write_json(self.writer, request));
+        try!(self.writer.flush());
+        read_json(self.reader)
+    }
+
+    /// Nodes matching `query`, oldest first.
+    pub fn query_nodes(&mut self, query: &NodeQuery) -> IoResult<Vec<NodeInfo>> {
+        try!(self.send_command(QueryNodes));
+        try!(write_json(self.writer, query));
+        try!(self.writer.flush());
+        read_json(self.reader)
+    }
+
+    /// Usage against quota for this namespace, and for `client` if given.
+    pub fn get_quota(&mut self, client: Option<&str>) -> IoResult<QuotaReport> {
+        try!(self.send_command(GetQuota));
+        try!(write_json(self.writer, &QuotaRequest {
+            client: client.map(|c| c.to_string())
+        }));
+        try!(self.writer.flush());
+        read_json(self.reader)
+    }
+
+    fn read_upload_result(&mut self) -> IoResult<UploadResult> {
+        match try!(self.reader.read_u8()) {
+            0 => Ok(UploadFailed),
+            1 => Ok(UploadCommitted(try!(read_uuid(self.reader)))),
+            2 => Ok(UploadDuplicate(try!(read_uuid(self.reader)))),
+            3 => Ok(UploadOverQuota(try!(read_json(self.reader)))),
+            other => Err(protocol_error(format!("unexpected response {}", other)))
+        }
+    }
+
+    pub fn upload_archive(&mut self, request: &MetadataUploadRequest,
+                          stream: &mut Reader) -> IoResult<UploadResult> {
+        try!(self.send_command(UploadArchiveWithMetadata));
+        try!(write_json(self.writer, request));
+        try!(relrw_io!(copy_in(stream, self.writer)));
+        try!(self.writer.flush());
+        self.read_upload_result()
+    }
+
+    pub fn upload_encrypted_archive(&mut self, request: &EncryptedUploadRequest,
+                                    stream: &mut Reader) -> IoResult<UploadResult> {
+        try!(self.send_command(UploadEncryptedArchive));
+        try!(write_json(self.writer, request));
+        try!(relrw_io!(copy_in(stream, self.writer)));
+        try!(self.writer.flush());
+        self.read_upload_result()
+    }
+
+    fn read_upload_session(&mut self) -> IoResult<Result<UploadSession, UploadResult>> {
+        match try!(self.reader.read_u8()) {
+            0 => Ok(Err(UploadFailed)),
+            1 => Ok(Ok(try!(read_json(self.reader)))),
+            3 => Ok(Err(UploadOverQuota(try!(read_json(self.reader))))),
+            other => Err(protocol_error(format!("unexpected response {}", other)))
+        }
+    }
+
+    /// Starts an upload that can be resumed.  Keep the session to hand
+    /// to `resume_upload` should the connection drop in `send_upload`.
+    pub fn begin_upload(&mut self, request: &MetadataUploadRequest)
+                        -> IoResult<Result<UploadSession, UploadResult>> {
+        try!(self.send_command(BeginUpload));
+        try!(write_json(self.writer, request));
+        try!(self.writer.flush());
+        self.read_upload_session()
+    }
+
+    /// Picks up an upload where an earlier connection left it.  A server
+    /// that no longer has the upload answers `UploadFailed`.
+    pub fn resume_upload(&mut self, session: &Uuid)
+                         -> IoResult<Result<UploadSession, UploadResult>> {
+        try!(self.send_command(ResumeUpload));
+        try!(write_json(self.writer, &ResumeRequest { session: session.clone() }));
+        try!(self.writer.flush());
+        self.read_upload_session()
+    }
+
+    /// Finishes an upload with `stream`, read from its start.  The part
+    /// the server already has is skipped but still hashed, so the digest
+    /// the server checks covers the whole stream.
+    pub fn send_upload(&mut self, session: &UploadSession,
+                       stream: &mut Reader) -> IoResult<UploadResult> {
+        let digest = {
+            let mut reader = HashingReader::new(stream);
+            let mut buf = [0u8, ..64 * 1024];
+            let mut left = session.offset;
+            while left > 0 {
+                let want = min(left, buf.len() as u64) as uint;
+                left -= try!(reader.read(buf.slice_to_mut(want))) as u64;
+            }
+            try!(relrw_io!(copy_in(&mut reader, self.writer)));
+            reader.digest()
+        };
+        try!(write_json(self.writer, &UploadDigest { sha256: digest.as_slice().to_hex() }));
+        try!(self.writer.flush());
+        self.read_upload_result()
+    }
+}
+
+
+#[test]
+fn test_common_version() {
+    let hello = |min_version: u32, max_version: u32| ClientHello {
+        min_version: min_version,
+        max_version: max_version,
+        capabilities: Vec::new(),
+        namespace: None
+    };
+    assert_eq!(common_version(&hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)),
+        Some(PROTOCOL_VERSION));
+    assert_eq!(common_version(&hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 5)),
+        Some(PROTOCOL_VERSION));
+    assert_eq!(common_version(&hello(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 5)), None);
+    assert_eq!(common_version(&hello(0, MIN_PROTOCOL_VERSION - 1)), None);
+}
+
+
+#[test]
+fn test_allowed_for() {
+    assert!(DownloadChain.allowed_for(&ReadRole));
+    assert!(!UploadArchive.allowed_for(&ReadRole));
+    assert!(UploadArchiveWithMetadata.allowed_for(&UploadRole));
+    assert!(!DownloadArchive.allowed_for(&UploadRole));
+    assert!(!Prune.allowed_for(&UploadRole));
+    assert!(!DeleteNode.allowed_for(&ReadRole));
+    assert!(DeleteNode.allowed_for(&AdminRole));
 }
@@ -0,0 +1,219 @@
+use std::collections::{HashSet, HashMap};
+
+use uuid::Uuid;
+
+use repository::{BackupNode, FullBackup};
+use metadata::ObjectMetadata;
+
+#[cfg(test)]
+use repository::{test_node, test_uuid};
+
+
+/// Which nodes to return.  Every field is optional and they all have to
+/// hold; an empty query matches everything.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct NodeQuery {
+    // Glob against the snapshot name in the stream: `*` and `?`
+    pub name: Option<String>,
+    pub min_ctransid: Option<u64>,
+    pub max_ctransid: Option<u64>,
+    // Seconds since the epoch, inclusive
+    pub uploaded_after: Option<i64>,
+    pub uploaded_before: Option<i64>,
+    // Only nodes sent as an incremental from this snapshot
+    pub children_of: Option<Uuid>,
+    // Drop matches that another match builds on, leaving the newest of
+    // each chain
+    pub latest_in_chain: Option<bool>
+}
+
+
+/// A node as reported to clients.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct NodeInfo {
+    pub uuid: Uuid,
+    pub parent: Option<Uuid>,
+    pub name: String,
+    // "full" or "incremental"
+    pub kind: String,
+    pub ctransid: u64,
+    pub size: u64,
+    pub stored_size: u64,
+    pub encrypted: bool,
+    pub tier: String,
+    pub uploaded_at: i64,
+    pub metadata: Option<ObjectMetadata>
+}
+
+
+impl NodeQuery {
+    pub fn new() -> NodeQuery {
+        NodeQuery {
+            name: None,
+            min_ctransid: None,
+            max_ctransid: None,
+            uploaded_after: None,
+            uploaded_before: None,
+            children_of: None,
+            latest_in_chain: None
+        }
+    }
+
+    /// Whether the node passes the filters that look at it alone.
+    pub fn matches(&self, node: &BackupNode) -> bool {
+        match self.name {
+            Some(ref pattern) if !glob_match(pattern.as_bytes(), node.name.as_slice()) => {
+                return false;
+            },
+            _ => ()
+        }
+        match self.min_ctransid {
+            Some(min) if node.ctransid() < min => return false,
+            _ => ()
+        }
+        match self.max_ctransid {
+            Some(max) if node.ctransid() > max => return false,
+            _ => ()
+        }
+        match self.children_of {
+            Some(ref parent) if node.parent_uuid.as_ref() != Some(parent) => return false,
+            _ => ()
+        }
+        if self.uploaded_after.is_some() || self.uploaded_before.is_some() {
+            let uploaded_at = node.uploaded_at();
+            match self.uploaded_after {
+                Some(after) if uploaded_at < after => return false,
+                _ => ()
+            }
+            match self.uploaded_before {
+                Some(before) if uploaded_at > before => return false,
+                _ => ()
+            }
+        }
+        true
+    }
+
+    /// Runs the query over `nodes`.  A snapshot stored more than once is
+    /// returned once.
+    pub fn run<'a>(&self, nodes: &'a [BackupNode]) -> Vec<&'a BackupNode> {
+        let mut seen: HashSet<Uuid> = HashSet::new();
+        let mut out: Vec<&'a BackupNode> = nodes.iter()
+            .filter(|n| self.matches(*n))
+            .filter(|n| seen.insert(n.uuid.clone()))
+            .collect();
+
+        if self.latest_in_chain == Some(true) {
+            let parents: HashMap<Uuid, Uuid> = nodes.iter()
+                .filter_map(|n| n.parent_uuid.as_ref().map(|p| (n.uuid.clone(), p.clone())))
+                .collect();
+            // Everything some match descends from
+            let mut ancestors: HashSet<Uuid> = HashSet::new();
+            for node in out.iter() {
+                let mut cursor = node.parent_uuid.clone();
+                loop {
+                    let uuid = match cursor {
+                        Some(uuid) => uuid,
+                        None => break
+                    };
+                    if !ancestors.insert(uuid.clone()) {
+                        break;
+                    }
+                    cursor = parents.find(&uuid).map(|p| p.clone());
+                }
+            }
+            out.retain(|n| !ancestors.contains(&n.uuid));
+        }
+        out.sort_by(|a, b| a.ctransid().cmp(&b.ctransid()));
+        out
+    }
+}
+
+
+impl NodeInfo {
+    pub fn from_node(node: &BackupNode) -> NodeInfo {
+        NodeInfo {
+            uuid: node.uuid.clone(),
+            parent: node.parent_uuid.clone(),
+            name: String::from_utf8_lossy(node.name.as_slice()).into_string(),
+            kind: match node.kind {
+                FullBackup(_) => "full".to_string(),
+                _ => "incremental".to_string()
+            },
+            ctransid: node.ctransid(),
+            size: node.size,
+            stored_size: node.stored_size,
+            encrypted: node.encrypted,
+            tier: node.tier.name().to_string(),
+            uploaded_at: node.uploaded_at(),
+            metadata: node.metadata.clone()
+        }
+    }
+}
+
+
+/// Shell-style matching of `*` (any run of bytes) and `?` (any one byte).
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    // Where to resume after the last `*`: its position in the pattern
+    // and how much of the name it has taken
+    let mut star: Option<(uint, uint)> = None;
+    let (mut p, mut n) = (0u, 0u);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else {
+            match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match(b"root_jessie*", b"root_jessie_2014-08-25"));
+    assert!(glob_match(b"*2014-0?-25", b"root_jessie_2014-08-25"));
+    assert!(glob_match(b"*", b""));
+    assert!(glob_match(b"a*b*c", b"aXbYbc"));
+    assert!(!glob_match(b"root_jessie*", b"home_2014-08-25"));
+    assert!(!glob_match(b"a*b", b"aXbY"));
+    assert!(!glob_match(b"?", b""));
+}
+
+
+#[cfg(test)]
+fn named_node(uuid: u8, parent: Option<u8>, name: &str) -> BackupNode {
+    BackupNode { name: name.as_bytes().to_vec(), ..test_node(uuid, parent) }
+}
+
+
+#[test]
+fn test_query_latest_in_chain() {
+    let nodes = vec![
+        named_node(1, None, "root_1"),
+        named_node(2, Some(1), "root_2"),
+        named_node(3, Some(2), "root_3"),
+        named_node(4, Some(3), "root_4"),
+        named_node(5, None, "home_5"),
+    ];
+    let mut query = NodeQuery::new();
+    query.name = Some("root_*".to_string());
+    query.max_ctransid = Some(3);
+    query.latest_in_chain = Some(true);
+    let found: Vec<Uuid> = query.run(nodes.as_slice()).iter().map(|n| n.uuid.clone()).collect();
+    assert_eq!(found, vec![test_uuid(3)]);
+
+    let mut children = NodeQuery::new();
+    children.children_of = Some(test_uuid(2));
+    assert_eq!(children.run(nodes.as_slice()).len(), 1);
+}
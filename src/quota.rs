@@ -0,0 +1,165 @@
+use std::io::{IoResult, IoError, OtherIoError};
+
+use repository::BackupNode;
+
+
+// Quotas count the bytes clients send, before compression or dedup, so
+// a client can tell what it may upload without knowing how we store it.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_objects: Option<u64>
+}
+
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Usage {
+    pub bytes: u64,
+    pub objects: u64
+}
+
+
+impl Usage {
+    pub fn new() -> Usage {
+        Usage { bytes: 0, objects: 0 }
+    }
+
+    pub fn of<'a, I: Iterator<&'a BackupNode>>(mut nodes: I) -> Usage {
+        let mut usage = Usage::new();
+        for node in nodes {
+            usage.add(node.size);
+        }
+        usage
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.objects += 1;
+    }
+}
+
+
+/// Why an upload was refused, as sent to the client.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct QuotaRejection {
+    // "namespace", or "client" for a per-client quota
+    pub scope: String,
+    // "bytes" or "objects"
+    pub limit_kind: String,
+    pub limit: u64,
+    pub used: u64
+}
+
+
+/// What an admitted upload may still store before it hits a byte limit.
+#[deriving(Clone, Show)]
+pub struct Allowance {
+    pub bytes: u64,
+    // The limit it would hit, as it stood when the upload was admitted
+    pub rejection: QuotaRejection
+}
+
+
+impl Quota {
+    /// Whether another object may be stored.  On success, how many more
+    /// bytes it may have, if that is limited.
+    pub fn admit(&self, scope: &str, usage: &Usage) -> Result<Option<Allowance>, QuotaRejection> {
+        match self.max_objects {
+            Some(limit) if usage.objects >= limit => return Err(QuotaRejection {
+                scope: scope.to_string(),
+                limit_kind: "objects".to_string(),
+                limit: limit,
+                used: usage.objects
+            }),
+            _ => ()
+        }
+        let rejection = |limit: u64| QuotaRejection {
+            scope: scope.to_string(),
+            limit_kind: "bytes".to_string(),
+            limit: limit,
+            used: usage.bytes
+        };
+        match self.max_bytes {
+            Some(limit) if usage.bytes >= limit => Err(rejection(limit)),
+            Some(limit) => Ok(Some(Allowance {
+                bytes: limit - usage.bytes,
+                rejection: rejection(limit)
+            })),
+            None => Ok(None)
+        }
+    }
+}
+
+
+/// Fails writes once more bytes than the allowance have gone through.
+pub struct QuotaWriter<'a> {
+    inner: &'a mut Writer+'a,
+    allowance: Option<Allowance>,
+    written: u64,
+    exceeded: bool
+}
+
+
+impl<'a> QuotaWriter<'a> {
+    pub fn new<'a>(inner: &'a mut Writer, allowance: Option<Allowance>) -> QuotaWriter<'a> {
+        QuotaWriter {
+            inner: inner,
+            allowance: allowance,
+            written: 0,
+            exceeded: false
+        }
+    }
+
+    /// What to tell the client, if the limit was hit.
+    pub fn rejection(&self) -> Option<QuotaRejection> {
+        match self.allowance {
+            Some(ref allowance) if self.exceeded => {
+                let mut rejection = allowance.rejection.clone();
+                rejection.used += self.written;
+                Some(rejection)
+            },
+            _ => None
+        }
+    }
+}
+
+
+impl<'a> Writer for QuotaWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.written += buf.len() as u64;
+        match self.allowance {
+            Some(ref allowance) if self.written > allowance.bytes => {
+                self.exceeded = true;
+                return Err(IoError {
+                    kind: OtherIoError,
+                    desc: "quota exceeded",
+                    detail: Some(format!("{} bytes allowed", allowance.bytes))
+                });
+            },
+            _ => ()
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+
+#[test]
+fn test_quota_admit() {
+    let quota = Quota { max_bytes: Some(100), max_objects: Some(2) };
+    let mut usage = Usage::new();
+    assert_eq!(quota.admit("namespace", &usage).ok().unwrap().map(|a| a.bytes), Some(100));
+    usage.add(60);
+    let allowance = quota.admit("namespace", &usage).ok().unwrap().unwrap();
+    assert_eq!(allowance.bytes, 40);
+    assert_eq!(allowance.rejection.used, 60);
+    usage.add(10);
+    let rejection = quota.admit("namespace", &usage).err().unwrap();
+    assert_eq!(rejection.limit_kind.as_slice(), "objects");
+
+    let unlimited = Quota { max_bytes: None, max_objects: None };
+    assert!(unlimited.admit("client", &usage).ok().unwrap().is_none());
+}
@@ -1,79 +1,443 @@
-use std::io::{File, BufReader, BufferedReader, IoResult};
-use std::io::fs::readdir;
+use std::io::{File, BufReader, BufferedReader, IoResult, IoError, OtherIoError, EndOfFile};
+use std::io::fs::{readdir, unlink, rename, mkdir_recursive, chmod, stat};
+use std::io::util::{copy, NullWriter};
+use std::io::{USER_RWX, USER_READ, GROUP_READ, OTHER_READ};
 use std::slice::Items;
-use std::collections::HashSet;
+use std::collections::{HashSet, HashMap};
+use std::collections::hashmap::{Occupied, Vacant};
+use std::fmt;
+use std::mem;
 
+use time;
 use uuid::Uuid;
 
+use serialize::hex::ToHex;
+
 use btrfs::{
     get_first_command,
+    BtrfsParseError,
+    ProtocolError,
+    ReadError,
     BtrfsCommand,
     BtrfsSubvol,
     BtrfsSnapshot,
     BTRFS_SEND_C_SUBVOL,
     BTRFS_SEND_C_SNAPSHOT,
 };
+use planner::{plan_restore, RestorePlan};
+use pool::{map_jobs, default_jobs};
+use query::NodeQuery;
+use encryption::MetadataEnvelope;
+use metadata::{METADATA_SIDECAR, ENVELOPE_SIDECAR, ObjectMetadata, HashingWriter};
+use chunking::{CHUNKED_MAGIC, ChunkStore, RecipeEntry, read_recipe};
+use lock::{LOCK_FILENAME, RepositoryLock, LockMode};
+use format::{FORMAT_FILENAME, CURRENT_VERSION, check_version, write_version};
+use config::{CONFIG_FILENAME, RepositoryConfig};
+use quota::{Usage, Allowance, QuotaRejection};
+use object::{
+    open_object,
+    object_info,
+    sidecar_path,
+    ObjectEncoding,
+    PlainObject,
+    DeflateObject,
+    ChunkedObject,
+    PendingObject,
+    FinishedObject,
+    PartialUpload,
+};
 
 
+#[deriving(Clone)]
 pub enum BackupNodeKind {
     FullBackup(BtrfsSubvol),
     IncrementalBackup(BtrfsSnapshot)
 }
 
 
+// Where an object is stored.  Both tiers are indexed and read the same
+// way; the cold one is just slower.
+#[deriving(PartialEq, Clone, Show)]
+pub enum StorageTier {
+    HotTier,
+    ColdTier
+}
+
+
+impl StorageTier {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HotTier => "hot",
+            ColdTier => "cold"
+        }
+    }
+}
+
+
+#[deriving(Clone)]
 pub struct BackupNode {
+    // Logical size of the stream
     pub size: u64,
+    // Size on disk, after any compression
+    pub stored_size: u64,
+    pub encoding: ObjectEncoding,
+    // Stored as ciphertext; the header comes from the envelope
+    pub encrypted: bool,
     pub kind: BackupNodeKind,
     pub uuid: Uuid,
     pub parent_uuid: Option<Uuid>,
     pub path: Path,
-    pub name: Vec<u8>
+    pub name: Vec<u8>,
+    // From the `.meta` sidecar, for objects that have one
+    pub metadata: Option<ObjectMetadata>,
+    pub tier: StorageTier
 }
 
 
 impl BackupNode {
-    fn from_btrfs_command(path: &Path, size: u64, command: &BtrfsCommand) -> BackupNode {
+    pub fn from_btrfs_command(path: &Path, size: u64, command: &BtrfsCommand) -> Result<BackupNode, BtrfsParseError> {
         let mut reader = BufReader::new(command.data.as_slice());
         match command.kind {
             BTRFS_SEND_C_SUBVOL => {
-                let subvol = match BtrfsSubvol::parse(&mut reader) {
-                    Ok(subvol) => subvol,
-                    Err(err) => fail!("err: {}", err)
-                };
-                BackupNode {
+                let subvol = try!(BtrfsSubvol::parse(&mut reader));
+                Ok(BackupNode {
                     size: size,
+                    stored_size: size,
+                    encoding: PlainObject,
+                    encrypted: false,
                     kind: FullBackup(subvol.clone()),
                     uuid: subvol.uuid.clone(),
                     parent_uuid: None,
                     path: path.clone(),
                     name: subvol.name.clone(),
-                }
+                    metadata: None,
+                    tier: HotTier
+                })
             },
             BTRFS_SEND_C_SNAPSHOT => {
-                let snap = match BtrfsSnapshot::parse(&mut reader) {
-                    Ok(snap) => snap,
-                    Err(err) => fail!("err: {}", err)
-                };
-                BackupNode {
+                let snap = try!(BtrfsSnapshot::parse(&mut reader));
+                Ok(BackupNode {
                     size: size,
+                    stored_size: size,
+                    encoding: PlainObject,
+                    encrypted: false,
                     kind: IncrementalBackup(snap.clone()),
                     uuid: snap.uuid.clone(),
                     parent_uuid: Some(snap.clone_uuid.clone()),
                     path: path.clone(),
-                    name: snap.name.clone()
-                }
+                    name: snap.name.clone(),
+                    metadata: None,
+                    tier: HotTier
+                })
             },
-            _ => {
-                fail!("invalid command {}", command.kind);
+            _ => Err(ProtocolError(format!(
+                "stream starts with {} instead of SUBVOL or SNAPSHOT", command.kind)))
+        }
+    }
+
+    /// Seconds since the epoch.  Objects from before metadata sidecars
+    /// go by the file's modification time.
+    pub fn uploaded_at(&self) -> i64 {
+        match self.metadata {
+            Some(ref metadata) => metadata.uploaded_at,
+            None => match stat(&self.path) {
+                Ok(stat) => (stat.modified / 1000) as i64,
+                Err(_) => 0
             }
         }
     }
+
+    pub fn ctransid(&self) -> u64 {
+        match self.kind {
+            FullBackup(ref subv) => subv.ctransid,
+            IncrementalBackup(ref snap) => snap.ctransid
+        }
+    }
+
+    /// Whether both nodes agree on everything we know without reading
+    /// the object contents.
+    pub fn same_metadata(&self, other: &BackupNode) -> bool {
+        self.uuid == other.uuid &&
+            self.parent_uuid == other.parent_uuid &&
+            self.size == other.size &&
+            self.ctransid() == other.ctransid()
+    }
+}
+
+
+#[deriving(PartialEq, Show)]
+pub enum DuplicateKind {
+    // Every copy is byte-for-byte the same object
+    IdenticalCopies,
+    // Copies differ in parent, size, ctransid or contents
+    ConflictingCopies,
+    // Identical incrementals alongside identical full backups of the same
+    // generation, as left behind by synthesizing a full
+    AlternateCopies
+}
+
+
+pub struct DuplicateSet<'a> {
+    pub uuid: Uuid,
+    pub kind: DuplicateKind,
+    pub nodes: Vec<&'a BackupNode>
+}
+
+
+fn all_identical(nodes: &[&BackupNode]) -> IoResult<bool> {
+    for other in nodes.iter().skip(1) {
+        if !nodes[0].same_metadata(*other) ||
+                !try!(files_identical(&nodes[0].path, &other.path)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
 
+fn classify_duplicates(nodes: &[&BackupNode]) -> IoResult<DuplicateKind> {
+    let ctransid = nodes[0].ctransid();
+    if nodes.iter().any(|n| n.ctransid() != ctransid) {
+        return Ok(ConflictingCopies);
+    }
+    let fulls: Vec<&BackupNode> = nodes.iter()
+        .filter(|n| n.parent_uuid.is_none())
+        .map(|n| *n)
+        .collect();
+    let incrementals: Vec<&BackupNode> = nodes.iter()
+        .filter(|n| n.parent_uuid.is_some())
+        .map(|n| *n)
+        .collect();
+    if !try!(all_identical(fulls.as_slice())) ||
+            !try!(all_identical(incrementals.as_slice())) {
+        return Ok(ConflictingCopies);
+    }
+    if fulls.len() > 0 && incrementals.len() > 0 {
+        Ok(AlternateCopies)
+    } else {
+        Ok(IdenticalCopies)
+    }
+}
+
+
+// Classifies a group on a worker task, which needs it owned.
+fn classify_group(nodes: Vec<BackupNode>) -> IoResult<DuplicateKind> {
+    let refs: Vec<&BackupNode> = nodes.iter().collect();
+    classify_duplicates(refs.as_slice())
+}
+
+
+// Reads an object's stream back and compares it with the content hash
+// taken when it was stored.  On a worker task, so everything is owned.
+fn check_content_at(job: (Path, String)) -> (Path, IoResult<bool>) {
+    let (path, expected) = job;
+    let result = open_object(&path).and_then(|reader| {
+        let mut reader = BufferedReader::new(reader);
+        let mut sink = NullWriter;
+        let mut writer = HashingWriter::new(&mut sink);
+        try!(copy(&mut reader, &mut writer));
+        Ok(writer.digest().as_slice().to_hex() == expected)
+    });
+    (path, result)
+}
+
+
+fn read_block(reader: &mut Reader, buf: &mut [u8]) -> IoResult<uint> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(buf[mut filled..]) {
+            Ok(len) => filled += len,
+            Err(ref err) if err.kind == EndOfFile => break,
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(filled)
+}
+
+
+// Compares the streams, not the files: the same stream may be stored
+// with different encodings.
+fn files_identical(left: &Path, right: &Path) -> IoResult<bool> {
+    let mut left_reader = BufferedReader::new(try!(open_object(left)));
+    let mut right_reader = BufferedReader::new(try!(open_object(right)));
+    let mut left_buf = [0u8, ..65536];
+    let mut right_buf = [0u8, ..65536];
+    loop {
+        let left_len = try!(read_block(&mut left_reader, left_buf[mut]));
+        let right_len = try!(read_block(&mut right_reader, right_buf[mut]));
+        if left_buf[..left_len] != right_buf[..right_len] {
+            return Ok(false);
+        }
+        if left_len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+
+/// Why a file in the repository is missing from the index.
+#[deriving(Clone)]
+pub enum LoadProblem {
+    // Couldn't be read at all, e.g. for its permissions
+    UnreadableFile(IoError),
+    // Readable, but not an object we know how to index
+    UnrecognizedFile(String)
+}
+
+
+impl fmt::Show for LoadProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnreadableFile(ref err) => write!(f, "unreadable: {}", err),
+            UnrecognizedFile(ref reason) => write!(f, "unrecognized: {}", reason)
+        }
+    }
+}
+
+
+#[deriving(Clone)]
+pub struct LoadDiagnostic {
+    pub path: Path,
+    pub problem: LoadProblem
+}
+
+
+impl fmt::Show for LoadDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.problem)
+    }
+}
+
+
+#[deriving(Clone)]
 pub struct Repository {
     root: Path,
-    pub nodes: Vec<BackupNode>
+    encoding: ObjectEncoding,
+    pub config: RepositoryConfig,
+    pub nodes: Vec<BackupNode>,
+    // Files passed over while loading, and why
+    pub diagnostics: Vec<LoadDiagnostic>,
+    // Objects `load_from` set aside because no full backup leads to them
+    pub orphans: Vec<BackupNode>,
+    // Whether orphans are kept out of `nodes` as changes come in too
+    set_aside: bool,
+    // How many objects to read at once when loading and checking
+    jobs: uint
+}
+
+
+// Objects are bare UUIDs; anything with an extension is a sidecar or an
+// upload in progress, and the rest belongs to the repository itself.
+fn is_object_path(path: &Path) -> bool {
+    match path.filename_str() {
+        Some(name) if name == FORMAT_FILENAME || name == LOCK_FILENAME => false,
+        _ => !path.is_dir() && path.extension().is_none()
+    }
+}
+
+
+fn read_node_at(path: Path) -> (Path, Result<BackupNode, LoadProblem>) {
+    let result = Repository::read_node(&path);
+    (path, result)
+}
+
+
+fn read_recipe_at(path: Path) -> IoResult<Vec<RecipeEntry>> {
+    let mut reader = BufferedReader::new(try!(File::open(&path)));
+    try!(reader.read_exact(CHUNKED_MAGIC.len()));
+    read_recipe(&mut reader)
+}
+
+
+// The recipe in `path` if it holds one, whether or not it could be indexed
+fn sniff_recipe_at(path: Path) -> IoResult<Option<Vec<RecipeEntry>>> {
+    let mut reader = BufferedReader::new(try!(File::open(&path)));
+    match reader.read_exact(CHUNKED_MAGIC.len()) {
+        Ok(ref magic) if magic.as_slice() == CHUNKED_MAGIC => (),
+        Ok(_) => return Ok(None),
+        Err(ref err) if err.kind == EndOfFile => return Ok(None),
+        Err(err) => return Err(err)
+    }
+    read_recipe(&mut reader).map(|recipe| Some(recipe))
+}
+
+
+pub struct CompressionStats {
+    pub size: u64,
+    pub stored_size: u64
+}
+
+
+pub struct DedupStats {
+    // Bytes of stream held in chunked objects
+    pub size: u64,
+    // Bytes of distinct chunks those objects reference
+    pub unique_size: u64
+}
+
+
+impl DedupStats {
+    pub fn saved(&self) -> u64 {
+        self.size - self.unique_size
+    }
+}
+
+
+impl CompressionStats {
+    pub fn ratio(&self) -> f64 {
+        if self.stored_size == 0 {
+            return 1.0;
+        }
+        self.size as f64 / self.stored_size as f64
+    }
+}
+
+
+fn repository_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail
+    }
+}
+
+
+fn copy_synced(from: &Path, to: &Path) -> IoResult<()> {
+    let mut reader = try!(File::open(from));
+    let mut writer = try!(File::create(to));
+    try!(copy(&mut reader, &mut writer));
+    writer.fsync()
+}
+
+
+fn min_allowance(left: Option<Allowance>, right: Option<Allowance>) -> Option<Allowance> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(if right.bytes < left.bytes { right } else { left }),
+        (None, right) => right,
+        (left, None) => left
+    }
+}
+
+
+// Each namespace is a complete repository of its own under
+// `<root>/ns/<name>`.  The root itself is the default namespace, which
+// is what every repository was before namespaces existed.
+pub static NAMESPACE_DIRECTORY: &'static str = "ns";
+
+static MAX_NAMESPACE_LEN: uint = 64;
+
+// A client that hasn't resumed an upload in a week isn't coming back
+static PARTIAL_EXPIRY_SECS: i64 = 7 * 24 * 60 * 60;
+
+
+/// Namespace names become directory names, so keep them boring: ASCII
+/// letters, digits, `-` and `_` only.
+pub fn valid_namespace(name: &str) -> bool {
+    name.len() > 0 && name.len() <= MAX_NAMESPACE_LEN
+        && name.bytes().all(|b| match b {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' => true,
+            _ => false
+        })
 }
 
 
@@ -102,10 +466,70 @@ impl Repository {
     pub fn new(path: &Path) -> Repository {
         Repository {
             root: path.clone(),
-            nodes: Vec::new()
+            encoding: DeflateObject,
+            config: RepositoryConfig::new(),
+            nodes: Vec::new(),
+            diagnostics: Vec::new(),
+            orphans: Vec::new(),
+            set_aside: false,
+            jobs: default_jobs()
+        }
+    }
+
+    /// The directory holding namespace `name`, or the root for the
+    /// default namespace.  `None` if the name isn't valid.
+    pub fn namespace_root(root: &Path, name: Option<&str>) -> Option<Path> {
+        match name {
+            None => Some(root.clone()),
+            Some(name) if valid_namespace(name) => {
+                Some(root.join(NAMESPACE_DIRECTORY).join(name))
+            },
+            Some(_) => None
         }
     }
 
+    /// Like `namespace_root`, creating the namespace if it doesn't exist.
+    /// New namespaces of an append-only repository are append-only too.
+    pub fn create_namespace(root: &Path, name: Option<&str>) -> IoResult<Option<Path>> {
+        match Repository::namespace_root(root, name) {
+            Some(ns_root) => {
+                if !ns_root.exists() {
+                    try!(mkdir_recursive(&ns_root, USER_RWX));
+                    try!(write_version(&ns_root, CURRENT_VERSION));
+                    let root_config = try!(RepositoryConfig::load(root));
+                    if root_config.is_append_only() {
+                        let mut config = RepositoryConfig::new();
+                        config.append_only = root_config.append_only;
+                        config.retention_days = root_config.retention_days;
+                        try!(config.save(&ns_root));
+                    }
+                }
+                Ok(Some(ns_root))
+            },
+            None => Ok(None)
+        }
+    }
+
+    pub fn list_namespaces(root: &Path) -> IoResult<Vec<String>> {
+        let ns_dir = root.join(NAMESPACE_DIRECTORY);
+        if !ns_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut out = Vec::new();
+        for path in try!(readdir(&ns_dir)).iter() {
+            match path.filename_str() {
+                Some(name) if path.is_dir() && valid_namespace(name) => {
+                    out.push(name.to_string());
+                },
+                _ => ()
+            }
+        }
+        out.sort();
+        Ok(out)
+    }
+
+    /// Callers should hold at least a shared lock (see `lock`) while
+    /// loading, so maintenance can't remove objects mid-scan.
     pub fn load_from(path: &Path) -> IoResult<Repository> {
         Repository::new(path).load(true)
     }
@@ -114,35 +538,510 @@ impl Repository {
         Repository::new(path).load(false)
     }
 
+    /// As `load_from_nofsck`, reading up to `jobs` objects at once.
+    pub fn load_from_nofsck_jobs(path: &Path, jobs: uint) -> IoResult<Repository> {
+        let mut repo = Repository::new(path);
+        repo.set_jobs(jobs);
+        repo.load(false)
+    }
+
+    /// Reads the index from disk again, for when another process may
+    /// have changed it.
+    pub fn reload(&mut self) -> IoResult<()> {
+        let mut fresh = Repository::new(&self.root);
+        fresh.encoding = self.encoding.clone();
+        fresh.jobs = self.jobs;
+        *self = try!(fresh.load(true));
+        Ok(())
+    }
+
     fn load(mut self, fsck: bool) -> IoResult<Repository> {
-        let paths = try!(readdir(&self.root));
-        for path in paths.iter() {
-            match File::open(path) {
-                Ok(mut file) => {
-                    let size = try!(file.stat()).size;
-                    let mut file = BufferedReader::new(file);
-                    let command = match get_first_command(&mut file) {
-                        Ok(command) => command,
-                        Err(_) => continue  // TODO: skip, I guess~  Maybe warn?
-                    };
-                    let node = BackupNode::from_btrfs_command(
-                        path, size, &command);
+        try!(check_version(&self.root));
+        self.config = try!(RepositoryConfig::load(&self.root));
+
+        let root = self.root.clone();
+        try!(self.scan(&root, HotTier));
+        match self.cold_root() {
+            Some(ref cold_root) if cold_root.exists() => try!(self.scan(cold_root, ColdTier)),
+            _ => ()
+        }
+
+        self.set_aside = fsck;
+        if fsck {
+            self.set_aside_orphans();
+        }
+
+        Ok(self)
+    }
+
+    // Moves the objects no full backup leads to out of `nodes`, and any
+    // that have since become reachable back in.
+    fn set_aside_orphans(&mut self) {
+        let mut all = mem::replace(&mut self.nodes, Vec::new());
+        all.extend(mem::replace(&mut self.orphans, Vec::new()).into_iter());
+        self.nodes = all;
+        let orphans = self.find_orphans();
+        for node in mem::replace(&mut self.nodes, Vec::new()).into_iter() {
+            if orphans.contains(&node.uuid) {
+                self.orphans.push(node);
+            } else {
+                self.nodes.push(node);
+            }
+        }
+    }
+
+    fn scan(&mut self, dir: &Path, tier: StorageTier) -> IoResult<()> {
+        let paths: Vec<Path> = try!(readdir(dir)).into_iter()
+            .filter(|path| is_object_path(path))
+            .collect();
+        for (path, result) in map_jobs(paths, self.jobs, read_node_at).into_iter() {
+            match result {
+                Ok(mut node) => {
+                    node.tier = tier.clone();
                     self.nodes.push(node);
                 },
-                Err(_) => {
-                    // TODO: skip, I guess~  Maybe warn?
+                Err(problem) => self.diagnostics.push(LoadDiagnostic {
+                    path: path,
+                    problem: problem
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the header of a single object.
+    pub fn read_node(path: &Path) -> Result<BackupNode, LoadProblem> {
+        let info = match object_info(path) {
+            Ok(info) => info,
+            Err(err) => return Err(UnreadableFile(err))
+        };
+
+        // Encrypted objects can't be parsed; their header is alongside
+        let envelope_path = sidecar_path(path, ENVELOPE_SIDECAR);
+        let mut node = if envelope_path.exists() {
+            match MetadataEnvelope::load(&envelope_path) {
+                Ok(envelope) => envelope.to_node(path, info.stored_size),
+                Err(err) => return Err(UnrecognizedFile(format!("bad envelope: {}", err)))
+            }
+        } else {
+            let mut reader = match open_object(path) {
+                Ok(reader) => BufferedReader::new(reader),
+                Err(err) => return Err(UnreadableFile(err))
+            };
+            let parsed = get_first_command(&mut reader).and_then(|command| {
+                BackupNode::from_btrfs_command(path, info.size, &command)
+            });
+            match parsed {
+                Ok(mut node) => {
+                    node.stored_size = info.stored_size;
+                    node.encoding = info.encoding;
+                    node
+                },
+                Err(ReadError(ref err)) if err.kind != EndOfFile => return Err(UnreadableFile(err.clone())),
+                Err(err) => return Err(UnrecognizedFile(format!("{}", err)))
+            }
+        };
+
+        // A damaged sidecar loses the metadata, not the object
+        let metadata_path = sidecar_path(path, METADATA_SIDECAR);
+        if metadata_path.exists() {
+            node.metadata = ObjectMetadata::load(&metadata_path).ok();
+        }
+        Ok(node)
+    }
+
+    /// Sets how many objects are read at once; 0 means the default.
+    pub fn set_jobs(&mut self, jobs: uint) {
+        self.jobs = if jobs > 0 { jobs } else { default_jobs() };
+    }
+
+    /// Sets how objects created from now on are stored.  Existing
+    /// objects are read back whatever their encoding.
+    pub fn set_encoding(&mut self, encoding: ObjectEncoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn get_encoding(&self) -> ObjectEncoding {
+        self.encoding.clone()
+    }
+
+    pub fn create_object(&self) -> IoResult<PendingObject> {
+        PendingObject::create(&self.root, self.encoding.clone())
+    }
+
+    pub fn create_object_with(&self, encoding: ObjectEncoding) -> IoResult<PendingObject> {
+        PendingObject::create(&self.root, encoding)
+    }
+
+    pub fn create_partial(&self) -> IoResult<PartialUpload> {
+        PartialUpload::create(&self.root)
+    }
+
+    /// The upload `session` left unfinished, if it's still here.
+    pub fn find_partial(&self, session: &Uuid) -> Option<PartialUpload> {
+        PartialUpload::open(&self.root, session)
+    }
+
+    /// Makes the stream of a finished upload an object without copying
+    /// it.  Only for repositories storing objects as `PlainObject`.
+    pub fn adopt_partial(&self, partial: &PartialUpload) -> IoResult<FinishedObject> {
+        assert_eq!(self.encoding, PlainObject);
+        partial.into_object(&self.root)
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        let mut stats = CompressionStats { size: 0, stored_size: 0 };
+        // Chunked objects share their storage; see `dedup_stats`
+        for node in self.nodes.iter().filter(|n| n.encoding != ChunkedObject) {
+            stats.size += node.size;
+            stats.stored_size += node.stored_size;
+        }
+        stats
+    }
+
+    // The recipe of every chunked object, in node order
+    fn read_recipes(&self) -> Vec<IoResult<Vec<RecipeEntry>>> {
+        let paths: Vec<Path> = self.nodes.iter()
+            .filter(|n| n.encoding == ChunkedObject)
+            .map(|n| n.path.clone())
+            .collect();
+        map_jobs(paths, self.jobs, read_recipe_at)
+    }
+
+    pub fn dedup_stats(&self) -> IoResult<DedupStats> {
+        let mut stats = DedupStats { size: 0, unique_size: 0 };
+        let mut seen: HashSet<Vec<u8>> = HashSet::new();
+        for recipe in self.read_recipes().into_iter() {
+            for entry in try!(recipe).into_iter() {
+                stats.size += entry.len as u64;
+                if seen.insert(entry.hash) {
+                    stats.unique_size += entry.len as u64;
                 }
             }
         }
+        Ok(stats)
+    }
 
-        if fsck {
-            let orphans = self.find_orphans();
-            self.nodes = self.nodes.into_iter()
-                .filter(|n| !orphans.contains(&n.uuid))
-                .collect();
+    pub fn lock(&self, mode: LockMode) -> IoResult<RepositoryLock> {
+        RepositoryLock::acquire(&self.root, mode)
+    }
+
+    pub fn try_lock(&self, mode: LockMode) -> IoResult<Option<RepositoryLock>> {
+        RepositoryLock::try_acquire(&self.root, mode)
+    }
+
+    /// Deletes an object and anything stored alongside it.  Only safe
+    /// while holding the repository lock exclusively.
+    pub fn remove_object(&self, path: &Path) -> IoResult<()> {
+        for kind in [ENVELOPE_SIDECAR, METADATA_SIDECAR].iter() {
+            let sidecar = sidecar_path(path, *kind);
+            if sidecar.exists() {
+                try!(unlink(&sidecar));
+            }
         }
+        unlink(path)
+    }
 
-        Ok(self)
+    /// Makes an object, its sidecars and any chunks it is made of
+    /// read-only if the repository is append-only.
+    pub fn seal(&self, path: &Path) -> IoResult<()> {
+        if !self.config.is_append_only() {
+            return Ok(());
+        }
+        for kind in [ENVELOPE_SIDECAR, METADATA_SIDECAR].iter() {
+            let sidecar = sidecar_path(path, *kind);
+            if sidecar.exists() {
+                try!(chmod(&sidecar, USER_READ | GROUP_READ | OTHER_READ));
+            }
+        }
+        match try!(sniff_recipe_at(path.clone())) {
+            Some(entries) => {
+                let store = ChunkStore::new(&path.dir_path());
+                for entry in entries.iter() {
+                    try!(chmod(&store.chunk_path(entry.hash.as_slice()),
+                               USER_READ | GROUP_READ | OTHER_READ));
+                }
+            },
+            None => ()
+        }
+        chmod(path, USER_READ | GROUP_READ | OTHER_READ)
+    }
+
+    /// Seals every object and every chunk, for a repository just made
+    /// append-only.  Returns how many objects were sealed.
+    pub fn seal_objects(&self) -> IoResult<uint> {
+        for node in self.nodes.iter().chain(self.orphans.iter()) {
+            try!(self.seal(&node.path));
+        }
+        try!(ChunkStore::new(&self.root).seal_all());
+        Ok(self.nodes.len() + self.orphans.len())
+    }
+
+    /// Fails if the object may not be deleted yet: in an append-only
+    /// repository, until it is older than the retention period.
+    pub fn check_removable(&self, node: &BackupNode) -> IoResult<()> {
+        if !self.config.is_append_only() {
+            return Ok(());
+        }
+        let kept_until = node.uploaded_at() + self.config.retention_secs();
+        if time::get_time().sec < kept_until {
+            return Err(repository_error("object is under retention",
+                Some(format!("{} is kept until {}", node.path.display(),
+                    time::at_utc(time::Timespec::new(kept_until, 0)).rfc3339()))));
+        }
+        Ok(())
+    }
+
+    /// Deletes an object, subject to the retention period.
+    pub fn delete_node(&self, node: &BackupNode) -> IoResult<()> {
+        try!(self.check_removable(node));
+        self.remove_object(&node.path)
+    }
+
+    /// Deletes the temporary files of uploads that never finished.  Only
+    /// safe while holding the repository lock exclusively.
+    pub fn remove_stale_tmp(&self) -> IoResult<Vec<Path>> {
+        let mut dirs = vec![self.root.clone()];
+        match self.cold_root() {
+            Some(cold_root) => if cold_root.exists() { dirs.push(cold_root) },
+            None => ()
+        }
+        let mut removed = Vec::new();
+        for dir in dirs.iter() {
+            for path in try!(readdir(dir)).iter() {
+                if path.extension_str() == Some("tmp") {
+                    try!(unlink(path));
+                    removed.push(path.clone());
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Deletes uploads nobody has added to for `PARTIAL_EXPIRY_SECS`.
+    /// Only safe while holding the repository lock exclusively.
+    pub fn remove_stale_partials(&self) -> IoResult<Vec<Path>> {
+        let expired_before = time::get_time().sec - PARTIAL_EXPIRY_SECS;
+        let mut removed = Vec::new();
+        for partial in try!(PartialUpload::list(&self.root)).into_iter() {
+            if try!(partial.modified()) < expired_before {
+                let path = partial.path().clone();
+                try!(partial.remove());
+                removed.push(path);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Reads a newly committed object into the index.  One that can't
+    /// be indexed is noted in `diagnostics`, as at load.
+    pub fn add_object(&mut self, path: &Path) -> IoResult<()> {
+        try!(self.seal(path));
+        self.refresh_path(path);
+        Ok(())
+    }
+
+    /// Brings the index up to date with a file that changed on disk: an
+    /// object that appeared or went away, or the sidecar of one.  Returns
+    /// the problem if the object can no longer be indexed.
+    pub fn refresh_path(&mut self, path: &Path) -> Option<&LoadDiagnostic> {
+        if *path == self.root.join(CONFIG_FILENAME) {
+            // Saved by rename, so a config that doesn't parse was edited
+            // by hand; the last good one stays in force
+            match RepositoryConfig::load(&self.root) {
+                Ok(config) => self.config = config,
+                Err(_) => ()
+            }
+            return None;
+        }
+        let object_path = match path.extension_str() {
+            None => path.clone(),
+            Some("tmp") => return None,
+            Some(_) => match path.filestem() {
+                Some(stem) => path.with_filename(stem),
+                None => return None
+            }
+        };
+        if !is_object_path(&object_path) {
+            return None;
+        }
+        // Objects never change once committed; only a sidecar can make
+        // an indexed one worth reading again
+        let indexed = self.nodes.iter().chain(self.orphans.iter()).any(|n| n.path == object_path);
+        if indexed && &object_path == path && object_path.exists() {
+            return None;
+        }
+
+        self.nodes.retain(|n| n.path != object_path);
+        self.orphans.retain(|n| n.path != object_path);
+        self.diagnostics.retain(|d| d.path != object_path);
+        if !object_path.exists() {
+            // Whatever hung off it may be orphaned now
+            if self.set_aside {
+                self.set_aside_orphans();
+            }
+            return None;
+        }
+        let tier = match self.cold_root() {
+            Some(ref cold_root) if cold_root.is_ancestor_of(&object_path) => ColdTier,
+            _ => HotTier
+        };
+        match Repository::read_node(&object_path) {
+            Ok(mut node) => {
+                node.tier = tier;
+                self.nodes.push(node);
+                if self.set_aside {
+                    self.set_aside_orphans();
+                }
+                None
+            },
+            Err(problem) => {
+                self.diagnostics.push(LoadDiagnostic {
+                    path: object_path,
+                    problem: problem
+                });
+                self.diagnostics.last()
+            }
+        }
+    }
+
+    /// What the repository holds, counting orphans and the bytes of
+    /// unfinished uploads as well.
+    pub fn usage(&self) -> Usage {
+        let mut usage = Usage::of(self.nodes.iter().chain(self.orphans.iter()));
+        for partial in self.partials().iter() {
+            usage.bytes += partial.offset().unwrap_or(0);
+        }
+        usage
+    }
+
+    /// What `client` has stored, counted as `usage` counts.  Uploads
+    /// that named no client all count as one client, so leaving the
+    /// name out doesn't escape the per-client quota.
+    pub fn client_usage(&self, client: Option<&str>) -> Usage {
+        let mut usage = Usage::of(self.nodes.iter().chain(self.orphans.iter()).filter(|n| {
+            let uploader = n.metadata.as_ref().and_then(|m| m.client.as_ref());
+            uploader.map(|c| c.as_slice()) == client
+        }));
+        for partial in self.partials().iter() {
+            if partial.client().as_ref().map(|c| c.as_slice()) == client {
+                usage.bytes += partial.offset().unwrap_or(0);
+            }
+        }
+        usage
+    }
+
+    /// Every client `client_usage` has something for, in order; `None`
+    /// stands for the uploads that named no client.
+    pub fn clients(&self) -> Vec<Option<String>> {
+        let mut clients: Vec<Option<String>> = self.nodes.iter().chain(self.orphans.iter())
+            .map(|n| n.metadata.as_ref().and_then(|m| m.client.clone()))
+            .chain(self.partials().iter().map(|p| p.client()))
+            .collect();
+        clients.sort();
+        clients.dedup();
+        clients
+    }
+
+    fn partials(&self) -> Vec<PartialUpload> {
+        PartialUpload::list(&self.root).unwrap_or(Vec::new())
+    }
+
+    /// Checks an upload by `client` against the configured quotas.  On
+    /// success, how much more it may store, if that is limited.
+    pub fn admit_upload(&self, client: Option<&str>) -> Result<Option<Allowance>, QuotaRejection> {
+        let mut allowance: Option<Allowance> = None;
+        match self.config.quota {
+            Some(ref quota) => {
+                allowance = min_allowance(allowance, try!(quota.admit("namespace", &self.usage())));
+            },
+            None => ()
+        }
+        match self.config.client_quota {
+            Some(ref quota) => {
+                let usage = self.client_usage(client);
+                allowance = min_allowance(allowance, try!(quota.admit("client", &usage)));
+            },
+            None => ()
+        }
+        Ok(allowance)
+    }
+
+    pub fn cold_root(&self) -> Option<Path> {
+        self.config.cold_root(&self.root)
+    }
+
+    /// Moves an object and its sidecars to the cold tier, returning its
+    /// new path.  The copy is complete before the original goes, so an
+    /// interruption leaves an identical duplicate rather than nothing.
+    /// Only safe while holding the repository lock exclusively.
+    pub fn move_to_cold(&self, node: &BackupNode) -> IoResult<Path> {
+        let cold_root = match self.cold_root() {
+            Some(cold_root) => cold_root,
+            None => return Err(repository_error("no cold tier configured", None))
+        };
+        if node.tier == ColdTier {
+            return Err(repository_error("already in the cold tier",
+                Some(format!("{}", node.path.display()))));
+        }
+        // Chunks are shared with hot objects and stay with them
+        if node.encoding == ChunkedObject {
+            return Err(repository_error("chunked objects can't be moved",
+                Some(format!("{}", node.path.display()))));
+        }
+        try!(mkdir_recursive(&cold_root, USER_RWX));
+
+        let filename = match node.path.filename() {
+            Some(filename) => filename.to_vec(),
+            None => unreachable!()
+        };
+        let cold_path = cold_root.join(filename.as_slice());
+        for kind in [ENVELOPE_SIDECAR, METADATA_SIDECAR].iter() {
+            let sidecar = sidecar_path(&node.path, *kind);
+            if sidecar.exists() {
+                try!(copy_synced(&sidecar, &sidecar_path(&cold_path, *kind)));
+            }
+        }
+        let tmp_path = cold_path.with_extension("tmp");
+        try!(copy_synced(&node.path, &tmp_path));
+        try!(rename(&tmp_path, &cold_path));
+        try!(self.seal(&cold_path));
+        try!(self.remove_object(&node.path));
+        Ok(cold_path)
+    }
+
+    /// Deletes chunks no object refers to.  Only safe while holding the
+    /// repository lock exclusively.  Every object file is read, not only
+    /// the indexed ones, so an object that failed to load keeps its
+    /// chunks until it is dealt with.  In an append-only repository,
+    /// chunks younger than the retention period are kept as well.
+    pub fn collect_chunks(&self) -> IoResult<Vec<Path>> {
+        let mut paths: Vec<Path> = try!(readdir(&self.root)).into_iter()
+            .filter(|path| is_object_path(path))
+            .collect();
+        match self.cold_root() {
+            Some(ref cold_root) if cold_root.exists() => {
+                paths.extend(try!(readdir(cold_root)).into_iter()
+                    .filter(|path| is_object_path(path)));
+            },
+            _ => ()
+        }
+
+        let mut referenced: HashSet<Vec<u8>> = HashSet::new();
+        for recipe in map_jobs(paths, self.jobs, sniff_recipe_at).into_iter() {
+            match try!(recipe) {
+                Some(entries) => for entry in entries.into_iter() {
+                    referenced.insert(entry.hash);
+                },
+                None => ()
+            }
+        }
+        let kept_since = if self.config.is_append_only() {
+            Some(time::get_time().sec - self.config.retention_secs())
+        } else {
+            None
+        };
+        ChunkStore::new(&self.root).collect_garbage(&referenced, kept_since)
     }
 
     pub fn iter_nodes<'a>(&'a self) -> Items<'a, BackupNode> {
@@ -153,6 +1052,75 @@ impl Repository {
         &self.root
     }
 
+    pub fn contains_uuid(&self, uuid: &Uuid) -> bool {
+        self.nodes.iter().any(|n| n.uuid == *uuid)
+    }
+
+    /// Whether any object stores `uuid`, counting the orphans `load_from`
+    /// set aside.  Another copy of an orphan is still a duplicate.
+    pub fn holds_uuid(&self, uuid: &Uuid) -> bool {
+        self.contains_uuid(uuid) || self.orphans.iter().any(|n| n.uuid == *uuid)
+    }
+
+    /// Reads every object whose metadata records a content hash and
+    /// returns those that no longer match it, or can't be read.
+    pub fn verify_contents(&self) -> Vec<(Path, IoResult<bool>)> {
+        let jobs: Vec<(Path, String)> = self.nodes.iter()
+            .filter_map(|n| match n.metadata {
+                Some(ref metadata) if metadata.content_hash.len() > 0 => {
+                    Some((n.path.clone(), metadata.content_hash.clone()))
+                },
+                _ => None
+            })
+            .collect();
+        map_jobs(jobs, self.jobs, check_content_at).into_iter()
+            .filter(|&(_, ref result)| match *result {
+                Ok(matched) => !matched,
+                Err(_) => true
+            })
+            .collect()
+    }
+
+    /// Finds every snapshot UUID claimed by more than one object, and
+    /// classifies each group.  Contents are only compared when the
+    /// metadata already agrees.
+    pub fn find_duplicates<'a>(&'a self) -> IoResult<Vec<DuplicateSet<'a>>> {
+        let mut by_uuid: HashMap<Uuid, Vec<&'a BackupNode>> = HashMap::new();
+        for node in self.nodes.iter() {
+            match by_uuid.entry(node.uuid.clone()) {
+                Vacant(entry) => entry.set(Vec::new()),
+                Occupied(entry) => entry.into_mut()
+            }.push(node);
+        }
+
+        let groups: Vec<(Uuid, Vec<&'a BackupNode>)> = by_uuid.into_iter()
+            .filter(|&(_, ref nodes)| nodes.len() > 1)
+            .collect();
+        // Comparing contents is the slow part, so groups go to the pool
+        let owned: Vec<Vec<BackupNode>> = groups.iter()
+            .map(|&(_, ref nodes)| nodes.iter().map(|n| (*n).clone()).collect())
+            .collect();
+        let kinds = map_jobs(owned, self.jobs, classify_group);
+
+        let mut out = Vec::new();
+        for ((uuid, nodes), kind) in groups.into_iter().zip(kinds.into_iter()) {
+            out.push(DuplicateSet {
+                uuid: uuid,
+                kind: try!(kind),
+                nodes: nodes
+            });
+        }
+        Ok(out)
+    }
+
+    pub fn plan_restore<'a>(&'a self, target: &Uuid) -> Option<RestorePlan<'a>> {
+        plan_restore(self.nodes.as_slice(), target)
+    }
+
+    pub fn query<'a>(&'a self, query: &NodeQuery) -> Vec<&'a BackupNode> {
+        query.run(self.nodes.as_slice())
+    }
+
     pub fn find_orphans(&self) -> HashSet<Uuid> {
         let mut root_reachable: HashSet<Uuid> = HashSet::new();
         let mut records: Vec<FsckReachabilityRecord> = Vec::new();
@@ -198,4 +1166,162 @@ impl Repository {
             .collect();
         out
     }
+
+    /// The nodes that removing `removed` would leave with no full backup
+    /// to restore from, leaving out those that have none already.
+    pub fn dependents_of<'a>(&'a self, removed: &[&BackupNode]) -> Vec<&'a BackupNode> {
+        let remaining: Vec<&'a BackupNode> = self.nodes.iter()
+            .filter(|n| !removed.iter().any(|r| r.path == n.path))
+            .collect();
+        let before = restorable_uuids(self.nodes.iter());
+        let after = restorable_uuids(remaining.iter().map(|n| *n));
+        let replayable = |reachable: &HashSet<Uuid>, node: &BackupNode| match node.parent_uuid {
+            Some(ref parent) => reachable.contains(parent),
+            None => true
+        };
+        remaining.into_iter()
+            .filter(|n| replayable(&before, *n) && !replayable(&after, *n))
+            .collect()
+    }
+}
+
+
+// Every snapshot that some chain of `nodes` restores from a full backup
+fn restorable_uuids<'a, I: Iterator<&'a BackupNode>>(nodes: I) -> HashSet<Uuid> {
+    let mut reachable: HashSet<Uuid> = HashSet::new();
+    let mut pending: Vec<(&'a Uuid, &'a Uuid)> = Vec::new();
+    for node in nodes {
+        match node.parent_uuid {
+            Some(ref parent) => pending.push((&node.uuid, parent)),
+            None => {
+                reachable.insert(node.uuid.clone());
+            }
+        }
+    }
+    loop {
+        let before = pending.len();
+        pending.retain(|&(uuid, parent)| {
+            if reachable.contains(parent) {
+                reachable.insert(uuid.clone());
+                false
+            } else {
+                true
+            }
+        });
+        if pending.len() == before {
+            return reachable;
+        }
+    }
+}
+
+
+#[test]
+fn test_namespace_root() {
+    let root = Path::new("/srv/backups");
+    assert_eq!(Repository::namespace_root(&root, None), Some(root.clone()));
+    assert_eq!(Repository::namespace_root(&root, Some("host-1_example")),
+        Some(Path::new("/srv/backups/ns/host-1_example")));
+    assert_eq!(Repository::namespace_root(&root, Some("")), None);
+    assert_eq!(Repository::namespace_root(&root, Some("..")), None);
+    assert_eq!(Repository::namespace_root(&root, Some("host.example")), None);
+    assert_eq!(Repository::namespace_root(&root, Some("h\u00f6st")), None);
+    assert_eq!(Repository::namespace_root(&root, Some("a/b")), None);
+}
+
+
+#[test]
+fn test_read_node_problems() {
+    use std::io::TempDir;
+
+    let dir = TempDir::new("repository").unwrap();
+    let junk = dir.path().join("00000000-0000-0000-0000-000000000000");
+    File::create(&junk).write(b"not a send stream").unwrap();
+    match Repository::read_node(&junk) {
+        Err(UnrecognizedFile(_)) => (),
+        other => fail!("expected an unrecognized file, got {}", other.err())
+    }
+    match Repository::read_node(&dir.path().join("missing")) {
+        Err(UnreadableFile(_)) => (),
+        other => fail!("expected an unreadable file, got {}", other.err())
+    }
+}
+
+
+#[test]
+fn test_refresh_path_reloads_config() {
+    use std::io::TempDir;
+
+    let dir = TempDir::new("repository").unwrap();
+    let mut repo = Repository::new(dir.path());
+    assert!(!repo.config.is_append_only());
+    let mut config = RepositoryConfig::new();
+    config.append_only = Some(true);
+    config.save(dir.path()).unwrap();
+    assert!(repo.refresh_path(&dir.path().join(CONFIG_FILENAME)).is_none());
+    assert!(repo.config.is_append_only());
+    assert!(repo.nodes.is_empty());
+}
+
+
+#[cfg(test)]
+pub fn test_uuid(idx: u8) -> Uuid {
+    Uuid::from_bytes(&[idx, ..16]).unwrap()
+}
+
+
+/// A node for tests elsewhere too: snapshot `uuid`, incremental on
+/// `parent` if there is one, named "snap" and 10 bytes long.
+#[cfg(test)]
+pub fn test_node(uuid: u8, parent: Option<u8>) -> BackupNode {
+    let kind = match parent {
+        Some(parent) => IncrementalBackup(BtrfsSnapshot {
+            name: b"snap".to_vec(),
+            uuid: test_uuid(uuid),
+            ctransid: uuid as u64,
+            clone_uuid: test_uuid(parent),
+            clone_ctransid: parent as u64
+        }),
+        None => FullBackup(BtrfsSubvol {
+            name: b"snap".to_vec(),
+            uuid: test_uuid(uuid),
+            ctransid: uuid as u64
+        })
+    };
+    BackupNode {
+        size: 10,
+        stored_size: 10,
+        encoding: PlainObject,
+        encrypted: false,
+        kind: kind,
+        uuid: test_uuid(uuid),
+        parent_uuid: parent.map(|p| test_uuid(p)),
+        path: Path::new(format!("/repo/{}-{}", uuid, parent)),
+        name: b"snap".to_vec(),
+        metadata: None,
+        tier: HotTier
+    }
+}
+
+
+#[test]
+fn test_dependents_of() {
+    let mut repo = Repository::new(&Path::new("/repo"));
+    repo.nodes = vec![
+        test_node(1, None),
+        test_node(2, Some(1)),
+        test_node(3, Some(2)),
+        // A synthesized full of 2 keeps 3 restorable without 1
+        test_node(2, None),
+        test_node(5, Some(4)),
+    ];
+    let dependents = |removed: &[uint]| -> Vec<Path> {
+        let removed: Vec<&BackupNode> = removed.iter().map(|idx| &repo.nodes[*idx]).collect();
+        repo.dependents_of(removed.as_slice()).iter().map(|n| n.path.clone()).collect()
+    };
+    // 2 is still restorable, but not by replaying the incremental
+    assert_eq!(dependents(&[0]), vec![repo.nodes[1].path.clone()]);
+    assert_eq!(dependents(&[0, 3]), vec![repo.nodes[1].path.clone(), repo.nodes[2].path.clone()]);
+    assert_eq!(dependents(&[2]), Vec::new());
+    // 5 had nothing to restore from anyway
+    assert_eq!(dependents(&[4]), Vec::new());
 }
@@ -1,51 +1,110 @@
 #![allow(dead_code)]
+#![feature(macro_rules)]
 #![feature(slicing_syntax)]
 
 extern crate serialize;
+extern crate libc;
 extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
 
 extern crate uuid;
 extern crate msgpack;
 
 extern crate reliable_rw;
+extern crate argparse;
 
 
-use std::os::{args_as_bytes, set_exit_status};
+use std::os;
 use std::io::fs::stat;
 use std::io::{FileStat, TypeDirectory, stdin, stdout};
-use repository::{Repository};
 use protocol::ProtocolServer as Protocol;
+use object::{PlainObject, DeflateObject, ChunkedObject};
+use argparse::{ArgumentParser, Store, StoreTrue};
 
 mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
 mod protocol;
+mod concat;
+mod daemon;
+mod inotify;
 mod btrfs;
 mod crc32;
 
 
-#[cfg(not(test))]
-fn print_usage(program: &[u8]) {
-    let mut stderr = std::io::stderr();
-    let mut out: Vec<u8> = Vec::new();
-    out.extend(b"USAGE: ".iter().map(|x| x.clone()));
-    out.extend(program.iter().map(|x| x.clone()));
-    out.extend(b" repository-directory\n".iter().map(|x| x.clone()));
-    assert!(stderr.write(out.as_slice()).is_ok());
+struct ProgramArgs {
+    respository_path: String,
+    namespace: String,
+    listen: String,
+    no_compress: bool,
+    dedup: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            namespace: "".to_string(),
+            listen: "".to_string(),
+            no_compress: false,
+            dedup: false
+        }
+    }
 }
 
 
 #[cfg(not(test))]
 fn main() {
-    let args_bytes = args_as_bytes();
-    let program_name = args_bytes[0].as_slice().clone();
+    let mut prog_args = ProgramArgs::new();
 
-    if args_bytes.len() < 2 {
-        print_usage(program_name);
-        set_exit_status(1);
-        return;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Serve a repository over stdin/stdout, or a unix socket");
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository")
+            .required();
+
+        ap.refer(&mut prog_args.namespace)
+            .add_option(["-N", "--namespace"], box Store::<String>,
+            "Only serve this namespace, whatever the client asks for");
+
+        ap.refer(&mut prog_args.listen)
+            .add_option(["--listen"], box Store::<String>,
+            "Stay running, serving clients of this unix socket from a repository kept in memory");
+
+        ap.refer(&mut prog_args.no_compress)
+            .add_option(["--no-compress"], box StoreTrue,
+            "Store uploaded objects uncompressed");
+
+        ap.refer(&mut prog_args.dedup)
+            .add_option(["--dedup"], box StoreTrue,
+            "Store uploaded objects as deduplicated chunks");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
     }
 
-    let repository_directory = args_bytes[1].as_slice().clone();
-    let path = Path::new(repository_directory);
+    let path = Path::new(prog_args.respository_path.as_slice());
 
     // Quick sanity check
     match stat(&path) {
@@ -54,15 +113,35 @@ fn main() {
         Err(e) => fail!("stat error: {}", e)
     }
 
-    let foo = match Repository::load_from(&path) {
-        Ok(repo) => repo,
-        Err(err) => fail!("Error while reading repository: {}", err)
+    let encoding = if prog_args.dedup {
+        ChunkedObject
+    } else if prog_args.no_compress {
+        PlainObject
+    } else {
+        DeflateObject
+    };
+    let pinned = if prog_args.namespace.len() > 0 {
+        Some(prog_args.namespace.as_slice())
+    } else {
+        None
     };
 
+    if prog_args.listen.len() > 0 {
+        let socket = Path::new(prog_args.listen.as_slice());
+        if socket.exists() {
+            fail!("{} already exists; remove it if no daemon is using it", socket.display());
+        }
+        match daemon::listen(&path, &socket, pinned.map(|p| p.to_string()), encoding) {
+            Ok(()) => (),
+            Err(err) => fail!("Error while listening on {}: {}", socket.display(), err)
+        }
+        return;
+    }
+
     let mut stdin = stdin();
     let mut stdout = stdout();
     let mut proto = Protocol::new(&mut stdin, &mut stdout);
-    match proto.run(&foo) {
+    match proto.run(&path, pinned, encoding) {
         Ok(_) => (),
         Err(err) => fail!("Error running protocol: {}", err)
     };
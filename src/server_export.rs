@@ -0,0 +1,265 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::io::fs::{stat, rename, unlink};
+use std::io::{File, BufferedReader, BufferedWriter, FileStat, TypeDirectory, stdout};
+use std::io::util::{copy, NullWriter};
+use lock::{RepositoryLock, SharedLock};
+use repository::Repository;
+use bundle::{BundleWriter, read_manifest, open_entry};
+use metadata::HashingWriter;
+use object::open_object;
+use serialize::hex::ToHex;
+use uuid::Uuid;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod bundle;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    respository_path: String,
+    target: String,
+    output: String,
+    unpack: String,
+    verbose: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            target: "".to_string(),
+            output: "".to_string(),
+            unpack: "".to_string(),
+            verbose: false
+        }
+    }
+}
+
+
+fn export(path: &Path, target: &Uuid, output: &Path, verbose: bool) {
+    // Quick sanity check
+    match stat(path) {
+        Ok(FileStat { kind: TypeDirectory, .. }) => (),  // Ok
+        Ok(stat) => fail!("repository is not a directory: {}", stat.kind),
+        Err(e) => fail!("stat error: {}", e)
+    }
+
+    let _lock = match RepositoryLock::acquire(path, SharedLock) {
+        Ok(lock) => lock,
+        Err(err) => fail!("Error while locking repository: {}", err)
+    };
+
+    // Without fsck, so an orphan is found and explained below
+    let repo = match Repository::load_from_nofsck(path) {
+        Ok(repo) => repo,
+        Err(err) => fail!("Error while reading repository: {}", err)
+    };
+
+    if !repo.contains_uuid(target) {
+        fail!("{} is not in the repository", target.to_hyphenated_string());
+    }
+    if repo.find_orphans().contains(target) {
+        fail!("{} can't be restored: its chain has no full backup",
+            target.to_hyphenated_string());
+    }
+    let plan = match repo.plan_restore(target) {
+        Some(plan) => plan,
+        None => fail!("{} can't be restored", target.to_hyphenated_string())
+    };
+    for node in plan.steps.iter() {
+        if node.encrypted {
+            fail!("{} is encrypted; bundles only hold plain streams", node.path.display());
+        }
+    }
+
+    let tmp_path = output.with_extension("tmp");
+    let file = match File::create(&tmp_path) {
+        Ok(file) => file,
+        Err(err) => fail!("Error while creating {}: {}", tmp_path.display(), err)
+    };
+    let mut bundle = BundleWriter::new(BufferedWriter::new(file));
+
+    for node in plan.steps.iter() {
+        let mut reader = match open_object(&node.path) {
+            Ok(reader) => BufferedReader::new(reader),
+            Err(err) => fail!("Error while opening {}: {}", node.path.display(), err)
+        };
+        let entry = match bundle.add(&node.uuid, node.parent_uuid.as_ref(),
+                                     node.metadata.as_ref(), &mut reader) {
+            Ok(entry) => entry,
+            Err(err) => {
+                let _ = unlink(&tmp_path);
+                fail!("Error while writing {}: {}", node.path.display(), err);
+            }
+        };
+        match node.metadata {
+            Some(ref metadata) if metadata.content_hash != entry.content_hash => {
+                let _ = unlink(&tmp_path);
+                fail!("{} is corrupt: content hash {}, expected {}",
+                    node.path.display(), entry.content_hash, metadata.content_hash);
+            },
+            _ => ()
+        }
+        if verbose {
+            println!("{} ({} bytes)", node.uuid.to_hyphenated_string(), entry.size);
+        }
+    }
+
+    let result = bundle.finish(target, time::get_time().sec).and_then(|(manifest, writer)| {
+        let mut file = writer.unwrap();
+        try!(file.fsync());
+        try!(rename(&tmp_path, output));
+        Ok(manifest)
+    });
+    match result {
+        Ok(manifest) => if verbose {
+            println!("wrote {} streams, {} bytes, to {}",
+                manifest.entries.len(), manifest.streams_size, output.display());
+        },
+        Err(err) => {
+            let _ = unlink(&tmp_path);
+            fail!("Error while writing bundle: {}", err);
+        }
+    }
+}
+
+
+// Writes the streams of a bundle to stdout, for `btrfs receive`.  Every
+// stream is checked against the manifest first, so nothing corrupt is
+// ever received.
+fn unpack(bundle_path: &Path) {
+    let mut file = match File::open(bundle_path) {
+        Ok(file) => file,
+        Err(err) => fail!("Error while opening {}: {}", bundle_path.display(), err)
+    };
+    let manifest = match read_manifest(&mut file) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => fail!("{} is not a bundle", bundle_path.display()),
+        Err(err) => fail!("Error while reading {}: {}", bundle_path.display(), err)
+    };
+    for entry in manifest.entries.iter() {
+        let digest = {
+            let mut sink = NullWriter;
+            let mut writer = HashingWriter::new(&mut sink);
+            let result = open_entry(&mut file, entry).and_then(|mut reader| {
+                copy(&mut reader, &mut writer)
+            });
+            match result {
+                Ok(()) => writer.digest(),
+                Err(err) => fail!("Error while reading {}: {}",
+                    entry.uuid.to_hyphenated_string(), err)
+            }
+        };
+        if digest.as_slice().to_hex() != entry.content_hash {
+            fail!("{} is corrupt in the bundle", entry.uuid.to_hyphenated_string());
+        }
+    }
+
+    let mut out = BufferedWriter::new(stdout());
+    for entry in manifest.entries.iter() {
+        let result = open_entry(&mut file, entry).and_then(|mut reader| {
+            copy(&mut reader, &mut out)
+        });
+        match result {
+            Ok(()) => (),
+            Err(err) => fail!("Error while writing {}: {}",
+                entry.uuid.to_hyphenated_string(), err)
+        }
+    }
+    match out.flush() {
+        Ok(()) => (),
+        Err(err) => fail!("Error while writing streams: {}", err)
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Write everything needed to restore a snapshot to a single ",
+            "bundle file, or unpack a bundle for btrfs receive"));
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository");
+
+        ap.refer(&mut prog_args.target)
+            .add_argument(
+                "uuid", box Store::<String>, "Snapshot to export");
+
+        ap.refer(&mut prog_args.output)
+            .add_option(["-o", "--output"], box Store::<String>,
+            "Bundle file to write");
+
+        ap.refer(&mut prog_args.unpack)
+            .add_option(["--unpack"], box Store::<String>,
+            "Write the streams of this bundle to stdout, checked, and exit");
+
+        ap.refer(&mut prog_args.verbose)
+            .add_option(["-v", "--verbose"], box StoreTrue, "Verbose");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    if prog_args.unpack.len() > 0 {
+        unpack(&Path::new(prog_args.unpack.as_slice()));
+        return;
+    }
+    if prog_args.respository_path.len() == 0 || prog_args.target.len() == 0
+            || prog_args.output.len() == 0 {
+        println!("repository, uuid and --output are required");
+        os::set_exit_status(2);
+        return;
+    }
+
+    let target = match Uuid::parse_str(prog_args.target.as_slice()).ok() {
+        Some(target) => target,
+        None => fail!("invalid uuid: {}", prog_args.target)
+    };
+    export(&Path::new(prog_args.respository_path.as_slice()), &target,
+        &Path::new(prog_args.output.as_slice()), prog_args.verbose);
+}
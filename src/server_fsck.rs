@@ -1,8 +1,13 @@
 #![allow(dead_code)]
+#![feature(macro_rules)]
 #![feature(slicing_syntax)]
 
 extern crate serialize;
+extern crate libc;
 extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
 
 extern crate uuid;
 extern crate msgpack;
@@ -11,17 +16,29 @@ extern crate reliable_rw;
 extern crate argparse;
 
 use std::os;
-use std::collections::HashMap;
-use std::collections::hashmap::{Occupied, Vacant};
 use std::os::set_exit_status;
 use std::io::fs::stat;
 use std::io::{FileStat, TypeDirectory};
-use repository::{Repository, BackupNode};
-use uuid::Uuid;
+use lock::{RepositoryLock, SharedLock, ExclusiveLock};
+use repository::{Repository, IdenticalCopies, ConflictingCopies, AlternateCopies};
 use argparse::{ArgumentParser, Store, StoreTrue};
 
 mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
 mod protocol;
+mod concat;
 mod btrfs;
 mod crc32;
 
@@ -30,6 +47,9 @@ mod crc32;
 struct ProgramArgs {
     respository_path: String,
     deep: bool,
+    repair: bool,
+    no_wait: bool,
+    jobs: uint,
     verbose: bool
 }
 
@@ -38,6 +58,9 @@ impl ProgramArgs {
         ProgramArgs {
             respository_path: "".to_string(),
             deep: false,
+            repair: false,
+            no_wait: false,
+            jobs: 0,
             verbose: false
         }
     }
@@ -58,7 +81,19 @@ fn main() {
 
     ap.refer(&mut prog_args.deep)
         .add_option(["-d", "--deep"], box StoreTrue,
-        "Deep scan");
+        "Also read every object back and check it against its content hash");
+
+    ap.refer(&mut prog_args.repair)
+        .add_option(["--repair"], box StoreTrue,
+        "Delete orphans, unfinished uploads and unreferenced chunks");
+
+    ap.refer(&mut prog_args.no_wait)
+        .add_option(["--no-wait"], box StoreTrue,
+        "Give up instead of waiting for other processes to release the repository");
+
+    ap.refer(&mut prog_args.jobs)
+        .add_option(["-j", "--jobs"], box Store::<uint>,
+        "How many objects to read at once (default: one per CPU, up to 16)");
 
     ap.refer(&mut prog_args.verbose)
         .add_option(["-v", "--verbose"], box StoreTrue, "Verbose");
@@ -80,38 +115,147 @@ fn main() {
         Err(e) => fail!("stat error: {}", e)
     }
 
-    let repo = match Repository::load_from_nofsck(&path) {
+    // Repairing deletes things, so it must wait out every upload in
+    // flight; an upload could be about to make an orphan's child.
+    let mode = if prog_args.repair { ExclusiveLock } else { SharedLock };
+    let _lock = if prog_args.no_wait {
+        match RepositoryLock::try_acquire(&path, mode) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                println!("repository is busy");
+                os::set_exit_status(1);
+                return;
+            },
+            Err(err) => fail!("Error while locking repository: {}", err)
+        }
+    } else {
+        match RepositoryLock::acquire(&path, mode) {
+            Ok(lock) => lock,
+            Err(err) => fail!("Error while locking repository: {}", err)
+        }
+    };
+
+    let mut repo = match Repository::load_from_nofsck_jobs(&path, prog_args.jobs) {
         Ok(repo) => repo,
         Err(err) => fail!("Error while reading repository: {}", err)
     };
 
     if prog_args.verbose {
         println!("Loaded repository with {} nodes", repo.nodes.len());
+        if repo.diagnostics.len() > 0 {
+            println!("    skipping {} unloadable files", repo.diagnostics.len());
+        }
+        let stats = repo.compression_stats();
+        println!("    {} bytes stored as {} ({:.2}x)",
+            stats.size, stats.stored_size, stats.ratio());
+        match repo.dedup_stats() {
+            Ok(ref dedup) if dedup.size > 0 => {
+                println!("    {} bytes in chunked objects, {} after dedup ({} saved)",
+                    dedup.size, dedup.unique_size, dedup.saved());
+            },
+            Ok(_) => (),
+            Err(err) => println!("    error reading chunk recipes: {}", err)
+        }
+    }
+    for diagnostic in repo.diagnostics.iter() {
+        println!("skipped: {}", diagnostic);
+    }
+    if repo.diagnostics.len() > 0 {
+        os::set_exit_status(1);
     }
+
+    if prog_args.deep {
+        let failures = repo.verify_contents();
+        for &(ref path, ref result) in failures.iter() {
+            match *result {
+                Ok(_) => println!("corrupt: {}", path.display()),
+                Err(ref err) => println!("unreadable: {}: {}", path.display(), err)
+            }
+        }
+        if failures.len() > 0 {
+            os::set_exit_status(1);
+        }
+    }
+
     let orphans = repo.find_orphans();
 
     if prog_args.verbose && orphans.len() > 0 {
         println!("    including {} orphans", orphans.len());
     }
 
-    let mut by_uuid: HashMap<Uuid, Vec<BackupNode>> = HashMap::new();
-    for node in repo.nodes.into_iter() {
-        match by_uuid.entry(node.uuid.clone()) {
-            Vacant(entry) => entry.set(Vec::new()),
-            Occupied(entry) => entry.into_mut()
-        }.push(node);
-    }
+    {
+        let duplicates = match repo.find_duplicates() {
+            Ok(duplicates) => duplicates,
+            Err(err) => fail!("Error while comparing duplicates: {}", err)
+        };
 
-    let mut orphan_nodes_lists = orphans.iter()
-        .map(|uu| by_uuid.pop(uu))
-        .filter(|opt_node_list| opt_node_list.is_some())
-        .map(|opt_node_list| opt_node_list.unwrap());
+        if prog_args.verbose && duplicates.len() > 0 {
+            println!("    including {} duplicated uuids", duplicates.len());
+        }
 
-    for orphan_nodes_list in orphan_nodes_lists {
-        for orphan_node in orphan_nodes_list.iter() {
-            println!("orphan: {}", orphan_node.path.display());
+        for dup in duplicates.iter() {
+            match dup.kind {
+                IdenticalCopies => {
+                    println!("duplicate (identical): {}", dup.uuid.to_hyphenated_string());
+                },
+                ConflictingCopies => {
+                    println!("duplicate (conflicting): {}", dup.uuid.to_hyphenated_string());
+                },
+                AlternateCopies => {
+                    println!("duplicate (alternate): {}", dup.uuid.to_hyphenated_string());
+                }
+            }
+            for node in dup.nodes.iter() {
+                let parent = match node.parent_uuid {
+                    Some(ref parent) => parent.to_hyphenated_string(),
+                    None => "-".to_string()
+                };
+                println!("    parent={} size={} ctransid={} {}",
+                    parent, node.size, node.ctransid(), node.path.display());
+            }
         }
     }
 
+    let mut removed_paths: Vec<Path> = Vec::new();
+    for orphan_node in repo.nodes.iter().filter(|n| orphans.contains(&n.uuid)) {
+        println!("orphan: {}", orphan_node.path.display());
+        if prog_args.repair {
+            match repo.delete_node(orphan_node) {
+                Ok(()) => {
+                    println!("    removed");
+                    removed_paths.push(orphan_node.path.clone());
+                },
+                Err(err) => println!("    error removing: {}", err)
+            }
+        }
+    }
 
+    if !prog_args.repair {
+        return;
+    }
+    repo.nodes.retain(|n| !removed_paths.contains(&n.path));
+
+    match repo.remove_stale_tmp() {
+        Ok(removed) => for path in removed.iter() {
+            println!("removed unfinished upload: {}", path.display());
+        },
+        Err(err) => println!("error removing unfinished uploads: {}", err)
+    }
+
+    match repo.remove_stale_partials() {
+        Ok(removed) => for path in removed.iter() {
+            println!("removed abandoned upload: {}", path.display());
+        },
+        Err(err) => println!("error removing abandoned uploads: {}", err)
+    }
+
+    // Only after the orphans are gone, so their chunks are collected too
+    match repo.collect_chunks() {
+        Ok(removed) => {
+            if prog_args.verbose || removed.len() > 0 {
+                println!("removed {} unreferenced chunks", removed.len());
+            }
+        },
+        Err(err) => println!("error collecting chunks: {}", err)
+    }
 }
@@ -0,0 +1,515 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::io::fs::{stat, link, unlink, walk_dir};
+use std::io::{File, BufferedReader, FileStat, TypeDirectory, TypeFile, TempDir};
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::util::{NullWriter, copy};
+use lock::{RepositoryLock, ExclusiveLock};
+use repository::{Repository, BackupNode};
+use btrfs::validate_stream;
+use bundle::{BundleManifest, read_manifest, open_entry};
+use metadata::{METADATA_SIDECAR, ObjectMetadata, HashingWriter};
+use object::{ObjectEncoding, PlainObject, DeflateObject, ChunkedObject};
+use object::{open_object, sidecar_path};
+use reliable_rw::{copy_out, IntegrityError, ProtocolError, ReadError, WriteError};
+use serialize::hex::ToHex;
+use uuid::Uuid;
+use argparse::{ArgumentParser, Store, StoreTrue, List};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod bundle;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+static ENCAP_MAGIC: &'static [u8] = b"reliable-encap";
+static STREAM_MAGIC: &'static [u8] = b"btrfs-stream\x00";
+
+
+struct ProgramArgs {
+    respository_path: String,
+    sources: Vec<String>,
+    hardlink: bool,
+    no_compress: bool,
+    dedup: bool,
+    dry_run: bool,
+    verbose: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            sources: Vec::new(),
+            hardlink: false,
+            no_compress: false,
+            dedup: false,
+            dry_run: false,
+            verbose: false
+        }
+    }
+}
+
+
+#[deriving(PartialEq)]
+enum SourceKind {
+    // A bare `btrfs send` stream
+    RawStream,
+    // The same, wrapped by reliable-encap
+    EncapStream
+}
+
+
+enum ImportOutcome {
+    // Committed at this path, not yet in the index
+    Imported(Path),
+    Duplicate(Uuid),
+    Invalid(String)
+}
+
+
+fn import_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail
+    }
+}
+
+
+fn sniff(path: &Path) -> IoResult<Option<SourceKind>> {
+    let mut file = try!(File::open(path));
+    let magic = match file.read_exact(ENCAP_MAGIC.len()) {
+        Ok(magic) => magic,
+        Err(_) => return Ok(None)
+    };
+    Ok(if magic.as_slice() == ENCAP_MAGIC {
+        Some(EncapStream)
+    } else if magic.as_slice().starts_with(STREAM_MAGIC) {
+        Some(RawStream)
+    } else {
+        None
+    })
+}
+
+
+// Writes the logical stream in `path` to `writer`, unwrapping it if need be.
+fn copy_stream<W: Writer>(path: &Path, kind: &SourceKind, writer: &mut W) -> IoResult<()> {
+    let mut reader = BufferedReader::new(try!(File::open(path)));
+    match *kind {
+        RawStream => copy(&mut reader, writer),
+        EncapStream => match copy_out(&mut reader, writer) {
+            Ok(()) => Ok(()),
+            Err(IntegrityError) => Err(import_error("reliable-encap integrity error", None)),
+            Err(ProtocolError) => Err(import_error("reliable-encap protocol error", None)),
+            Err(ReadError(err)) => Err(err),
+            Err(WriteError(err)) => Err(err)
+        }
+    }
+}
+
+
+// Reads the stored object back through the stream parser, giving the
+// node it describes.
+fn validate(object_path: &Path, size: u64) -> IoResult<Result<BackupNode, String>> {
+    let mut reader = BufferedReader::new(try!(open_object(object_path)));
+    Ok(validate_stream(&mut reader).and_then(|command| {
+        BackupNode::from_btrfs_command(object_path, size, &command)
+    }).map_err(|err| format!("{}", err)))
+}
+
+
+// Hard links a raw stream into the repository.  The source file becomes
+// the object, so nothing is written until the stream checks out.  Being
+// the same file, the source is made read-only too when an append-only
+// repository seals the object.  Where a link can't be made, say across
+// filesystems, the stream is copied instead.
+fn import_linked(repo: &Repository, path: &Path, metadata: &mut ObjectMetadata) -> IoResult<ImportOutcome> {
+    let size = try!(stat(path)).size;
+    let node = match try!(validate(path, size)) {
+        Ok(node) => node,
+        Err(reason) => return Ok(Invalid(reason))
+    };
+    if repo.holds_uuid(&node.uuid) {
+        return Ok(Duplicate(node.uuid));
+    }
+    let content_hash = {
+        let mut sink = NullWriter;
+        let mut writer = HashingWriter::new(&mut sink);
+        try!(copy_stream(path, &RawStream, &mut writer));
+        writer.digest()
+    };
+    metadata.content_hash = content_hash.as_slice().to_hex();
+
+    let final_path = repo.get_root().join(Uuid::new_v4().to_hyphenated_string());
+    let metadata_path = sidecar_path(&final_path, METADATA_SIDECAR);
+    try!(metadata.save(&metadata_path));
+    match link(path, &final_path) {
+        Ok(()) => Ok(Imported(final_path)),
+        Err(err) => {
+            try!(unlink(&metadata_path));
+            println!("{}: can't link ({}), copying instead", path.display(), err);
+            import_copied(repo, |writer| copy_stream(path, &RawStream, writer), None, metadata)
+        }
+    }
+}
+
+
+// Copies the stream `fill` writes into a new object in the repository's
+// encoding.
+fn import_copied(repo: &Repository, fill: |&mut HashingWriter| -> IoResult<()>,
+                 expected_hash: Option<&str>, metadata: &mut ObjectMetadata) -> IoResult<ImportOutcome> {
+    let mut pending = try!(repo.create_object());
+    let written = {
+        let mut writer = HashingWriter::new(&mut pending.writer);
+        fill(&mut writer).map(|()| writer.digest().as_slice().to_hex())
+    };
+    let content_hash = match written {
+        Ok(content_hash) => content_hash,
+        Err(err) => {
+            let _ = pending.rollback();
+            return Err(err);
+        }
+    };
+    let finished = try!(pending.finish());
+    match expected_hash {
+        Some(expected) if expected != content_hash.as_slice() => {
+            try!(finished.rollback());
+            return Ok(Invalid(format!("content hash {}, expected {}", content_hash, expected)));
+        },
+        _ => ()
+    }
+
+    // Sizes don't matter here; the node is only for its header
+    let node = match validate(finished.tmp_path(), 0) {
+        Ok(Ok(node)) => node,
+        Ok(Err(reason)) => {
+            try!(finished.rollback());
+            return Ok(Invalid(reason));
+        },
+        Err(err) => {
+            let _ = finished.rollback();
+            return Err(err);
+        }
+    };
+    if repo.holds_uuid(&node.uuid) {
+        try!(finished.rollback());
+        return Ok(Duplicate(node.uuid));
+    }
+    metadata.content_hash = content_hash;
+    try!(metadata.save(&sidecar_path(finished.final_path(), METADATA_SIDECAR)));
+    Ok(Imported(try!(finished.commit())))
+}
+
+
+// Each stream of a bundle is imported as if it had been uploaded, with
+// the metadata it was exported with.
+fn import_bundle(repo: &Repository, path: &Path, manifest: &BundleManifest) -> IoResult<Vec<(String, ImportOutcome)>> {
+    let mut file = try!(File::open(path));
+    let mut out = Vec::new();
+    for entry in manifest.entries.iter() {
+        let mut metadata = match entry.metadata {
+            Some(ref metadata) => metadata.clone(),
+            None => ObjectMetadata::new(b"")
+        };
+        metadata.labels.push("imported".to_string());
+        let outcome = try!(import_copied(repo, |writer| {
+            open_entry(&mut file, entry).and_then(|mut reader| copy(&mut reader, writer))
+        }, Some(entry.content_hash.as_slice()), &mut metadata));
+        out.push((format!("{}:{}", path.display(), entry.uuid.to_hyphenated_string()), outcome));
+    }
+    Ok(out)
+}
+
+
+fn import_file(repo: &Repository, path: &Path, hardlink: bool) -> IoResult<Vec<(String, ImportOutcome)>> {
+    match try!(read_manifest(&mut try!(File::open(path)))) {
+        Some(manifest) => return import_bundle(repo, path, &manifest),
+        None => ()
+    }
+    let label = format!("{}", path.display());
+    let kind = match try!(sniff(path)) {
+        Some(kind) => kind,
+        None => return Ok(vec![(label, Invalid("not a send stream".to_string()))])
+    };
+    let mut metadata = ObjectMetadata::new(b"");
+    metadata.source_path = Some(format!("{}", path.display()));
+    metadata.labels.push("imported".to_string());
+    // These backups were taken when the file was written, not now;
+    // tiering and retention should see them as that old.
+    metadata.uploaded_at = (try!(stat(path)).modified / 1000) as i64;
+
+    let outcome = if hardlink && kind == RawStream {
+        try!(import_linked(repo, path, &mut metadata))
+    } else {
+        try!(import_copied(repo, |writer| copy_stream(path, &kind, writer), None, &mut metadata))
+    };
+    Ok(vec![(label, outcome)])
+}
+
+
+// What an import would find, without touching the repository.  Wrapped
+// streams are unwrapped into `spool` to be read.
+fn check_file(path: &Path, spool: &Path) -> IoResult<Vec<(String, Result<BackupNode, String>)>> {
+    let mut file = try!(File::open(path));
+    match try!(read_manifest(&mut file)) {
+        Some(manifest) => {
+            let mut out = Vec::new();
+            for entry in manifest.entries.iter() {
+                let mut reader = try!(open_entry(&mut file, entry));
+                let result = validate_stream(&mut reader).and_then(|command| {
+                    BackupNode::from_btrfs_command(path, entry.size, &command)
+                }).map_err(|err| format!("{}", err));
+                out.push((format!("{}:{}", path.display(), entry.uuid.to_hyphenated_string()), result));
+            }
+            return Ok(out);
+        },
+        None => ()
+    }
+    let result = match try!(sniff(path)) {
+        Some(RawStream) => try!(validate(path, try!(stat(path)).size)),
+        Some(EncapStream) => {
+            {
+                let mut file = try!(File::create(spool));
+                try!(copy_stream(path, &EncapStream, &mut file));
+            }
+            let result = validate(spool, 0);
+            try!(unlink(spool));
+            try!(result)
+        },
+        None => Err("not a send stream".to_string())
+    };
+    Ok(vec![(format!("{}", path.display()), result)])
+}
+
+
+// Every regular file named, recursing into directories, in a stable
+// order.
+fn collect_sources(sources: &[String]) -> Vec<Path> {
+    let mut out = Vec::new();
+    for source in sources.iter() {
+        let path = Path::new(source.as_slice());
+        match stat(&path) {
+            Ok(FileStat { kind: TypeDirectory, .. }) => {
+                let mut found: Vec<Path> = match walk_dir(&path) {
+                    Ok(paths) => paths.filter(|p| p.is_file()).collect(),
+                    Err(err) => fail!("Error while listing {}: {}", path.display(), err)
+                };
+                found.sort();
+                out.extend(found.into_iter());
+            },
+            Ok(FileStat { kind: TypeFile, .. }) => out.push(path),
+            Ok(stat) => println!("skipping {}: {}", path.display(), stat.kind),
+            Err(err) => fail!("stat error on {}: {}", path.display(), err)
+        }
+    }
+    out
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Import existing btrfs send streams, raw or wrapped in ",
+            "reliable-encap, or exported bundles into a repository"));
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository")
+            .required();
+
+        ap.refer(&mut prog_args.sources)
+            .add_argument(
+                "source", box List::<String>, "Stream or bundle files, or directories of them")
+            .required();
+
+        ap.refer(&mut prog_args.hardlink)
+            .add_option(["-l", "--link"], box StoreTrue, concat!(
+                "Hard link raw streams into the repository instead of copying. ",
+                "They are stored uncompressed, and in an append-only repository ",
+                "the source files become read-only with the objects"));
+
+        ap.refer(&mut prog_args.no_compress)
+            .add_option(["--no-compress"], box StoreTrue,
+            "Store copied streams as they are, without compression");
+
+        ap.refer(&mut prog_args.dedup)
+            .add_option(["--dedup"], box StoreTrue,
+            "Store copied streams as deduplicated chunks");
+
+        ap.refer(&mut prog_args.dry_run)
+            .add_option(["-n", "--dry-run"], box StoreTrue,
+            "Only check the streams and report what would be imported");
+
+        ap.refer(&mut prog_args.verbose)
+            .add_option(["-v", "--verbose"], box StoreTrue, "Verbose");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    let path = Path::new(prog_args.respository_path.as_slice());
+
+    // Quick sanity check
+    match stat(&path) {
+        Ok(FileStat { kind: TypeDirectory, .. }) => (),  // Ok
+        Ok(stat) => fail!("repository is not a directory: {}", stat.kind),
+        Err(e) => fail!("stat error: {}", e)
+    }
+
+    let _lock = match RepositoryLock::acquire(&path, ExclusiveLock) {
+        Ok(lock) => lock,
+        Err(err) => fail!("Error while locking repository: {}", err)
+    };
+
+    let mut repo = match Repository::load_from(&path) {
+        Ok(repo) => repo,
+        Err(err) => fail!("Error while reading repository: {}", err)
+    };
+    let encoding: ObjectEncoding = if prog_args.dedup {
+        ChunkedObject
+    } else if prog_args.no_compress {
+        PlainObject
+    } else {
+        DeflateObject
+    };
+    repo.set_encoding(encoding);
+
+    let sources = collect_sources(prog_args.sources.as_slice());
+    let spool = match TempDir::new("btrfs-import") {
+        Ok(spool) => spool,
+        Err(err) => fail!("Error creating spool directory: {}", err)
+    };
+    let spool_path = spool.path().join("stream");
+
+    // Snapshot and parent of everything imported
+    let mut imported: Vec<(Uuid, Option<Uuid>)> = Vec::new();
+    let mut duplicates: uint = 0;
+    let mut invalid: uint = 0;
+
+    for source in sources.iter() {
+        let outcomes = if prog_args.dry_run {
+            let checked = match check_file(source, &spool_path) {
+                Ok(checked) => checked,
+                Err(err) => fail!("Error while reading {}: {}", source.display(), err)
+            };
+            let mut outcomes = Vec::new();
+            for (label, result) in checked.into_iter() {
+                match result {
+                    Ok(ref node) if repo.holds_uuid(&node.uuid)
+                            || imported.iter().any(|&(ref uuid, _)| *uuid == node.uuid) => {
+                        outcomes.push((label, Duplicate(node.uuid.clone())));
+                    },
+                    Ok(node) => {
+                        println!("would import {} as {}", label, node.uuid.to_hyphenated_string());
+                        imported.push((node.uuid.clone(), node.parent_uuid.clone()));
+                    },
+                    Err(reason) => outcomes.push((label, Invalid(reason)))
+                }
+            }
+            outcomes
+        } else {
+            match import_file(&repo, source, prog_args.hardlink) {
+                Ok(outcomes) => outcomes,
+                Err(err) => fail!("Error while importing {}: {}", source.display(), err)
+            }
+        };
+
+        for (label, outcome) in outcomes.into_iter() {
+            match outcome {
+                Imported(object_path) => {
+                    // Indexed straight away, so later files see it as a duplicate
+                    match repo.add_object(&object_path) {
+                        Ok(()) => (),
+                        Err(err) => fail!("Error while reading {}: {}", object_path.display(), err)
+                    }
+                    // It may have been set aside as an orphan, so don't
+                    // look for it among the nodes
+                    let node = match Repository::read_node(&object_path) {
+                        Ok(node) => node,
+                        Err(problem) => {
+                            println!("{}: imported as {} but can't be indexed: {}",
+                                label, object_path.display(), problem);
+                            invalid += 1;
+                            continue;
+                        }
+                    };
+                    if prog_args.verbose {
+                        println!("imported {} as {}", label, node.uuid.to_hyphenated_string());
+                    }
+                    imported.push((node.uuid.clone(), node.parent_uuid.clone()));
+                },
+                Duplicate(uuid) => {
+                    println!("{}: duplicate of {}", label, uuid.to_hyphenated_string());
+                    duplicates += 1;
+                },
+                Invalid(reason) => {
+                    println!("{}: invalid: {}", label, reason);
+                    invalid += 1;
+                }
+            }
+        }
+    }
+
+    // Parents may arrive later in the same import, so only judge once
+    // everything is in.
+    let mut missing_parents: uint = 0;
+    for &(ref uuid, ref parent) in imported.iter() {
+        match *parent {
+            Some(ref parent) if !repo.contains_uuid(parent)
+                    && !imported.iter().any(|&(ref other, _)| *other == *parent) => {
+                println!("{}: parent {} is missing", uuid.to_hyphenated_string(),
+                    parent.to_hyphenated_string());
+                missing_parents += 1;
+            },
+            _ => ()
+        }
+    }
+
+    println!("{} {}, {} duplicates, {} invalid, {} with missing parents",
+        imported.len(), if prog_args.dry_run { "to import" } else { "imported" },
+        duplicates, invalid, missing_parents);
+    if invalid > 0 || missing_parents > 0 {
+        os::set_exit_status(1);
+    }
+}
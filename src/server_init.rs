@@ -0,0 +1,202 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use lock::{RepositoryLock, ExclusiveLock};
+use format::{CURRENT_VERSION, init, migrate};
+use repository::Repository;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    respository_path: String,
+    migrate: bool,
+    append_only: bool,
+    retention_days: u64,
+    namespace: String
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            migrate: false,
+            append_only: false,
+            retention_days: 0,
+            namespace: "".to_string()
+        }
+    }
+}
+
+
+// Turns on append-only mode and seals whatever is already stored, in
+// the root and in every namespace.  Turning it off again is deliberately
+// left to editing the config by hand on the server.
+fn make_append_only(path: &Path, retention_days: u64) {
+    let namespaces = match Repository::list_namespaces(path) {
+        Ok(namespaces) => namespaces,
+        Err(err) => fail!("Error while listing namespaces: {}", err)
+    };
+    let mut roots = vec![(path.clone(), "default".to_string())];
+    for name in namespaces.into_iter() {
+        match Repository::namespace_root(path, Some(name.as_slice())) {
+            Some(ns_root) => roots.push((ns_root, name)),
+            None => ()
+        }
+    }
+
+    for &(ref root, ref name) in roots.iter() {
+        // An upload still running could commit after the seal pass
+        let _lock = match RepositoryLock::acquire(root, ExclusiveLock) {
+            Ok(lock) => lock,
+            Err(err) => fail!("Error while locking namespace {}: {}", name, err)
+        };
+        // Sealing is about files, not the backup graph; orphans and all
+        let mut repo = match Repository::load_from_nofsck(root) {
+            Ok(repo) => repo,
+            Err(err) => fail!("Error while reading namespace {}: {}", name, err)
+        };
+        repo.config.append_only = Some(true);
+        if retention_days > 0 {
+            repo.config.retention_days = Some(retention_days);
+        }
+        match repo.config.save(root) {
+            Ok(()) => (),
+            Err(err) => fail!("Error while saving config of namespace {}: {}", name, err)
+        }
+        match repo.seal_objects() {
+            Ok(sealed) => println!("namespace {} is append-only; {} objects sealed, kept for {} days",
+                name, sealed, repo.config.retention_days.unwrap_or(0)),
+            Err(err) => fail!("Error while sealing objects of namespace {}: {}", name, err)
+        }
+    }
+}
+
+
+// Clients only get namespaces that already exist, unless the config
+// lets them create their own.
+fn add_namespace(path: &Path, name: &str) {
+    match Repository::namespace_root(path, Some(name)) {
+        Some(ref ns_root) if ns_root.exists() => {
+            println!("namespace {} already exists", name);
+            return;
+        },
+        Some(_) => (),
+        None => fail!("Invalid namespace name: {}", name)
+    }
+    match Repository::create_namespace(path, Some(name)) {
+        Ok(_) => println!("created namespace {}", name),
+        Err(err) => fail!("Error while creating namespace: {}", err)
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Create a repository, or upgrade an existing one");
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository")
+            .required();
+
+        ap.refer(&mut prog_args.migrate)
+            .add_option(["--migrate"], box StoreTrue,
+            "Upgrade an existing repository to the current format in place");
+
+        ap.refer(&mut prog_args.append_only)
+            .add_option(["--append-only"], box StoreTrue, concat!(
+                "Make committed objects immutable and refuse deletes from ",
+                "clients. With --migrate, applies to an existing repository"));
+
+        ap.refer(&mut prog_args.retention_days)
+            .add_option(["--retention-days"], box Store::<u64>,
+            "In an append-only repository, keep every object at least this long");
+
+        ap.refer(&mut prog_args.namespace)
+            .add_option(["--namespace"], box Store::<String>,
+            "Add a namespace to an existing repository, instead of creating one");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    let path = Path::new(prog_args.respository_path.as_slice());
+
+    if prog_args.namespace.len() > 0 {
+        add_namespace(&path, prog_args.namespace.as_slice());
+        return;
+    }
+
+    if !prog_args.migrate {
+        match init(&path) {
+            Ok(()) => println!("created repository (format {})", CURRENT_VERSION),
+            Err(err) => fail!("Error while creating repository: {}", err)
+        }
+        if prog_args.append_only {
+            make_append_only(&path, prog_args.retention_days);
+        }
+        return;
+    }
+
+    {
+        // Nothing else may touch the repository while its layout changes
+        let _lock = match RepositoryLock::acquire(&path, ExclusiveLock) {
+            Ok(lock) => lock,
+            Err(err) => fail!("Error while locking repository: {}", err)
+        };
+        match migrate(&path) {
+            Ok(from) if from == CURRENT_VERSION => {
+                println!("repository is already at format {}", CURRENT_VERSION);
+            },
+            Ok(from) => println!("migrated repository from format {} to {}", from, CURRENT_VERSION),
+            Err(err) => fail!("Error while migrating repository: {}", err)
+        }
+    }
+    // Takes each namespace's lock in turn
+    if prog_args.append_only {
+        make_append_only(&path, prog_args.retention_days);
+    }
+}
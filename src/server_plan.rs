@@ -0,0 +1,151 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::io::fs::stat;
+use std::io::{FileStat, TypeDirectory, stderr};
+use lock::{RepositoryLock, SharedLock};
+use repository::Repository;
+use uuid::Uuid;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    respository_path: String,
+    target: String,
+    verbose: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            target: "".to_string(),
+            verbose: false
+        }
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    let mut ap = ArgumentParser::new();
+    ap.set_description(concat!(
+        "Print the cheapest set of objects to replay to restore a snapshot, ",
+        "in an order btrfs_concat accepts"));
+
+    ap.refer(&mut prog_args.respository_path)
+        .add_argument(
+            "repository", box Store::<String>, "Path to a Repository")
+        .required();
+
+    ap.refer(&mut prog_args.target)
+        .add_argument(
+            "uuid", box Store::<String>, "Snapshot UUID to restore")
+        .required();
+
+    ap.refer(&mut prog_args.verbose)
+        .add_option(["-v", "--verbose"], box StoreTrue,
+        "Describe each step on stderr");
+
+    match ap.parse_args() {
+        Ok(()) => {}
+        Err(x) => {
+            os::set_exit_status(x);
+            return;
+        }
+    }
+
+    let target = match Uuid::parse_str(prog_args.target.as_slice()) {
+        Ok(uuid) => uuid,
+        Err(err) => fail!("invalid uuid {}: {}", prog_args.target, err)
+    };
+
+    let path = Path::new(prog_args.respository_path);
+
+    // Quick sanity check
+    match stat(&path) {
+        Ok(FileStat { kind: TypeDirectory, .. }) => (),  // Ok
+        Ok(stat) => fail!("repository is not a directory: {}", stat.kind),
+        Err(e) => fail!("stat error: {}", e)
+    }
+
+    let _lock = match RepositoryLock::acquire(&path, SharedLock) {
+        Ok(lock) => lock,
+        Err(err) => fail!("Error while locking repository: {}", err)
+    };
+
+    let repo = match Repository::load_from(&path) {
+        Ok(repo) => repo,
+        Err(err) => fail!("Error while reading repository: {}", err)
+    };
+
+    let plan = match repo.plan_restore(&target) {
+        Some(plan) => plan,
+        None => {
+            let mut err = stderr();
+            assert!(err.write(format!(
+                "no restore path to {}\n", target.to_hyphenated_string()
+            ).as_bytes()).is_ok());
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    if prog_args.verbose {
+        let mut err = stderr();
+        for node in plan.steps.iter() {
+            let parent = match node.parent_uuid {
+                Some(ref parent) => parent.to_hyphenated_string(),
+                None => "-".to_string()
+            };
+            assert!(err.write(format!(
+                "    {} -> {} ({} bytes, {})\n",
+                parent, node.uuid.to_hyphenated_string(), node.size, node.tier.name()
+            ).as_bytes()).is_ok());
+        }
+        assert!(err.write(format!(
+            "{} objects, {} bytes total, {} from cold storage\n",
+            plan.len(), plan.total_size, plan.cold_steps()
+        ).as_bytes()).is_ok());
+    }
+
+    for node in plan.steps.iter() {
+        println!("{}", node.path.display());
+    }
+}
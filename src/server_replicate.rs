@@ -0,0 +1,504 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::collections::HashSet;
+use std::io::{File, BufferedReader, BufferedWriter, IoResult, EndOfFile, PermissionDenied, TempDir};
+use std::io::{Command, Process, InheritFd};
+use std::io::pipe::PipeStream;
+use std::io::fs::unlink;
+use std::io::util::{NullWriter, copy};
+use serialize::hex::ToHex;
+use protocol::{
+    ProtocolClient,
+    Edge,
+    Graph,
+    DownloadHeader,
+    MetadataUploadRequest,
+    EncryptedUploadRequest,
+    UploadFailed,
+    UploadCommitted,
+    UploadDuplicate,
+    UploadOverQuota,
+};
+use metadata::HashingWriter;
+use uuid::Uuid;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    source: String,
+    destination: String,
+    server_command: String,
+    namespace: String,
+    client_name: String,
+    client_key: String,
+    verify: bool,
+    dry_run: bool,
+    verbose: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            source: "".to_string(),
+            destination: "".to_string(),
+            server_command: "backupserver".to_string(),
+            namespace: "".to_string(),
+            client_name: "".to_string(),
+            client_key: "".to_string(),
+            verify: false,
+            dry_run: false,
+            verbose: false
+        }
+    }
+}
+
+
+// A backupserver we talk the protocol to: run locally for a path, or
+// through ssh for `[user@]host:path`.
+struct Endpoint {
+    name: String,
+    process: Process,
+    reader: BufferedReader<PipeStream>,
+    writer: BufferedWriter<PipeStream>,
+    // What the handshake agreed, for every client made after it
+    version: u32,
+    capabilities: Vec<String>
+}
+
+
+impl Endpoint {
+    fn spawn(spec: &str, server_command: &str) -> IoResult<Endpoint> {
+        let remote = match spec.find(':') {
+            Some(colon) if !spec.slice_to(colon).contains("/") => {
+                Some((spec.slice_to(colon), spec.slice_from(colon + 1)))
+            },
+            _ => None
+        };
+        let mut command = match remote {
+            Some((host, path)) => {
+                let mut command = Command::new("ssh");
+                command.arg(host).arg(server_command).arg(path);
+                command
+            },
+            None => {
+                let mut command = Command::new(server_command);
+                command.arg(spec);
+                command
+            }
+        };
+        let mut process = try!(command.stderr(InheritFd(2)).spawn());
+        let reader = BufferedReader::new(process.stdout.take().unwrap());
+        let writer = BufferedWriter::new(process.stdin.take().unwrap());
+        Ok(Endpoint {
+            name: spec.to_string(),
+            process: process,
+            reader: reader,
+            writer: writer,
+            version: 0,
+            capabilities: Vec::new()
+        })
+    }
+
+    // Starts a server for `spec` and opens a session with it.  A server
+    // that hangs up on the versioned handshake is started again and
+    // spoken to the way servers were before protocol versions.
+    fn connect(spec: &str, server_command: &str, namespace: Option<&str>,
+               credentials: &Option<(&str, Vec<u8>)>) -> IoResult<Option<Endpoint>> {
+        let mut endpoint = try!(Endpoint::spawn(spec, server_command));
+        let result = {
+            let mut client = ProtocolClient::new(&mut endpoint.reader, &mut endpoint.writer);
+            match *credentials {
+                Some((name, ref key)) => client.set_credentials(name, key.clone()),
+                None => ()
+            }
+            match client.handshake(namespace) {
+                Ok(accepted) => Ok((accepted, client.version(), client.capabilities())),
+                Err(err) => Err(err)
+            }
+        };
+        let (accepted, version, capabilities) = match result {
+            Ok(agreed) => agreed,
+            Err(ref err) if err.kind == EndOfFile && credentials.is_none() => {
+                let _ = endpoint.process.wait();
+                endpoint = try!(Endpoint::spawn(spec, server_command));
+                let accepted = try!(ProtocolClient::new(&mut endpoint.reader, &mut endpoint.writer)
+                                    .legacy_handshake(namespace));
+                (accepted, 0, Vec::new())
+            },
+            Err(err) => return Err(err)
+        };
+        if !accepted {
+            return Ok(None);
+        }
+        endpoint.version = version;
+        endpoint.capabilities = capabilities;
+        Ok(Some(endpoint))
+    }
+
+    fn client<'a>(&'a mut self) -> ProtocolClient<'a> {
+        ProtocolClient::resume(&mut self.reader, &mut self.writer,
+                               self.version, self.capabilities.clone())
+    }
+
+    fn close(mut self) {
+        let _ = self.client().quit();
+        drop(self.writer);
+        let _ = self.process.wait();
+    }
+}
+
+
+fn describe(edge: &Edge) -> String {
+    match edge.from_node {
+        Some(ref parent) => format!("{} (from {})",
+            edge.to_node.to_hyphenated_string(), parent.to_hyphenated_string()),
+        None => format!("{} (full)", edge.to_node.to_hyphenated_string())
+    }
+}
+
+
+// Snapshots the destination lacks, each as the cheapest source edge we
+// can send, ordered so every parent arrives before its children.
+// Snapshots whose chain can't be completed are left out.
+fn plan_copies<'a>(source: &'a Graph, destination: &Graph) -> Vec<&'a Edge> {
+    let mut present: HashSet<Uuid> = destination.edges.iter()
+        .map(|e| e.to_node.clone())
+        .collect();
+    let mut missing: HashSet<Uuid> = source.edges.iter()
+        .map(|e| e.to_node.clone())
+        .filter(|uuid| !present.contains(uuid))
+        .collect();
+
+    let mut out = Vec::new();
+    loop {
+        let mut round: Vec<&Edge> = Vec::new();
+        for uuid in missing.iter() {
+            let best = source.edges.iter()
+                .filter(|e| e.to_node == *uuid)
+                .filter(|e| match e.from_node {
+                    Some(ref parent) => present.contains(parent),
+                    None => true
+                })
+                .min_by(|e| e.size);
+            match best {
+                Some(edge) => round.push(edge),
+                None => ()
+            }
+        }
+        if round.len() == 0 {
+            break;
+        }
+        for edge in round.into_iter() {
+            missing.remove(&edge.to_node);
+            present.insert(edge.to_node.clone());
+            out.push(edge);
+        }
+    }
+    out
+}
+
+
+// Fetches one object into the spool, checking it against the content
+// hash recorded when it was first uploaded.  `Err` says why the object
+// can't be copied; the connection is still good for the rest.
+fn fetch(source: &mut Endpoint, edge: &Edge, spool_path: &Path) -> IoResult<Result<DownloadHeader, String>> {
+    let mut file = BufferedWriter::new(try!(File::create(spool_path)));
+    let (header, digest) = {
+        let mut writer = HashingWriter::new(&mut file);
+        let header = try!(source.client().download_archive(
+            &edge.to_node, edge.from_node.as_ref(), &mut writer));
+        (header, writer.digest())
+    };
+    try!(file.flush());
+
+    let header = match header {
+        Some(header) => header,
+        None => return Ok(Err(format!("{} vanished from {}", describe(edge), source.name)))
+    };
+    match header.metadata {
+        Some(ref metadata) if metadata.content_hash != digest.as_slice().to_hex() => {
+            return Ok(Err(format!("{} is corrupt on {}: content hash {}, expected {}",
+                describe(edge), source.name, digest.as_slice().to_hex(),
+                metadata.content_hash)));
+        },
+        _ => ()
+    }
+    Ok(Ok(header))
+}
+
+
+// Uploads a fetched object.  `Err` says why the destination refused it.
+fn store(destination: &mut Endpoint, edge: &Edge, header: &DownloadHeader,
+         spool_path: &Path) -> IoResult<Result<(), String>> {
+    let mut stream = BufferedReader::new(try!(File::open(spool_path)));
+    let result = match header.envelope {
+        Some(ref envelope) => {
+            let request = EncryptedUploadRequest {
+                force: false,
+                client: header.metadata.as_ref().and_then(|m| m.client.clone()),
+                envelope: envelope.clone()
+            };
+            try!(destination.client().upload_encrypted_archive(&request, &mut stream))
+        },
+        None => {
+            let request = match header.metadata {
+                Some(ref metadata) => MetadataUploadRequest {
+                    force: false,
+                    client: metadata.client.clone(),
+                    source_path: metadata.source_path.clone(),
+                    labels: metadata.labels.clone()
+                },
+                None => MetadataUploadRequest {
+                    force: false,
+                    client: None,
+                    source_path: None,
+                    labels: Vec::new()
+                }
+            };
+            try!(destination.client().upload_archive(&request, &mut stream))
+        }
+    };
+    Ok(match result {
+        UploadCommitted(_) => Ok(()),
+        UploadDuplicate(_) => Ok(()),
+        UploadFailed => Err(format!("{} rejected {}", destination.name, describe(edge))),
+        UploadOverQuota(rejection) => Err(format!(
+            "{} is over its {} quota: {} of {} {}, so {} wasn't copied", destination.name,
+            rejection.scope, rejection.used, rejection.limit, rejection.limit_kind,
+            describe(edge)))
+    })
+}
+
+
+// Reads the object back from the destination and compares it with what
+// we sent.
+fn verify(destination: &mut Endpoint, edge: &Edge, spool_path: &Path) -> IoResult<bool> {
+    let expected = {
+        let mut spool = BufferedReader::new(try!(File::open(spool_path)));
+        let mut sink = NullWriter;
+        let mut writer = HashingWriter::new(&mut sink);
+        try!(copy(&mut spool, &mut writer));
+        writer.digest()
+    };
+    let mut sink = NullWriter;
+    let mut writer = HashingWriter::new(&mut sink);
+    match try!(destination.client().download_archive(
+            &edge.to_node, edge.from_node.as_ref(), &mut writer)) {
+        Some(_) => Ok(writer.digest() == expected),
+        None => Ok(false)
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Copy the snapshots one repository has and another lacks. ",
+            "A repository is a local path or [user@]host:path, reached over ssh"));
+
+        ap.refer(&mut prog_args.source)
+            .add_argument(
+                "source", box Store::<String>, "Repository to copy from")
+            .required();
+
+        ap.refer(&mut prog_args.destination)
+            .add_argument(
+                "destination", box Store::<String>, "Repository to copy to")
+            .required();
+
+        ap.refer(&mut prog_args.server_command)
+            .add_option(["--server-command"], box Store::<String>,
+            "backupserver to run at each end");
+
+        ap.refer(&mut prog_args.namespace)
+            .add_option(["-N", "--namespace"], box Store::<String>,
+            "Replicate this namespace rather than the default one");
+
+        ap.refer(&mut prog_args.client_name)
+            .add_option(["--client"], box Store::<String>,
+            "Name to authenticate as, to servers that ask");
+
+        ap.refer(&mut prog_args.client_key)
+            .add_option(["--client-key"], box Store::<String>,
+            "File holding the client's key, as hex");
+
+        ap.refer(&mut prog_args.verify)
+            .add_option(["--verify"], box StoreTrue,
+            "Read each copied object back from the destination and compare");
+
+        ap.refer(&mut prog_args.dry_run)
+            .add_option(["-n", "--dry-run"], box StoreTrue,
+            "Print what would be copied without copying");
+
+        ap.refer(&mut prog_args.verbose)
+            .add_option(["-v", "--verbose"], box StoreTrue, "Verbose");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    let namespace = if prog_args.namespace.len() > 0 {
+        Some(prog_args.namespace.as_slice())
+    } else {
+        None
+    };
+
+    let credentials = match (prog_args.client_name.len() > 0, prog_args.client_key.len() > 0) {
+        (false, false) => None,
+        (true, true) => match auth::load_key(&Path::new(prog_args.client_key.as_slice())) {
+            Ok(key) => Some((prog_args.client_name.as_slice(), key)),
+            Err(err) => fail!("Error reading client key: {}", err)
+        },
+        _ => fail!("--client and --client-key go together")
+    };
+
+    let mut endpoints = Vec::new();
+    for spec in [prog_args.source.as_slice(), prog_args.destination.as_slice()].iter() {
+        match Endpoint::connect(*spec, prog_args.server_command.as_slice(), namespace, &credentials) {
+            Ok(Some(endpoint)) => endpoints.push(endpoint),
+            Ok(None) => fail!("{} refused the namespace", spec),
+            Err(ref err) if err.kind == PermissionDenied => fail!("{} refused the credentials", spec),
+            Err(err) => fail!("Error connecting to {}: {}", spec, err)
+        }
+    }
+    let mut destination = endpoints.pop().unwrap();
+    let mut source = endpoints.pop().unwrap();
+
+    let source_graph = match source.client().get_graph() {
+        Ok(graph) => graph,
+        Err(err) => fail!("Error reading graph of {}: {}", source.name, err)
+    };
+    let destination_graph = match destination.client().get_graph() {
+        Ok(graph) => graph,
+        Err(err) => fail!("Error reading graph of {}: {}", destination.name, err)
+    };
+
+    let copies = plan_copies(&source_graph, &destination_graph);
+    if prog_args.verbose || prog_args.dry_run {
+        println!("{} snapshots to copy", copies.len());
+    }
+
+    // Nothing here survives an interruption but the spool.  Objects land
+    // on the destination whole or not at all, so running again resumes
+    // with whatever is still missing.
+    let spool = match TempDir::new("btrfs-replicate") {
+        Ok(spool) => spool,
+        Err(err) => fail!("Error creating spool directory: {}", err)
+    };
+
+    let mut failures: uint = 0;
+    // Snapshots that didn't make it; nothing built on them can follow
+    let mut failed: HashSet<Uuid> = HashSet::new();
+    for edge in copies.iter() {
+        if prog_args.dry_run {
+            println!("would copy {} ({} bytes)", describe(*edge), edge.size);
+            continue;
+        }
+        match edge.from_node {
+            Some(ref parent) if failed.contains(parent) => {
+                println!("skipping {}: its parent wasn't copied", describe(*edge));
+                failed.insert(edge.to_node.clone());
+                continue;
+            },
+            _ => ()
+        }
+        if prog_args.verbose {
+            println!("copying {} ({} bytes)", describe(*edge), edge.size);
+        }
+        let spool_path = spool.path().join(edge.to_node.to_hyphenated_string());
+
+        let header = match fetch(&mut source, *edge, &spool_path) {
+            Ok(Ok(header)) => header,
+            Ok(Err(why)) => {
+                println!("{}", why);
+                failures += 1;
+                failed.insert(edge.to_node.clone());
+                let _ = unlink(&spool_path);
+                continue;
+            },
+            Err(err) => fail!("Error downloading {}: {}", describe(*edge), err)
+        };
+        match store(&mut destination, *edge, &header, &spool_path) {
+            Ok(Ok(())) => (),
+            Ok(Err(why)) => {
+                println!("{}", why);
+                failures += 1;
+                failed.insert(edge.to_node.clone());
+                let _ = unlink(&spool_path);
+                continue;
+            },
+            Err(err) => fail!("Error uploading {}: {}", describe(*edge), err)
+        }
+        if prog_args.verify {
+            match verify(&mut destination, *edge, &spool_path) {
+                Ok(true) => (),
+                Ok(false) => {
+                    println!("{} does not match after copying", describe(*edge));
+                    failures += 1;
+                },
+                Err(err) => fail!("Error verifying {}: {}", describe(*edge), err)
+            }
+        }
+        let _ = unlink(&spool_path);
+    }
+
+    source.close();
+    destination.close();
+
+    let skipped = source_graph.edges.iter()
+        .map(|e| e.to_node.clone())
+        .filter(|uuid| !destination_graph.edges.iter().any(|e| e.to_node == *uuid))
+        .collect::<HashSet<Uuid>>()
+        .len() - copies.len();
+    if skipped > 0 {
+        println!("{} snapshots skipped: their chains are incomplete on {}",
+            skipped, prog_args.source);
+    }
+    if failures > 0 {
+        os::set_exit_status(1);
+    }
+}
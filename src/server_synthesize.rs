@@ -0,0 +1,276 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::collections::HashSet;
+use std::io::fs::stat;
+use std::io::{FileStat, TypeDirectory};
+use lock::{RepositoryLock, SharedLock};
+use repository::{Repository, BackupNode, FullBackup, HotTier};
+use btrfs::BtrfsSubvol;
+use concat::{BtrfsCommandConcatIter, write_out};
+use metadata::{METADATA_SIDECAR, ObjectMetadata, HashingWriter};
+use object::sidecar_path;
+use uuid::Uuid;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod protocol;
+mod concat;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    respository_path: String,
+    max_links: uint,
+    max_bytes: u64,
+    dry_run: bool,
+    verbose: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            max_links: 0,
+            max_bytes: 0,
+            dry_run: false,
+            verbose: false
+        }
+    }
+
+    fn exceeds(&self, links: uint, bytes: u64) -> bool {
+        (self.max_links > 0 && links > self.max_links) ||
+            (self.max_bytes > 0 && bytes > self.max_bytes)
+    }
+}
+
+
+struct Candidate {
+    uuid: Uuid,
+    links: uint,
+    bytes: u64,
+    paths: Vec<Path>
+}
+
+
+// Of all snapshots whose cheapest restore breaks a limit, pick the one
+// closest to a full backup.  Synthesizing it shortens every chain that
+// runs through it, so the snapshots behind it are reconsidered afterwards.
+fn next_candidate(repo: &Repository, args: &ProgramArgs) -> Option<Candidate> {
+    let uuids: HashSet<Uuid> = repo.iter_nodes()
+        .map(|n| n.uuid.clone())
+        .collect();
+
+    let mut best: Option<Candidate> = None;
+    for uuid in uuids.iter() {
+        let plan = match repo.plan_restore(uuid) {
+            Some(plan) => plan,
+            None => continue
+        };
+        if plan.len() < 2 || !args.exceeds(plan.len(), plan.total_size) {
+            continue;
+        }
+        // We can't read what clients encrypted
+        if plan.steps.iter().any(|n| n.encrypted) {
+            continue;
+        }
+        let is_better = match best {
+            Some(ref cand) => (plan.len(), plan.total_size) < (cand.links, cand.bytes),
+            None => true
+        };
+        if is_better {
+            best = Some(Candidate {
+                uuid: uuid.clone(),
+                links: plan.len(),
+                bytes: plan.total_size,
+                paths: plan.paths()
+            });
+        }
+    }
+    best
+}
+
+
+fn estimated_node(repo: &Repository, cand: &Candidate) -> BackupNode {
+    let target = repo.iter_nodes().find(|n| n.uuid == cand.uuid).unwrap();
+    let subvol = BtrfsSubvol {
+        name: target.name.clone(),
+        uuid: target.uuid.clone(),
+        ctransid: target.ctransid()
+    };
+    BackupNode {
+        size: cand.bytes,
+        stored_size: cand.bytes,
+        encoding: repo.get_encoding(),
+        encrypted: false,
+        kind: FullBackup(subvol),
+        uuid: target.uuid.clone(),
+        parent_uuid: None,
+        path: Path::new(format!("(synthetic {})", cand.uuid.to_hyphenated_string())),
+        name: target.name.clone(),
+        metadata: None,
+        tier: HotTier
+    }
+}
+
+
+fn synthesize(repo: &Repository, cand: &Candidate) -> BackupNode {
+    let iter = match BtrfsCommandConcatIter::new_synthetic(cand.paths.clone()) {
+        Ok(iter) => iter,
+        Err(err) => fail!("error opening chain for {}: {}",
+            cand.uuid.to_hyphenated_string(), err)
+    };
+    let mut pending = match repo.create_object() {
+        Ok(pending) => pending,
+        Err(err) => fail!("error creating object: {}", err)
+    };
+    let written = {
+        let mut writer = HashingWriter::new(&mut pending.writer);
+        write_out(iter, &mut writer).map(|()| writer.digest())
+    };
+    let mut metadata = match written {
+        Ok(content_hash) => ObjectMetadata::new(content_hash.as_slice()),
+        Err(err) => {
+            let _ = pending.rollback();
+            fail!("error writing synthetic full: {}", err);
+        }
+    };
+    metadata.labels.push("synthesized".to_string());
+
+    let final_path = match pending.finish().and_then(|finished| {
+        try!(metadata.save(&sidecar_path(finished.final_path(), METADATA_SIDECAR)));
+        let final_path = try!(finished.commit());
+        // Read-only from here on if the repository is append-only
+        try!(repo.seal(&final_path));
+        Ok(final_path)
+    }) {
+        Ok(final_path) => final_path,
+        Err(err) => fail!("error committing synthetic full: {}", err)
+    };
+
+    match Repository::read_node(&final_path) {
+        Ok(node) => node,
+        Err(problem) => fail!("synthesized object {} is {}", final_path.display(), problem)
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Build full backups on the server for snapshots whose ",
+            "incremental chain is too long"));
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository")
+            .required();
+
+        ap.refer(&mut prog_args.max_links)
+            .add_option(["-l", "--max-links"], box Store::<uint>,
+            "Longest chain of objects to allow before synthesizing");
+
+        ap.refer(&mut prog_args.max_bytes)
+            .add_option(["-b", "--max-bytes"], box Store::<u64>,
+            "Largest restore read, in bytes, to allow before synthesizing");
+
+        ap.refer(&mut prog_args.dry_run)
+            .add_option(["-n", "--dry-run"], box StoreTrue,
+            "Only report what would be synthesized");
+
+        ap.refer(&mut prog_args.verbose)
+            .add_option(["-v", "--verbose"], box StoreTrue, "Verbose");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    if prog_args.max_links == 0 && prog_args.max_bytes == 0 {
+        println!("one of --max-links or --max-bytes is required");
+        os::set_exit_status(2);
+        return;
+    }
+
+    let path = Path::new(prog_args.respository_path.as_slice());
+
+    // Quick sanity check
+    match stat(&path) {
+        Ok(FileStat { kind: TypeDirectory, .. }) => (),  // Ok
+        Ok(stat) => fail!("repository is not a directory: {}", stat.kind),
+        Err(e) => fail!("stat error: {}", e)
+    }
+
+    let _lock = match RepositoryLock::acquire(&path, SharedLock) {
+        Ok(lock) => lock,
+        Err(err) => fail!("Error while locking repository: {}", err)
+    };
+
+    let mut repo = match Repository::load_from(&path) {
+        Ok(repo) => repo,
+        Err(err) => fail!("Error while reading repository: {}", err)
+    };
+
+    let mut synthesized: uint = 0;
+    loop {
+        let cand = match next_candidate(&repo, &prog_args) {
+            Some(cand) => cand,
+            None => break
+        };
+        if prog_args.verbose || prog_args.dry_run {
+            println!("synthesize: {} ({} objects, {} bytes)",
+                cand.uuid.to_hyphenated_string(), cand.links, cand.bytes);
+        }
+        let node = if prog_args.dry_run {
+            estimated_node(&repo, &cand)
+        } else {
+            synthesize(&repo, &cand)
+        };
+        if prog_args.verbose {
+            println!("    -> {} ({} bytes)", node.path.display(), node.size);
+        }
+        repo.nodes.push(node);
+        synthesized += 1;
+    }
+
+    if prog_args.verbose {
+        println!("synthesized {} full backups", synthesized);
+    }
+}
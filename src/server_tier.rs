@@ -0,0 +1,206 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::collections::HashSet;
+use std::io::fs::stat;
+use std::io::{FileStat, TypeDirectory};
+use lock::{RepositoryLock, ExclusiveLock};
+use repository::{Repository, HotTier};
+use object::ChunkedObject;
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+static SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+
+struct ProgramArgs {
+    respository_path: String,
+    cold_root: String,
+    older_than: uint,
+    ignore_chains: bool,
+    dry_run: bool,
+    verbose: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            cold_root: "".to_string(),
+            older_than: 0,
+            ignore_chains: false,
+            dry_run: false,
+            verbose: false
+        }
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Move old objects from the repository to its cold tier. ",
+            "Objects still needed to restore a recent snapshot stay put"));
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository")
+            .required();
+
+        ap.refer(&mut prog_args.cold_root)
+            .add_option(["--cold-root"], box Store::<String>,
+            "Set the repository's cold tier to this directory");
+
+        ap.refer(&mut prog_args.older_than)
+            .add_option(["-d", "--older-than"], box Store::<uint>,
+            "Move objects uploaded more than this many days ago");
+
+        ap.refer(&mut prog_args.ignore_chains)
+            .add_option(["--ignore-chains"], box StoreTrue,
+            "Go by age alone, even if a recent snapshot builds on the object");
+
+        ap.refer(&mut prog_args.dry_run)
+            .add_option(["-n", "--dry-run"], box StoreTrue,
+            "Print what would be moved without moving it");
+
+        ap.refer(&mut prog_args.verbose)
+            .add_option(["-v", "--verbose"], box StoreTrue, "Verbose");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    let path = Path::new(prog_args.respository_path.as_slice());
+
+    // Quick sanity check
+    match stat(&path) {
+        Ok(FileStat { kind: TypeDirectory, .. }) => (),  // Ok
+        Ok(stat) => fail!("repository is not a directory: {}", stat.kind),
+        Err(e) => fail!("stat error: {}", e)
+    }
+
+    let _lock = match RepositoryLock::acquire(&path, ExclusiveLock) {
+        Ok(lock) => lock,
+        Err(err) => fail!("Error while locking repository: {}", err)
+    };
+
+    let mut repo = match Repository::load_from(&path) {
+        Ok(repo) => repo,
+        Err(err) => fail!("Error while reading repository: {}", err)
+    };
+
+    if prog_args.cold_root.len() > 0 {
+        repo.config.cold_root = Some(prog_args.cold_root.clone());
+        match repo.config.save(&path) {
+            Ok(()) => (),
+            Err(err) => fail!("Error while saving repository config: {}", err)
+        }
+        // Objects already there count as cold from now on
+        repo = match Repository::load_from(&path) {
+            Ok(repo) => repo,
+            Err(err) => fail!("Error while reading repository: {}", err)
+        };
+    }
+    if prog_args.older_than == 0 {
+        return;
+    }
+    match repo.cold_root() {
+        Some(ref cold_root) if prog_args.verbose => {
+            println!("cold tier is {}", cold_root.display());
+        },
+        Some(_) => (),
+        None => fail!("repository has no cold tier; set one with --cold-root")
+    }
+
+    let cutoff = time::get_time().sec - prog_args.older_than as i64 * SECONDS_PER_DAY;
+
+    // Everything on the cheapest restore of a recent snapshot stays hot,
+    // or restoring it would mean reading the cold tier anyway.
+    let mut needed: HashSet<Path> = HashSet::new();
+    if !prog_args.ignore_chains {
+        for node in repo.iter_nodes().filter(|n| n.uploaded_at() >= cutoff) {
+            match repo.plan_restore(&node.uuid) {
+                Some(plan) => needed.extend(plan.steps.iter().map(|n| n.path.clone())),
+                None => ()
+            }
+        }
+    }
+
+    let mut moved: uint = 0;
+    let mut moved_bytes: u64 = 0;
+    for node in repo.iter_nodes() {
+        if node.tier != HotTier || node.uploaded_at() >= cutoff || needed.contains(&node.path) {
+            continue;
+        }
+        if node.encoding == ChunkedObject {
+            if prog_args.verbose {
+                println!("skipping {}: chunked objects stay hot", node.path.display());
+            }
+            continue;
+        }
+        if prog_args.dry_run {
+            println!("would move {} ({} bytes)", node.path.display(), node.stored_size);
+        } else {
+            match repo.move_to_cold(node) {
+                Ok(cold_path) => if prog_args.verbose {
+                    println!("moved {} to {}", node.path.display(), cold_path.display());
+                },
+                Err(err) => {
+                    println!("error moving {}: {}", node.path.display(), err);
+                    os::set_exit_status(1);
+                    continue;
+                }
+            }
+        }
+        moved += 1;
+        moved_bytes += node.stored_size;
+    }
+
+    if prog_args.verbose || prog_args.dry_run {
+        println!("{} objects, {} bytes {}", moved, moved_bytes,
+            if prog_args.dry_run { "to move" } else { "moved" });
+    }
+}
@@ -0,0 +1,245 @@
+#![allow(dead_code)]
+#![feature(macro_rules)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate libc;
+extern crate debug;
+extern crate flate;
+extern crate time;
+extern crate "rust-crypto" as crypto;
+
+extern crate uuid;
+extern crate msgpack;
+
+extern crate reliable_rw;
+extern crate argparse;
+
+use std::os;
+use std::io::fs::stat;
+use std::io::{FileStat, TypeDirectory};
+use lock::{RepositoryLock, SharedLock, ExclusiveLock};
+use repository::Repository;
+use quota::{Quota, Usage};
+use argparse::{ArgumentParser, Store, StoreTrue};
+
+mod repository;
+mod lock;
+mod format;
+mod config;
+mod auth;
+mod quota;
+mod planner;
+mod query;
+mod pool;
+mod object;
+mod compression;
+mod chunking;
+mod encryption;
+mod metadata;
+mod protocol;
+mod concat;
+mod btrfs;
+mod crc32;
+
+
+struct ProgramArgs {
+    respository_path: String,
+    namespace: String,
+    max_bytes: u64,
+    max_objects: u64,
+    client_max_bytes: u64,
+    client_max_objects: u64,
+    clear: bool,
+    clients: bool
+}
+
+impl ProgramArgs {
+    fn new() -> ProgramArgs {
+        ProgramArgs {
+            respository_path: "".to_string(),
+            namespace: "".to_string(),
+            max_bytes: 0,
+            max_objects: 0,
+            client_max_bytes: 0,
+            client_max_objects: 0,
+            clear: false,
+            clients: false
+        }
+    }
+
+    fn changes_quota(&self) -> bool {
+        self.clear || self.max_bytes > 0 || self.max_objects > 0
+            || self.client_max_bytes > 0 || self.client_max_objects > 0
+    }
+}
+
+
+// Zero means no change
+fn update_quota(quota: &mut Option<Quota>, max_bytes: u64, max_objects: u64) {
+    if max_bytes == 0 && max_objects == 0 {
+        return;
+    }
+    let mut updated = match *quota {
+        Some(ref quota) => quota.clone(),
+        None => Quota { max_bytes: None, max_objects: None }
+    };
+    if max_bytes > 0 {
+        updated.max_bytes = Some(max_bytes);
+    }
+    if max_objects > 0 {
+        updated.max_objects = Some(max_objects);
+    }
+    *quota = Some(updated);
+}
+
+
+fn show_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(limit) => format!("{}", limit),
+        None => "unlimited".to_string()
+    }
+}
+
+
+fn print_usage(label: &str, usage: &Usage, quota: &Option<Quota>) {
+    let (max_bytes, max_objects) = match *quota {
+        Some(ref quota) => (quota.max_bytes, quota.max_objects),
+        None => (None, None)
+    };
+    println!("{}: {} of {} bytes, {} of {} objects", label,
+        usage.bytes, show_limit(max_bytes),
+        usage.objects, show_limit(max_objects));
+}
+
+
+fn report(label: &str, repo: &Repository, clients: bool) {
+    print_usage(label, &repo.usage(), &repo.config.quota);
+    if !clients {
+        return;
+    }
+    // The same totals the per-client quota is enforced against
+    for client in repo.clients().iter() {
+        let label = match *client {
+            Some(ref name) => format!("  client {}", name),
+            None => "  unnamed clients".to_string()
+        };
+        print_usage(label.as_slice(), &repo.client_usage(client.as_ref().map(|c| c.as_slice())),
+            &repo.config.client_quota);
+    }
+}
+
+
+#[cfg(not(test))]
+fn main() {
+    let mut prog_args = ProgramArgs::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(concat!(
+            "Report what a repository stores against its quotas, ",
+            "or set the quotas of one namespace"));
+
+        ap.refer(&mut prog_args.respository_path)
+            .add_argument(
+                "repository", box Store::<String>, "Path to a Repository")
+            .required();
+
+        ap.refer(&mut prog_args.namespace)
+            .add_option(["-N", "--namespace"], box Store::<String>,
+            "Only this namespace");
+
+        ap.refer(&mut prog_args.max_bytes)
+            .add_option(["--max-bytes"], box Store::<u64>,
+            "Set the most bytes the namespace may store");
+
+        ap.refer(&mut prog_args.max_objects)
+            .add_option(["--max-objects"], box Store::<u64>,
+            "Set the most objects the namespace may store");
+
+        ap.refer(&mut prog_args.client_max_bytes)
+            .add_option(["--client-max-bytes"], box Store::<u64>,
+            "Set the most bytes each client may store in the namespace");
+
+        ap.refer(&mut prog_args.client_max_objects)
+            .add_option(["--client-max-objects"], box Store::<u64>,
+            "Set the most objects each client may store in the namespace");
+
+        ap.refer(&mut prog_args.clear)
+            .add_option(["--clear"], box StoreTrue,
+            "Remove the namespace's quotas before setting any given");
+
+        ap.refer(&mut prog_args.clients)
+            .add_option(["-c", "--clients"], box StoreTrue,
+            "Break usage down by client");
+
+        match ap.parse_args() {
+            Ok(()) => {}
+            Err(x) => {
+                os::set_exit_status(x);
+                return;
+            }
+        }
+    }
+
+    let path = Path::new(prog_args.respository_path.as_slice());
+
+    // Quick sanity check
+    match stat(&path) {
+        Ok(FileStat { kind: TypeDirectory, .. }) => (),  // Ok
+        Ok(stat) => fail!("repository is not a directory: {}", stat.kind),
+        Err(e) => fail!("stat error: {}", e)
+    }
+
+    let namespaces: Vec<Option<String>> = if prog_args.namespace.len() > 0 {
+        vec![Some(prog_args.namespace.clone())]
+    } else if prog_args.changes_quota() {
+        vec![None]
+    } else {
+        let mut namespaces = vec![None];
+        match Repository::list_namespaces(&path) {
+            Ok(names) => namespaces.extend(names.into_iter().map(|n| Some(n))),
+            Err(err) => fail!("Error while listing namespaces: {}", err)
+        }
+        namespaces
+    };
+
+    let mode = if prog_args.changes_quota() { ExclusiveLock } else { SharedLock };
+    for namespace in namespaces.iter() {
+        let name = namespace.as_ref().map(|n| n.as_slice());
+        let ns_root = match Repository::namespace_root(&path, name) {
+            Some(ns_root) => ns_root,
+            None => fail!("invalid namespace name: {}", name.unwrap())
+        };
+        // Each namespace is locked on its own, as the server does
+        let _lock = match RepositoryLock::acquire(&ns_root, mode) {
+            Ok(lock) => lock,
+            Err(err) => fail!("Error while locking repository: {}", err)
+        };
+        let mut repo = match Repository::load_from(&ns_root) {
+            Ok(repo) => repo,
+            Err(err) => fail!("Error while reading repository: {}", err)
+        };
+
+        if prog_args.changes_quota() {
+            if prog_args.clear {
+                repo.config.quota = None;
+                repo.config.client_quota = None;
+            }
+            update_quota(&mut repo.config.quota,
+                prog_args.max_bytes, prog_args.max_objects);
+            update_quota(&mut repo.config.client_quota,
+                prog_args.client_max_bytes, prog_args.client_max_objects);
+            match repo.config.save(&ns_root) {
+                Ok(()) => (),
+                Err(err) => fail!("Error while saving repository config: {}", err)
+            }
+        }
+
+        let label = match name {
+            Some(name) => format!("namespace {}", name),
+            None => "repository".to_string()
+        };
+        report(label.as_slice(), &repo, prog_args.clients);
+    }
+}
@@ -67,8 +67,14 @@ class ProtocolClient(object):
         self.reader = reader
         self.writer = writer
 
-    def _handshake(self):
-        self.writer.write('\xa8\x5b\x4b\x2b\x1b\x75\x4c\x0a')
+    def _handshake(self, namespace=None):
+        if namespace is None:
+            self.writer.write('\xa8\x5b\x4b\x2b\x1b\x75\x4c\x0a')
+        else:
+            self.writer.write('\xa8\x5b\x4b\x2b\x1b\x75\x4e\x0a')
+            self.writer.write(struct.pack('>I', len(namespace)))
+            self.writer.write(namespace)
+        self.writer.flush()
         assert self.reader.read(8) == b"\xfb\x70\x4c\x63\x41\x1d\x9c\x0a"
 
     def list_nodes(self):
@@ -91,6 +97,61 @@ class ProtocolClient(object):
             out.append(UUID(bytes=self.reader.read(16)))
         return out
 
+    def download_archive(self, uuid, parent=None):
+        request = json.dumps({
+            'uuid': str(uuid),
+            'parent': None if parent is None else str(parent),
+        })
+        self.writer.write(struct.pack('>QI', 8, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        if self.reader.read(1) != '\x01':
+            return None
+        (len_,) = struct.unpack('>I', self.reader.read(4))
+        # The reliable-encap stream follows
+        return json.loads(self.reader.read(len_))
+
+    def download_chain(self, target):
+        request = json.dumps({'target': str(target)})
+        self.writer.write(struct.pack('>QI', 11, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        status = self.reader.read(1)
+        if status not in ('\x01', '\x02'):
+            return None
+        (len_,) = struct.unpack('>I', self.reader.read(4))
+        body = json.loads(self.reader.read(len_))
+        if status == '\x02':
+            # The chain is there but the server couldn't read it
+            raise IOError(body['error'])
+        # A reliable-encap stream for btrfs receive follows
+        return body
+
+    def delete_node(self, uuid, parent=None, cascade=False, dry_run=True):
+        request = json.dumps({
+            'uuid': str(uuid),
+            'parent': None if parent is None else str(parent),
+            'cascade': cascade,
+            'dry_run': dry_run,
+        })
+        self.writer.write(struct.pack('>QI', 12, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        (len_,) = struct.unpack('>I', self.reader.read(4))
+        return json.loads(self.reader.read(len_))
+
+    def prune(self, query, cascade=False, dry_run=True):
+        request = json.dumps({
+            'query': query,
+            'cascade': cascade,
+            'dry_run': dry_run,
+        })
+        self.writer.write(struct.pack('>QI', 13, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        (len_,) = struct.unpack('>I', self.reader.read(4))
+        return json.loads(self.reader.read(len_))
+
     def get_graph(self):
         self.writer.write(struct.pack('>Q', 4))
         (len_,) = struct.unpack(
@@ -99,10 +160,98 @@ class ProtocolClient(object):
         return Graph.inflate_from_json(
             json.loads(self.reader.read(len_)))
 
-    def upload_archive(self):
-        self.writer.write(struct.pack('>Q', 3))
+    def upload_archive(self, force=False):
+        self.writer.write(struct.pack('>Q', 5 if force else 3))
+        return self.writer
+
+    def upload_archive_with_metadata(self, client=None, source_path=None,
+                                     labels=(), force=False):
+        request = json.dumps({
+            'force': force,
+            'client': client,
+            'source_path': source_path,
+            'labels': list(labels),
+        })
+        self.writer.write(struct.pack('>QI', 7, len(request)))
+        self.writer.write(request)
         return self.writer
 
+    def upload_encrypted_archive(self, envelope, client=None, force=False):
+        # envelope is the parsed JSON written by `btrfs_crypt encrypt`
+        request = json.dumps({'force': force, 'client': client,
+                              'envelope': envelope})
+        self.writer.write(struct.pack('>QI', 6, len(request)))
+        self.writer.write(request)
+        return self.writer
+
+    def begin_upload(self, client=None, source_path=None, labels=(),
+                     force=False):
+        # Returns ('\x01', {'session': ..., 'offset': 0}).  Send the
+        # reliable-encap stream from the offset, then finish_upload.
+        request = json.dumps({
+            'force': force,
+            'client': client,
+            'source_path': source_path,
+            'labels': list(labels),
+        })
+        self.writer.write(struct.pack('>QI', 14, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        return self._read_upload_session()
+
+    def resume_upload(self, session):
+        # '\x00' if the server no longer has the session
+        request = json.dumps({'session': str(session)})
+        self.writer.write(struct.pack('>QI', 15, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        return self._read_upload_session()
+
+    def _read_upload_session(self):
+        code = self.reader.read(1)
+        if code in ('\x01', '\x03'):
+            (len_,) = struct.unpack('>I', self.reader.read(4))
+            return code, json.loads(self.reader.read(len_))
+        return code, None
+
+    def finish_upload(self, sha256):
+        # Hex SHA-256 of the whole stream, including what earlier
+        # sessions sent
+        request = json.dumps({'sha256': sha256})
+        self.writer.write(struct.pack('>I', len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        return self.read_upload_result()
+
+    def query_nodes(self, **query):
+        # e.g. query_nodes(name='root_jessie*', uploaded_before=1393804800,
+        #                  latest_in_chain=True)
+        request = json.dumps(query)
+        self.writer.write(struct.pack('>QI', 10, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        (len_,) = struct.unpack('>I', self.reader.read(4))
+        return json.loads(self.reader.read(len_))
+
+    def get_quota(self, client=None):
+        request = json.dumps({'client': client})
+        self.writer.write(struct.pack('>QI', 9, len(request)))
+        self.writer.write(request)
+        self.writer.flush()
+        (len_,) = struct.unpack('>I', self.reader.read(4))
+        return json.loads(self.reader.read(len_))
+
+    def read_upload_result(self):
+        # \x03 means over quota, followed by the rejection as JSON; the
+        # server hangs up after it
+        code = self.reader.read(1)
+        if code in ('\x01', '\x02'):
+            return code, UUID(bytes=self.reader.read(16))
+        if code == '\x03':
+            (len_,) = struct.unpack('>I', self.reader.read(4))
+            return code, json.loads(self.reader.read(len_))
+        return code, None
+
     def exit(self):
         self.writer.write(struct.pack('>Q', 0))
 
@@ -194,7 +343,9 @@ def main(argv):
 
     graph = client.get_graph()
     print("got a graph: {!r}".format(graph))
-    archive = client.upload_archive()
+    archive = client.upload_archive_with_metadata(
+        client=os.uname()[1],
+        source_path=os.path.join(subv_root, subv_name))
     snapshot_name = '{}_{}' \
         .format(subv_name, datetime.datetime.now()) \
         .replace(' ', 'T')